@@ -0,0 +1,342 @@
+use std::{cell::RefCell, process::Command, str::FromStr};
+
+use smithay::{
+    desktop::space::SpaceElement,
+    reexports::wayland_protocols::xdg::shell::server::xdg_toplevel,
+    utils::{Logical, Point, Rectangle, SERIAL_COUNTER},
+};
+
+use crate::{state::State, window::WindowElement, workspace::WorkspaceTarget};
+
+/// How far a window is moved by `move-window`, in logical pixels.
+const MOVE_STEP: i32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "left" => Ok(Direction::Left),
+            "right" => Ok(Direction::Right),
+            "up" => Ok(Direction::Up),
+            "down" => Ok(Direction::Down),
+            _ => Err(format!("invalid direction `{}`", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Nothing to do, for example when a keyboard event is passed to the client.
+    None,
+    /// Ctrl-Alt-Fx, to change tty.
+    ChangeVt(i32),
+    /// Ctrl-Alt-Backspace by default, to exit the compositor.
+    Quit,
+    /// Run a program with its arguments, which may be quoted in the config.
+    Spawn(Vec<String>),
+    /// Run a command line with `sh -c`.
+    Shell(String),
+    /// Ask the focused window to close.
+    CloseWindow,
+    /// Focus the window below the focused one in the stacking order.
+    FocusNext,
+    /// Focus the window above the focused one in the stacking order.
+    FocusPrevious,
+    /// Focus the nearest window in a direction.
+    FocusWindow(Direction),
+    /// Move the focused window a step in a direction.
+    MoveWindow(Direction),
+    ToggleFullscreen,
+    ToggleMaximize,
+    /// Show a workspace on the current output, creating it if needed.
+    Workspace(WorkspaceTarget),
+    /// Move the focused window to a workspace, creating it if needed.
+    MoveToWorkspace(WorkspaceTarget),
+    /// Show a new, empty workspace on the current output.
+    NewWorkspace,
+    /// Destroy the workspace on the current output, its windows go to the one shown instead.
+    DestroyWorkspace,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    /// Parse an action as written in the config file, e.g. `quit`, `spawn foot` or
+    /// `focus left`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let rest = rest.trim();
+        let args = rest.split_whitespace().collect::<Vec<_>>();
+
+        match (name, args.as_slice()) {
+            ("none", []) => Ok(Action::None),
+            ("change-vt", [vt]) => vt
+                .parse::<i32>()
+                .map(Action::ChangeVt)
+                .map_err(|_| format!("invalid VT number `{}`", vt)),
+            ("quit" | "exit", []) => Ok(Action::Quit),
+            // Arguments may be quoted, as in `spawn foot -T "My terminal"`. What follows a `#` is
+            // a comment, which may leave nothing to run.
+            ("spawn", [_, ..]) => {
+                let args = shell_words::split(rest)
+                    .map_err(|err| format!("invalid command line `{}`: {}", rest, err))?;
+                match args.first() {
+                    Some(program) if !program.is_empty() => Ok(Action::Spawn(args)),
+                    _ => Err(format!("no program to run in `{}`", rest)),
+                }
+            }
+            ("shell", [_, ..]) => Ok(Action::Shell(rest.to_string())),
+            ("close-window", []) => Ok(Action::CloseWindow),
+            ("focus", ["next"]) => Ok(Action::FocusNext),
+            ("focus", ["previous"]) => Ok(Action::FocusPrevious),
+            ("focus", [direction]) => direction.parse().map(Action::FocusWindow),
+            ("move-window", [direction]) => direction.parse().map(Action::MoveWindow),
+            ("toggle-fullscreen", []) => Ok(Action::ToggleFullscreen),
+            ("toggle-maximize", []) => Ok(Action::ToggleMaximize),
+            ("workspace", [target]) => Ok(Action::Workspace(target.parse()?)),
+            ("move-to-workspace", [target]) => Ok(Action::MoveToWorkspace(target.parse()?)),
+            ("new-workspace", []) => Ok(Action::NewWorkspace),
+            ("destroy-workspace", []) => Ok(Action::DestroyWorkspace),
+            _ => Err(format!("invalid action `{}`", s)),
+        }
+    }
+}
+
+/// Where a window was before it got maximized or fullscreened, stored in its user data.
+#[derive(Default)]
+struct RestoreGeometry(RefCell<Option<Rectangle<i32, Logical>>>);
+
+impl<BackendData> State<BackendData> {
+    /// Run an action. Actions that depend on the backend, like changing VT or quitting, are
+    /// returned so that the backend can take care of them.
+    pub fn run_action(&mut self, action: Action) -> Action {
+        match action {
+            Action::None | Action::ChangeVt(_) | Action::Quit => return action,
+            Action::Spawn(args) => {
+                if let Some((program, args)) = args.split_first() {
+                    let mut command = Command::new(program);
+                    command.args(args);
+                    spawn(command);
+                }
+            }
+            Action::Shell(command_line) => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(command_line);
+                spawn(command);
+            }
+            Action::CloseWindow => {
+                if let Some(window) = self.focused_window() {
+                    window.send_close();
+                }
+            }
+            Action::FocusNext | Action::FocusPrevious => {
+                // The last element of the space is the topmost one.
+                let windows = self
+                    .space
+                    .elements()
+                    .rev()
+                    .filter(|window| !window.is_override_redirect())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if windows.is_empty() {
+                    return Action::None;
+                }
+                let next = match self
+                    .focused_window()
+                    .and_then(|focused| windows.iter().position(|w| *w == focused))
+                {
+                    Some(index) if action == Action::FocusNext => (index + 1) % windows.len(),
+                    Some(index) => (index + windows.len() - 1) % windows.len(),
+                    None => 0,
+                };
+                self.focus_window(windows[next].clone());
+            }
+            Action::FocusWindow(direction) => {
+                if let Some(window) = self.window_in_direction(direction) {
+                    self.focus_window(window);
+                }
+            }
+            Action::MoveWindow(direction) => {
+                if let Some(window) = self.focused_window() {
+                    if let Some(location) = self.space.element_location(&window) {
+                        let delta: Point<i32, Logical> = match direction {
+                            Direction::Left => (-MOVE_STEP, 0),
+                            Direction::Right => (MOVE_STEP, 0),
+                            Direction::Up => (0, -MOVE_STEP),
+                            Direction::Down => (0, MOVE_STEP),
+                        }
+                        .into();
+                        self.space.map_element(window, location + delta, true);
+                    }
+                }
+            }
+            Action::ToggleFullscreen => {
+                if let Some(window) = self.focused_window() {
+                    self.toggle_toplevel_state(&window, xdg_toplevel::State::Fullscreen);
+                }
+            }
+            Action::ToggleMaximize => {
+                if let Some(window) = self.focused_window() {
+                    self.toggle_toplevel_state(&window, xdg_toplevel::State::Maximized);
+                }
+            }
+            Action::Workspace(target) => self.switch_workspace(&target),
+            Action::MoveToWorkspace(target) => {
+                if let Some(window) = self.focused_window() {
+                    self.move_window_to_workspace(&window, &target);
+                }
+            }
+            Action::NewWorkspace => self.new_workspace(),
+            Action::DestroyWorkspace => self.destroy_workspace(),
+        }
+
+        Action::None
+    }
+
+    /// The window that has keyboard focus.
+    pub fn focused_window(&self) -> Option<WindowElement> {
+        let focus = self.seat.get_keyboard()?.current_focus()?;
+        self.space
+            .elements()
+            .find(|window| window.has_surface(&focus))
+            .cloned()
+    }
+
+    /// The topmost window that can take the keyboard focus.
+    pub fn topmost_window(&self) -> Option<WindowElement> {
+        self.space
+            .elements()
+            .rev()
+            .find(|window| !window.is_override_redirect())
+            .cloned()
+    }
+
+    /// Raise a window and give it keyboard focus.
+    pub fn focus_window(&mut self, window: WindowElement) {
+        let serial = SERIAL_COUNTER.next_serial();
+
+        self.space.raise_element(&window, true);
+        // A lock surface or a layer surface with exclusive keyboard interactivity keeps the focus.
+        if !self.is_locked() && self.exclusive_layer().is_none() {
+            if let (Some(keyboard), Some(surface)) = (self.seat.get_keyboard(), window.wl_surface())
+            {
+                keyboard.set_focus(self, Some(surface), serial);
+            }
+        }
+        for window in self.space.elements() {
+            window.send_pending_configure();
+        }
+    }
+
+    /// The nearest window whose center lies in `direction` from the center of the focused one.
+    fn window_in_direction(&self, direction: Direction) -> Option<WindowElement> {
+        let focused = self.focused_window()?;
+        let center = |window: &WindowElement| {
+            self.space
+                .element_geometry(window)
+                .map(|geo| (geo.loc.x + geo.size.w / 2, geo.loc.y + geo.size.h / 2))
+        };
+        let (x, y) = center(&focused)?;
+
+        self.space
+            .elements()
+            .filter(|window| **window != focused)
+            .filter_map(|window| center(window).map(|center| (window, center)))
+            .filter(|(_, (cx, cy))| match direction {
+                Direction::Left => *cx < x,
+                Direction::Right => *cx > x,
+                Direction::Up => *cy < y,
+                Direction::Down => *cy > y,
+            })
+            .min_by_key(|(_, (cx, cy))| (cx - x).pow(2) + (cy - y).pow(2))
+            .map(|(window, _)| window.clone())
+    }
+
+    /// Toggle a window between its normal geometry and covering the output it is on, in the given
+    /// state (fullscreen or maximized).
+    pub fn toggle_toplevel_state(&mut self, window: &WindowElement, state: xdg_toplevel::State) {
+        let is_set = window.has_state(state);
+        self.set_toplevel_state(window, state, !is_set);
+    }
+
+    /// Put a window into or out of the given state (fullscreen or maximized).
+    pub fn set_toplevel_state(
+        &mut self,
+        window: &WindowElement,
+        state: xdg_toplevel::State,
+        set: bool,
+    ) {
+        window
+            .user_data()
+            .insert_if_missing(RestoreGeometry::default);
+        let restore = window.user_data().get::<RestoreGeometry>().unwrap();
+
+        if set {
+            let location = match self.space.element_location(window) {
+                Some(location) => location,
+                None => return,
+            };
+            let output = self
+                .space
+                .outputs_for_element(window)
+                .first()
+                .or_else(|| self.space.outputs().next())
+                .cloned();
+            // Maximized windows leave room for panels, fullscreen ones cover them.
+            let output_geo = match output.and_then(|output| match state {
+                xdg_toplevel::State::Maximized => self.usable_area(&output),
+                _ => self.space.output_geometry(&output),
+            }) {
+                Some(geo) => geo,
+                None => return,
+            };
+
+            // Only remember the geometry of a window that is in neither state, so that going from
+            // maximized to fullscreen and back still restores the original one.
+            let mut restore = restore.0.borrow_mut();
+            if restore.is_none() {
+                *restore = Some(Rectangle::from_loc_and_size(
+                    location,
+                    window.geometry().size,
+                ));
+            }
+
+            window.set_state(state, true);
+            window.request_size(output_geo.size);
+            self.space.map_element(window.clone(), output_geo.loc, true);
+        } else {
+            let remaining = window.set_state(state, false);
+            if !remaining {
+                if let Some(geo) = restore.0.borrow_mut().take() {
+                    window.request_size(geo.size);
+                    self.space.map_element(window.clone(), geo.loc, true);
+                }
+            }
+        }
+
+        window.send_pending_configure();
+    }
+}
+
+/// Start a process without waiting for it. It is reaped on a separate thread, so that it doesn't
+/// linger as a zombie.
+fn spawn(mut command: Command) {
+    match command.spawn() {
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(err) => {
+            tracing::error!("Failed to spawn {:?}: {}", command, err);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use drm::control::{connector, crtc, Device as ControlDevice};
+use smithay::backend::drm::DrmDevice;
+
+use super::{
+    surface::{connector_name, OutputSurface},
+    Connector,
+};
+
+/// The CRTCs that can drive a connector, through any of its encoders.
+fn possible_crtcs(drm: &DrmDevice, connector: &connector::Info) -> Vec<crtc::Handle> {
+    let resources = match drm.resource_handles() {
+        Ok(resources) => resources,
+        Err(_) => return Vec::new(),
+    };
+    let mut crtcs = Vec::new();
+    for encoder in connector.encoders() {
+        let encoder = match drm.get_encoder(*encoder) {
+            Ok(encoder) => encoder,
+            Err(_) => continue,
+        };
+        for crtc in resources.filter_crtcs(encoder.possible_crtcs()) {
+            if !crtcs.contains(&crtc) {
+                crtcs.push(crtc);
+            }
+        }
+    }
+    crtcs
+}
+
+/// Find a CRTC for a connector. When all the CRTCs it can use are taken, a connector that holds
+/// one of them is moved to another free CRTC if it can be, which turns its output off until
+/// `apply_drm_output_config` sets it up again.
+pub fn allocate_crtc(
+    drm: &DrmDevice,
+    connectors: &mut HashMap<crtc::Handle, Connector>,
+    surfaces: &mut HashMap<crtc::Handle, OutputSurface>,
+    connector: &connector::Info,
+) -> Option<crtc::Handle> {
+    let wanted = possible_crtcs(drm, connector);
+    if let Some(crtc) = wanted.iter().find(|crtc| !connectors.contains_key(crtc)) {
+        return Some(*crtc);
+    }
+
+    for crtc in wanted {
+        let other = possible_crtcs(drm, &connectors[&crtc].info)
+            .into_iter()
+            .find(|crtc| !connectors.contains_key(crtc));
+        if let Some(other) = other {
+            let holder = connectors.remove(&crtc).unwrap();
+            surfaces.remove(&crtc);
+            tracing::info!(
+                "Moving {} to another CRTC to make room for {}",
+                connector_name(&holder.info),
+                connector_name(connector)
+            );
+            connectors.insert(other, holder);
+            return Some(crtc);
+        }
+    }
+    None
+}
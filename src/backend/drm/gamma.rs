@@ -0,0 +1,91 @@
+use smithay::output::Output;
+
+use crate::{
+    backend::drm::{surface::gamma_size, DrmData},
+    delegate_gamma_control,
+    night_light::{gamma_ramp, local_time_of_day, temperature, DAY_TEMPERATURE},
+    protocols::gamma_control::{GammaControlHandler, GammaControlManagerState},
+    state::State,
+};
+
+impl GammaControlHandler for State<DrmData> {
+    fn gamma_control_state(&mut self) -> &mut GammaControlManagerState {
+        &mut self.backend_data.gamma_control_state
+    }
+
+    fn gamma_size(&mut self, output: &Output) -> Option<usize> {
+        self.backend_data
+            .devices
+            .values()
+            .find_map(|device| {
+                device
+                    .connectors
+                    .iter()
+                    .find(|(_, connector)| connector.output == *output)
+                    .map(|(crtc, _)| gamma_size(&device.drm, *crtc))
+            })
+            .filter(|size| *size >= 2)
+    }
+
+    fn set_gamma(&mut self, output: &Output, ramp: Option<Vec<u16>>) -> bool {
+        let connector = self.backend_data.devices.values_mut().find_map(|device| {
+            device
+                .connectors
+                .values_mut()
+                .chain(device.disabled.iter_mut())
+                .find(|connector| connector.output == *output)
+        });
+        match connector {
+            Some(connector) => connector.client_gamma = ramp.clone(),
+            None => return false,
+        }
+        self.refresh_gamma();
+
+        // Disabled outputs get it once they are turned on.
+        self.backend_data.devices.values().all(|device| {
+            device
+                .surfaces
+                .values()
+                .filter(|surface| surface.output == *output)
+                .all(|surface| surface.gamma == ramp)
+        })
+    }
+}
+delegate_gamma_control!(State<DrmData>);
+
+impl State<DrmData> {
+    /// Give every CRTC the gamma ramp it should have: the one set by a client, or else the one of
+    /// the night light, or else the original one.
+    pub fn refresh_gamma(&mut self) {
+        let temperature = self.backend_data.night_light;
+        for device in self.backend_data.devices.values_mut() {
+            for (crtc, surface) in device.surfaces.iter_mut() {
+                let client_gamma = device
+                    .connectors
+                    .get(crtc)
+                    .and_then(|connector| connector.client_gamma.clone());
+                let gamma = client_gamma.or_else(|| {
+                    (temperature != DAY_TEMPERATURE)
+                        .then(|| gamma_ramp(temperature, gamma_size(&device.drm, *crtc)))
+                });
+                if surface.gamma != gamma {
+                    surface.set_gamma(&device.drm, gamma);
+                }
+            }
+        }
+    }
+
+    /// Follow the colour temperature of the night light for the current time.
+    pub fn update_night_light(&mut self) {
+        let temperature = match (&self.config.night_light, local_time_of_day()) {
+            (Some(config), Some(minute)) => temperature(config, minute),
+            _ => DAY_TEMPERATURE,
+        };
+        if temperature == self.backend_data.night_light {
+            return;
+        }
+        tracing::debug!("Night light at {} K", temperature);
+        self.backend_data.night_light = temperature;
+        self.refresh_gamma();
+    }
+}
@@ -1,47 +1,98 @@
+mod crtc_allocator;
+mod gamma;
 mod rendering;
 mod surface;
 mod udev;
 
-use drm::control::crtc;
+use drm::control::{connector, crtc};
+use drm_fourcc::DrmFormat;
 
 use smithay::{
     backend::{
         allocator::{
-            dmabuf::DmabufAllocator,
+            dmabuf::{Dmabuf, DmabufAllocator},
             gbm::{GbmAllocator, GbmDevice},
         },
         drm::{DrmDevice, DrmDeviceFd, DrmEvent, DrmNode, NodeType},
+        input::InputEvent,
         libinput::{LibinputInputBackend, LibinputSessionInterface},
         renderer::{
             gles::GlesRenderer,
             multigpu::{gbm::GbmGlesBackend, GpuManager},
+            ImportDma,
         },
         session::{libseat::LibSeatSession, Event as SessionEvent, Session},
         udev::{primary_gpu, UdevBackend, UdevEvent},
     },
+    delegate_dmabuf,
+    output::Output,
     reexports::{
-        calloop::{self, generic::Generic, EventLoop, Interest, LoopHandle, PostAction},
-        input::Libinput,
-        wayland_server::Display,
+        calloop::{
+            self,
+            generic::Generic,
+            timer::{TimeoutAction, Timer},
+            EventLoop, Interest, LoopHandle, PostAction, RegistrationToken,
+        },
+        input::{self, Led, Libinput},
+        wayland_server::{Display, DisplayHandle},
+    },
+    wayland::dmabuf::{
+        DmabufFeedbackBuilder, DmabufGlobal, DmabufHandler, DmabufState, ImportError,
     },
 };
 use smithay_drm_extras::drm_scanner::DrmScanner;
-use std::collections::HashMap;
-use std::os::fd::AsRawFd;
+use std::collections::{HashMap, HashSet};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use crate::{data::Data, init_wayland_socket, input::Action, state::State};
+use crate::{
+    action::Action, data::Data, init_wayland_socket, night_light::DAY_TEMPERATURE,
+    output::queue_redraw, protocols::gamma_control::GammaControlManagerState, state::State,
+};
 
 use self::surface::OutputSurface;
 
 use super::Error;
 
+/// A connected connector and the CRTC it has been given.
+struct Connector {
+    info: connector::Info,
+    output: Output,
+    /// The gamma ramp set by a `zwlr_gamma_control_v1` client, which wins over the night light.
+    client_gamma: Option<Vec<u16>>,
+}
+
+/// How many times in a row a CRTC has failed, and when to try it again.
+struct CrtcFailures {
+    count: u32,
+    retry_at: Instant,
+}
+
 struct Device {
+    /// What the session opened the device as, to be closed through it.
+    fd: RawFd,
     drm: DrmDevice,
     gbm: GbmDevice<DrmDeviceFd>,
+    connectors: HashMap<crtc::Handle, Connector>,
+    /// Connectors whose output is disabled. They give their CRTC back, for other connectors to
+    /// be lit up with, and take one again when they are enabled.
+    disabled: Vec<Connector>,
+    /// Only the CRTCs of enabled outputs have a surface.
     surfaces: HashMap<crtc::Handle, OutputSurface>,
+    /// Connected connectors that no CRTC was left for, which have no output yet. They are lit up
+    /// once one is freed.
+    pending: Vec<connector::Info>,
+    /// The CRTCs that failed to be set up or to show frames lately, see `crtc_failed`.
+    failures: HashMap<crtc::Handle, CrtcFailures>,
+    /// The gamma ramps the CRTCs had before they were first set up, to be put back when nothing
+    /// else sets one. `None` if it could not be read.
+    original_gamma: HashMap<crtc::Handle, Option<Vec<u16>>>,
     render_node: DrmNode,
     gbm_allocator: DmabufAllocator<GbmAllocator<DrmDeviceFd>>,
     drm_scanner: DrmScanner,
+    /// The source of the events of `drm`, removed along with the device.
+    notifier: RegistrationToken,
 }
 
 pub struct DrmData {
@@ -50,9 +101,19 @@ pub struct DrmData {
     devices: HashMap<DrmNode, Device>,
     primary_gpu: DrmNode,
     gpu_manager: GpuManager<GbmGlesBackend<GlesRenderer>>,
+    dmabuf_state: DmabufState,
+    gamma_control_state: GammaControlManagerState,
+    /// The current colour temperature of the night light, in Kelvin.
+    night_light: u32,
+    /// The keyboards, whose LEDs follow the lock keys.
+    keyboards: Vec<input::Device>,
+    leds: Led,
 }
 
-pub fn run_drm_backend() -> Result<(), Error> {
+pub fn run_drm_backend(
+    socket_name: Option<&str>,
+    config_path: Option<PathBuf>,
+) -> Result<(), Error> {
     let mut event_loop = EventLoop::<Data<DrmData>>::try_new().or_else(|_| {
         tracing::error!("Failed to create event loop");
         Err(Error::EventLoopCreateFailure)
@@ -66,7 +127,7 @@ pub fn run_drm_backend() -> Result<(), Error> {
     })?;
 
     // Create a Unix socket for clients to connect to.
-    let socket = init_wayland_socket(&mut event_loop).or_else(|_| {
+    let socket = init_wayland_socket(&mut event_loop, socket_name).or_else(|_| {
         tracing::error!("Failed to create Wayland socket");
         Err(Error::SocketCreateFailure)
     })?;
@@ -83,7 +144,9 @@ pub fn run_drm_backend() -> Result<(), Error> {
             ),
             |_, _, data| {
                 // Handle the events from the display, once.
-                data.display.dispatch_clients(&mut data.state).unwrap();
+                if let Err(err) = data.display.dispatch_clients(&mut data.state) {
+                    tracing::error!("Failed to dispatch clients: {}", err);
+                }
                 // Then we continue listening for other events.
                 Ok(PostAction::Continue)
             },
@@ -114,12 +177,15 @@ pub fn run_drm_backend() -> Result<(), Error> {
                 return Err(Error::PrimaryGPUGetFailure);
             }
         };
-        DrmNode::from_path(&path)
-            .and_then(|node| Ok(node.node_with_type(NodeType::Render).unwrap()?))
-            .or_else(|_| {
-                tracing::error!("No GPU found");
-                Err(Error::NoGPUFound)
-            })?
+        let node = DrmNode::from_path(&path).or_else(|_| {
+            tracing::error!("No GPU found");
+            Err(Error::NoGPUFound)
+        })?;
+        // Display-only devices like simpledrm have no render node, so their primary node stands
+        // in for it, as when the device is added.
+        node.node_with_type(NodeType::Render)
+            .and_then(Result::ok)
+            .unwrap_or(node)
     };
     tracing::info!("Using {} as primary GPU", primary_gpu);
 
@@ -132,27 +198,30 @@ pub fn run_drm_backend() -> Result<(), Error> {
             tracing::error!("Failed to create GPU manager");
             Err(Error::GpuManagerCreateFailure)
         })?,
+        dmabuf_state: DmabufState::new(),
+        gamma_control_state: GammaControlManagerState::new::<State<DrmData>>(&display.handle()),
+        night_light: DAY_TEMPERATURE,
+        keyboards: Vec::new(),
+        leds: Led::empty(),
     };
-    let mut state = State::new(&display, &mut event_loop, backend_data)
+    let mut state = State::new(&display, &mut event_loop, backend_data, config_path)
         .map_err(|err| Error::StateCreateFailure(err))?;
 
-    let dh = display.handle();
-
     // Initialize the udev backend.
     let udev_backend = UdevBackend::new(&state.backend_data.session.seat()).or_else(|_| {
         tracing::error!("Failed to initialize Udev backend");
         Err(Error::UdevInitFailure)
     })?;
     for (device_id, path) in udev_backend.device_list() {
-        state.on_udev_event(
-            &dh,
-            UdevEvent::Added {
-                device_id,
-                path: path.to_owned(),
-            },
-        );
+        state.on_udev_event(UdevEvent::Added {
+            device_id,
+            path: path.to_owned(),
+        });
     }
 
+    // The primary GPU has been added by now.
+    state.init_drm_dmabuf_global(&display.handle());
+
     // Initialize the libinput backend.
     let mut libinput_context = Libinput::new_with_udev::<LibinputSessionInterface<LibSeatSession>>(
         state.backend_data.session.clone().into(),
@@ -169,7 +238,24 @@ pub fn run_drm_backend() -> Result<(), Error> {
     event_loop
         .handle()
         .insert_source(libinput_backend, move |event, _, data| {
+            let mut new_keyboard = false;
+            match &event {
+                InputEvent::DeviceAdded { device }
+                    if device.has_capability(input::DeviceCapability::Keyboard) =>
+                {
+                    data.state.backend_data.keyboards.push(device.clone());
+                    new_keyboard = true;
+                }
+                InputEvent::DeviceRemoved { device } => {
+                    data.state
+                        .backend_data
+                        .keyboards
+                        .retain(|keyboard| keyboard != device);
+                }
+                _ => (),
+            }
             let action = data.state.handle_input(event);
+            data.state.update_keyboard_leds(new_keyboard);
             match action {
                 Action::ChangeVt(vt) => {
                     data.state.backend_data.session.change_vt(vt).ok();
@@ -177,7 +263,7 @@ pub fn run_drm_backend() -> Result<(), Error> {
                 Action::Quit => {
                     data.state.loop_signal.stop();
                 }
-                Action::None => (),
+                _ => (),
             }
         })
         .map_err(|_| Error::SourceInsertFailure)?;
@@ -207,15 +293,45 @@ pub fn run_drm_backend() -> Result<(), Error> {
                     device.drm.activate();
 
                     for (crtc, surface) in device.surfaces.iter_mut() {
-                        surface.gbm_surface.reset_buffers();
+                        // Whoever had the device may have changed the mode, the gamma and the
+                        // variable refresh, so the next frame does a full modeset, is drawn from
+                        // scratch and turns variable refresh back on if it should be.
+                        if let Err(err) = surface.compositor.surface().reset_state() {
+                            tracing::warn!("Failed to reset the state of a CRTC: {}", err);
+                        }
+                        surface.vrr = false;
+                        // The frame may never have been shown, it is not to be taken for a
+                        // locked one by the VBlank that is made up below.
+                        surface.locked_frame_pending = false;
+                        surface.compositor.reset_buffers();
+                        surface.restore_gamma(&device.drm);
+                        queue_redraw(&surface.output);
 
                         renderers.push((*node, *crtc));
                     }
                 }
 
+                // The VBlank of the last frame has gone elsewhere.
                 for (node, crtc) in renderers {
-                    data.state.on_drm_event(node, DrmEvent::VBlank(crtc));
+                    data.state
+                        .on_drm_event(node, DrmEvent::VBlank(crtc), &mut None);
                 }
+
+                // Monitors may have been plugged in or out meanwhile.
+                let nodes = data
+                    .state
+                    .backend_data
+                    .devices
+                    .keys()
+                    .copied()
+                    .collect::<Vec<_>>();
+                for node in nodes {
+                    data.state.on_device_changed(node);
+                }
+
+                // So were the releases of the keys held down when switching away.
+                data.state.release_pressed_keys();
+                data.state.update_keyboard_leds(true);
             }
         })
         .map_err(|_| Error::SourceInsertFailure)?;
@@ -224,7 +340,16 @@ pub fn run_drm_backend() -> Result<(), Error> {
     event_loop
         .handle()
         .insert_source(udev_backend, |event, _, data| {
-            data.state.on_udev_event(&data.display.handle(), event);
+            data.state.on_udev_event(event);
+        })
+        .map_err(|_| Error::SourceInsertFailure)?;
+
+    // The colour temperature changes slowly, and a change to the config shows within a minute.
+    event_loop
+        .handle()
+        .insert_source(Timer::immediate(), |_, _, data| {
+            data.state.update_night_light();
+            TimeoutAction::ToDuration(Duration::from_secs(60))
         })
         .map_err(|_| Error::SourceInsertFailure)?;
 
@@ -233,9 +358,107 @@ pub fn run_drm_backend() -> Result<(), Error> {
     let mut data = Data { display, state };
     event_loop
         .run(None, &mut data, |data| {
-            data.display.flush_clients().unwrap();
+            let failed = data.state.apply_drm_output_config();
+            data.state.test_drm_output_configs();
+            data.state.refresh_output_management(&failed);
+            data.state.render_idle_surfaces();
+            data.state.refresh_x11_windows();
+            data.state.refresh_surface_scales();
+            data.state.refresh_ext_workspace();
+            if let Err(err) = data.display.flush_clients() {
+                tracing::warn!("Failed to flush clients: {}", err);
+            }
         })
-        .unwrap();
+        .or_else(|_| {
+            tracing::error!("Failed to run the event loop");
+            Err(Error::EventLoopRunFailure)
+        })?;
 
     Ok(())
 }
+
+/// The formats clients can allocate buffers in: the ones the renderer of the primary GPU can
+/// render to.
+fn primary_render_formats(
+    gpu_manager: &mut GpuManager<GbmGlesBackend<GlesRenderer>>,
+    primary_gpu: &DrmNode,
+) -> HashSet<DrmFormat> {
+    gpu_manager
+        .single_renderer(primary_gpu)
+        .map(|mut renderer| {
+            renderer
+                .as_mut()
+                .egl_context()
+                .dmabuf_render_formats()
+                .clone()
+        })
+        .unwrap_or_default()
+}
+
+impl State<DrmData> {
+    /// Light the Caps Lock and Num Lock LEDs of the keyboards as the lock keys are. `force` sets
+    /// them even if they have not changed, for new keyboards and when the session comes back.
+    fn update_keyboard_leds(&mut self, force: bool) {
+        let modifiers = match self.seat.get_keyboard() {
+            Some(keyboard) => keyboard.modifier_state(),
+            None => return,
+        };
+        let mut leds = Led::empty();
+        leds.set(Led::CAPSLOCK, modifiers.caps_lock);
+        leds.set(Led::NUMLOCK, modifiers.num_lock);
+
+        let backend_data = &mut self.backend_data;
+        if leds == backend_data.leds && !force {
+            return;
+        }
+        backend_data.leds = leds;
+        for keyboard in &mut backend_data.keyboards {
+            keyboard.led_update(leds);
+        }
+    }
+
+    /// Advertise the render node of the primary GPU, so that clients allocate buffers on it.
+    fn init_drm_dmabuf_global(&mut self, dh: &DisplayHandle) {
+        let backend_data = &mut self.backend_data;
+        let formats =
+            primary_render_formats(&mut backend_data.gpu_manager, &backend_data.primary_gpu);
+
+        match DmabufFeedbackBuilder::new(backend_data.primary_gpu.dev_id(), formats.clone()).build()
+        {
+            Ok(feedback) => {
+                backend_data
+                    .dmabuf_state
+                    .create_global_with_default_feedback::<Self>(dh, &feedback);
+            }
+            Err(_) => {
+                tracing::warn!("Failed to build dmabuf feedback, falling back to dmabuf v3");
+                backend_data
+                    .dmabuf_state
+                    .create_global::<Self>(dh, formats.into_iter().collect());
+            }
+        }
+    }
+}
+
+impl DmabufHandler for State<DrmData> {
+    fn dmabuf_state(&mut self) -> &mut DmabufState {
+        &mut self.backend_data.dmabuf_state
+    }
+
+    fn dmabuf_imported(
+        &mut self,
+        _global: &DmabufGlobal,
+        dmabuf: Dmabuf,
+    ) -> Result<(), ImportError> {
+        // Only accept buffers the primary GPU is able to sample from, it composites every output.
+        let primary_gpu = self.backend_data.primary_gpu;
+        self.backend_data
+            .gpu_manager
+            .single_renderer(&primary_gpu)
+            .ok()
+            .and_then(|mut renderer| renderer.import_dmabuf(&dmabuf, None).ok())
+            .map(|_| ())
+            .ok_or(ImportError::Failed)
+    }
+}
+delegate_dmabuf!(State<DrmData>);
@@ -1,15 +1,39 @@
+use std::time::{Duration, Instant};
+
+use drm::control::{connector, crtc};
 use drm_fourcc::DrmFourcc;
 use smithay::{
-    backend::drm::{DrmEvent, DrmNode},
-    reexports::wayland_server::DisplayHandle,
+    backend::drm::{DrmEvent, DrmEventMetadata, DrmEventTime, DrmNode},
+    input::pointer::MotionEvent,
+    output::{Mode, Output},
+    reexports::{
+        calloop::timer::{TimeoutAction, Timer},
+        wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
+    },
+    utils::{Logical, Monotonic, Point, SERIAL_COUNTER},
+    wayland::dmabuf::DmabufFeedbackBuilder,
 };
 use smithay_drm_extras::drm_scanner::DrmScanEvent;
 
 use crate::{
-    backend::drm::{surface::OutputSurface, DrmData},
+    backend::drm::{
+        crtc_allocator::allocate_crtc,
+        primary_render_formats,
+        surface::{connector_name, create_output, read_gamma, OutputSurface},
+        Connector, CrtcFailures, DrmData,
+    },
+    config::{OutputConfig, VrrMode},
+    ipc::Event,
+    output::{queue_redraw, refresh_interval, take_redraw},
     state::State,
 };
 
+/// Frames that fail in a row before the CRTC is set up again from scratch.
+const MAX_FAILED_FRAMES: u32 = 3;
+
+/// Times in a row a CRTC may fail before its output is turned off for good.
+const MAX_CRTC_FAILURES: u32 = 5;
+
 const SUPPORTED_FORMATS: &[DrmFourcc] = &[
     DrmFourcc::Abgr2101010,
     DrmFourcc::Argb2101010,
@@ -18,50 +42,199 @@ const SUPPORTED_FORMATS: &[DrmFourcc] = &[
 ];
 
 impl State<DrmData> {
-    pub fn on_drm_event(&mut self, node: DrmNode, event: DrmEvent) {
+    pub fn on_drm_event(
+        &mut self,
+        node: DrmNode,
+        event: DrmEvent,
+        metadata: &mut Option<DrmEventMetadata>,
+    ) {
         match event {
-            // When a new frame should be rendered.
+            // The next frame is drawn once something changes, see `render_idle_surfaces`.
             DrmEvent::VBlank(crtc) => {
-                if let Some(device) = self.backend_data.devices.get_mut(&node) {
-                    if let Some(surface) = device.surfaces.get_mut(&crtc) {
-                        let mut renderer = if self.backend_data.primary_gpu == device.render_node {
-                            self.backend_data
-                                .gpu_manager
-                                .single_renderer(&device.render_node)
-                                .unwrap()
-                        } else {
-                            self.backend_data
-                                .gpu_manager
-                                .renderer(
-                                    &self.backend_data.primary_gpu,
-                                    &device.render_node,
-                                    &mut device.gbm_allocator,
-                                    surface.gbm_surface.format(),
-                                )
-                                .unwrap()
+                let surface = match self
+                    .backend_data
+                    .devices
+                    .get_mut(&node)
+                    .and_then(|device| device.surfaces.get_mut(&crtc))
+                {
+                    Some(surface) => surface,
+                    None => return,
+                };
+                match surface.compositor.frame_submitted() {
+                    Ok(Some(Some(mut feedback))) => {
+                        // Without a timestamp from the hardware, the time it was received at will
+                        // have to do.
+                        let (time, flags) = match metadata.as_ref().map(|metadata| metadata.time) {
+                            Some(DrmEventTime::Monotonic(time)) => (
+                                time.into(),
+                                wp_presentation_feedback::Kind::Vsync
+                                    | wp_presentation_feedback::Kind::HwClock
+                                    | wp_presentation_feedback::Kind::HwCompletion,
+                            ),
+                            _ => (self.clock.now(), wp_presentation_feedback::Kind::Vsync),
                         };
-                        surface.gbm_surface.frame_submitted().unwrap();
-                        surface.next_buffer(
-                            &self.space,
-                            self.start_time,
-                            &mut renderer,
-                            self.seat.get_pointer().as_ref(),
-                            &self.clock,
-                            self.cursor_status.clone(),
+                        let sequence = metadata.as_ref().map_or(0, |metadata| metadata.sequence);
+                        feedback.presented::<_, Monotonic>(
+                            time,
+                            refresh_interval(&surface.output),
+                            sequence as u64,
+                            flags,
                         );
                     }
+                    Ok(_) => (),
+                    Err(err) => tracing::warn!("Failed to submit frame: {}", err),
+                }
+                surface.frame_pending = false;
+                // Only a frame that has reached the screen proves that the desktop is hidden.
+                if std::mem::take(&mut surface.locked_frame_pending) {
+                    let output = surface.output.clone();
+                    self.lock_frame_rendered(&output);
                 }
             }
             _ => (),
         }
     }
 
-    pub fn on_drm_connector_event(
-        &mut self,
-        dh: &DisplayHandle,
-        node: DrmNode,
-        event: DrmScanEvent,
-    ) {
+    /// Draw the next frame of a CRTC if anything has been damaged, unless one is already waiting
+    /// for its VBlank. Surfaces that wait for a frame callback when nothing has been drawn get it
+    /// after a refresh cycle instead.
+    pub fn render_surface(&mut self, node: DrmNode, crtc: crtc::Handle) {
+        let output = match self
+            .backend_data
+            .devices
+            .get(&node)
+            .and_then(|device| device.surfaces.get(&crtc))
+        {
+            Some(surface) => surface.output.clone(),
+            None => return,
+        };
+        let vrr_mode = self.output_config(&output).vrr;
+        let locked = self.is_locked();
+
+        let device = match self.backend_data.devices.get_mut(&node) {
+            Some(device) => device,
+            None => return,
+        };
+        let surface = match device.surfaces.get_mut(&crtc) {
+            Some(surface) => surface,
+            None => return,
+        };
+        if surface.frame_pending {
+            return;
+        }
+
+        let vrr = match vrr_mode {
+            VrrMode::Always => true,
+            VrrMode::Never => false,
+            VrrMode::Fullscreen => surface.fullscreen_window(&self.space).is_some(),
+        };
+        surface.set_vrr(&device.drm, vrr);
+
+        let renderer = if self.backend_data.primary_gpu == device.render_node {
+            self.backend_data
+                .gpu_manager
+                .single_renderer(&device.render_node)
+        } else {
+            self.backend_data.gpu_manager.renderer(
+                &self.backend_data.primary_gpu,
+                &device.render_node,
+                &mut device.gbm_allocator,
+                surface.compositor.format(),
+            )
+        };
+        let queued = match renderer {
+            Ok(mut renderer) => surface.next_buffer(
+                &self.space,
+                self.start_time,
+                &mut renderer,
+                self.seat.get_pointer().as_ref(),
+                &self.clock,
+                self.cursor_status.clone(),
+                self.config.background_color,
+                &self.lock,
+            ),
+            Err(err) => {
+                tracing::error!("Failed to get a renderer for {}: {}", output.name(), err);
+                surface.failed_frames += 1;
+                false
+            }
+        };
+        surface.frame_pending = queued;
+        surface.locked_frame_pending = queued && locked;
+        if queued {
+            device.failures.remove(&crtc);
+        }
+        if surface.failed_frames >= MAX_FAILED_FRAMES {
+            self.crtc_failed(node, crtc);
+            return;
+        }
+
+        // Sending the frame callbacks right away would have clients that commit without damage
+        // spin, and the event loop with them.
+        if !queued
+            && !surface.frame_callbacks_scheduled
+            && surface.wants_frame(&self.space, &self.lock)
+        {
+            let interval = match refresh_interval(&output) {
+                0 => Duration::from_micros(16_667),
+                interval => Duration::from_nanos(interval as u64),
+            };
+            let timer = self.backend_data.event_loop_handle.insert_source(
+                Timer::from_duration(interval),
+                move |_, _, data| {
+                    data.state.send_idle_frame_callbacks(node, crtc);
+                    TimeoutAction::Drop
+                },
+            );
+            surface.frame_callbacks_scheduled = timer.is_ok();
+        }
+    }
+
+    /// Send the frame callbacks of an output on which nothing has been drawn. An animated cursor
+    /// has it drawn again, clients do by committing.
+    fn send_idle_frame_callbacks(&mut self, node: DrmNode, crtc: crtc::Handle) {
+        if let Some(surface) = self
+            .backend_data
+            .devices
+            .get_mut(&node)
+            .and_then(|device| device.surfaces.get_mut(&crtc))
+        {
+            surface.frame_callbacks_scheduled = false;
+            surface.send_frame_callbacks(&self.space, self.start_time, &self.lock);
+            if surface.cursor_animated {
+                queue_redraw(&surface.output);
+            }
+        }
+    }
+
+    /// Draw the outputs that have changed and are not waiting for a VBlank: those that have been
+    /// queued for a redraw by commits, cursor motion and the like, and those on which windows have
+    /// been rearranged. Called on every event loop iteration, outputs that are still waiting keep
+    /// their redraw for after their VBlank.
+    pub fn render_idle_surfaces(&mut self) {
+        let surfaces = self
+            .backend_data
+            .devices
+            .iter()
+            .flat_map(|(node, device)| {
+                device
+                    .surfaces
+                    .iter()
+                    .filter(|(_, surface)| !surface.frame_pending)
+                    .filter(|(_, surface)| {
+                        take_redraw(&surface.output) || surface.windows_changed(&self.space)
+                    })
+                    .map(|(crtc, _)| (*node, *crtc))
+            })
+            .collect::<Vec<_>>();
+        for (node, crtc) in surfaces {
+            self.render_surface(node, crtc);
+        }
+    }
+
+    /// The CRTCs picked by the scanner are not used: it never gives the CRTC of a connector that
+    /// goes away to one that is waiting for it.
+    pub fn on_drm_connector_event(&mut self, node: DrmNode, event: DrmScanEvent) {
         let device = if let Some(device) = self.backend_data.devices.get_mut(&node) {
             device
         } else {
@@ -69,20 +242,296 @@ impl State<DrmData> {
         };
 
         match event {
-            DrmScanEvent::Connected {
-                connector,
-                crtc: Some(crtc),
-            } => {
-                let mut renderer = self
+            DrmScanEvent::Connected { connector, .. } => {
+                self.connect_connector(node, connector);
+            }
+            DrmScanEvent::Disconnected { connector, .. } => {
+                device
+                    .pending
+                    .retain(|info| info.handle() != connector.handle());
+                if let Some(index) = device
+                    .disabled
+                    .iter()
+                    .position(|c| c.info.handle() == connector.handle())
+                {
+                    let connector = device.disabled.remove(index);
+                    self.remove_drm_output(&connector.output);
+                    return;
+                }
+                let crtc = device
+                    .connectors
+                    .iter()
+                    .find(|(_, c)| c.info.handle() == connector.handle())
+                    .map(|(crtc, _)| *crtc);
+                if let Some(crtc) = crtc {
+                    device.surfaces.remove(&crtc);
+                    if let Some(connector) = device.connectors.remove(&crtc) {
+                        self.remove_drm_output(&connector.output);
+                    }
+                    // The CRTC may be what a pending connector was waiting for.
+                    let pending = self
+                        .backend_data
+                        .devices
+                        .get_mut(&node)
+                        .map(|device| std::mem::take(&mut device.pending))
+                        .unwrap_or_default();
+                    for connector in pending {
+                        self.connect_connector(node, connector);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Give a connector a CRTC and create its output, or leave it pending if there is none left.
+    fn connect_connector(&mut self, node: DrmNode, connector: connector::Info) {
+        let device = match self.backend_data.devices.get_mut(&node) {
+            Some(device) => device,
+            None => return,
+        };
+        let crtc = match allocate_crtc(
+            &device.drm,
+            &mut device.connectors,
+            &mut device.surfaces,
+            &connector,
+        ) {
+            Some(crtc) => crtc,
+            None => {
+                tracing::warn!(
+                    "No CRTC is left for {}, it stays off until another monitor is unplugged",
+                    connector_name(&connector)
+                );
+                device.pending.push(connector);
+                return;
+            }
+        };
+
+        if connector.modes().is_empty() {
+            tracing::warn!(
+                "{} reports no modes, it cannot be turned on",
+                connector_name(&connector)
+            );
+        }
+
+        let output = create_output(&device.drm, &connector);
+        device.connectors.insert(
+            crtc,
+            Connector {
+                info: connector,
+                output: output.clone(),
+                client_gamma: None,
+            },
+        );
+        self.add_output(output.clone());
+        // Turn it on right away if it is enabled.
+        self.apply_drm_output_config();
+
+        let output = self.output_info(&output);
+        self.emit_ipc_event(Event::OutputAdded { output });
+    }
+
+    /// Remove the output of a connector that is gone. The windows and the pointer that were on it
+    /// are moved to the outputs that remain.
+    pub fn remove_drm_output(&mut self, output: &Output) {
+        self.remove_output(output);
+        self.backend_data.gamma_control_state.output_removed(output);
+        self.emit_ipc_event(Event::OutputRemoved {
+            name: output.name(),
+        });
+
+        // Relative motion is only allowed from somewhere on an output, so the pointer would be
+        // stuck where it is.
+        let pointer = match self.seat.get_pointer() {
+            Some(pointer) => pointer,
+            None => return,
+        };
+        if self
+            .space
+            .output_under(pointer.current_location())
+            .next()
+            .is_some()
+        {
+            return;
+        }
+        let geometry = match self
+            .space
+            .outputs()
+            .next()
+            .and_then(|output| self.space.output_geometry(output))
+        {
+            Some(geometry) => geometry,
+            None => return,
+        };
+        let location = Point::<i32, Logical>::from((
+            geometry.loc.x + geometry.size.w / 2,
+            geometry.loc.y + geometry.size.h / 2,
+        ));
+        self.queue_redraw_at(location.to_f64());
+        // The focus follows on the next motion.
+        pointer.motion(
+            self,
+            None,
+            &MotionEvent {
+                location: location.to_f64(),
+                serial: SERIAL_COUNTER.next_serial(),
+                time: Duration::from(self.clock.now()).as_millis() as u32,
+            },
+        );
+    }
+
+    /// Make the CRTCs follow the outputs: drive the enabled ones with their current mode, and turn
+    /// off the others, which give their CRTC back. Cheap when nothing has changed, so it is done
+    /// on every event loop iteration, which catches changes to the config as well as hotplugs.
+    ///
+    /// Returns the outputs that could not be set up. An output whose new mode the device rejects
+    /// keeps its old one, and one that no CRTC is left for is turned back off.
+    pub fn apply_drm_output_config(&mut self) -> Vec<Output> {
+        let mut failed = Vec::new();
+        let mut broken = Vec::new();
+        let mut no_crtc = Vec::new();
+        let mut released = Vec::new();
+        let mut gamma_changed = false;
+        let now = Instant::now();
+        let nodes = self
+            .backend_data
+            .devices
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        for node in nodes {
+            let device = match self.backend_data.devices.get_mut(&node) {
+                Some(device) => device,
+                None => continue,
+            };
+
+            let enabled = |output: &Output| self.space.outputs().any(|o| o == output);
+
+            // Dropping the surface turns the CRTC off.
+            let disabled = device
+                .connectors
+                .iter()
+                .filter(|(_, connector)| !enabled(&connector.output))
+                .map(|(crtc, _)| *crtc)
+                .collect::<Vec<_>>();
+            for crtc in disabled {
+                device.surfaces.remove(&crtc);
+                // It gets a fresh start when it is turned back on.
+                device.failures.remove(&crtc);
+                let connector = device.connectors.remove(&crtc).unwrap();
+                device.disabled.push(connector);
+                released.push(node);
+            }
+            for connector in std::mem::take(&mut device.disabled) {
+                if !enabled(&connector.output) {
+                    device.disabled.push(connector);
+                    continue;
+                }
+                match allocate_crtc(
+                    &device.drm,
+                    &mut device.connectors,
+                    &mut device.surfaces,
+                    &connector.info,
+                ) {
+                    Some(crtc) => {
+                        device.connectors.insert(crtc, connector);
+                    }
+                    None => {
+                        tracing::warn!("No CRTC is left for {}", connector.output.name());
+                        failed.push(connector.output.clone());
+                        no_crtc.push(connector.output.clone());
+                        device.disabled.push(connector);
+                    }
+                }
+            }
+
+            let mut new_surfaces = Vec::new();
+            for (crtc, connector) in &device.connectors {
+                let mode = connector
+                    .output
+                    .current_mode()
+                    .and_then(|mode| {
+                        connector
+                            .info
+                            .modes()
+                            .iter()
+                            .find(|m| Mode::from(**m) == mode)
+                    })
+                    .or_else(|| connector.info.modes().first())
+                    .copied();
+                let mode = match mode {
+                    Some(mode) => mode,
+                    None => continue,
+                };
+
+                match device.surfaces.get_mut(crtc) {
+                    // The device tests the mode before taking it, so the screen is left alone if
+                    // it fails.
+                    Some(surface) if surface.mode != mode => {
+                        match surface.compositor.use_mode(mode) {
+                            Ok(()) => surface.mode = mode,
+                            Err(err) => {
+                                tracing::warn!(
+                                    "Failed to change the mode of {}: {}",
+                                    connector.output.name(),
+                                    err
+                                );
+                                connector.output.change_current_state(
+                                    Some(Mode::from(surface.mode)),
+                                    None,
+                                    None,
+                                    None,
+                                );
+                                failed.push(connector.output.clone());
+                            }
+                        }
+                    }
+                    Some(_) => (),
+                    None if device
+                        .failures
+                        .get(crtc)
+                        .map_or(false, |failures| failures.retry_at > now) => {}
+                    None => new_surfaces.push((*crtc, mode)),
+                }
+            }
+
+            for (crtc, mode) in new_surfaces {
+                let connector = &device.connectors[&crtc];
+                let dmabuf_feedback = DmabufFeedbackBuilder::new(
+                    self.backend_data.primary_gpu.dev_id(),
+                    primary_render_formats(
+                        &mut self.backend_data.gpu_manager,
+                        &self.backend_data.primary_gpu,
+                    ),
+                );
+                let mut renderer = match self
                     .backend_data
                     .gpu_manager
                     .single_renderer(&device.render_node)
-                    .unwrap();
+                {
+                    Ok(renderer) => renderer,
+                    Err(err) => {
+                        tracing::error!(
+                            "Failed to get a renderer for {}: {}",
+                            connector.output.name(),
+                            err
+                        );
+                        failed.push(connector.output.clone());
+                        broken.push((node, crtc));
+                        continue;
+                    }
+                };
 
-                let mut surface = OutputSurface::new(
-                    dh,
+                let original_gamma = device
+                    .original_gamma
+                    .entry(crtc)
+                    .or_insert_with(|| read_gamma(&device.drm, crtc))
+                    .clone();
+
+                let surface = match OutputSurface::new(
                     crtc,
-                    &connector,
+                    &connector.info,
+                    mode,
+                    connector.output.clone(),
                     SUPPORTED_FORMATS,
                     renderer
                         .as_mut()
@@ -91,26 +540,158 @@ impl State<DrmData> {
                         .clone(),
                     &device.drm,
                     device.gbm.clone(),
-                )
-                .unwrap();
-                let output = surface.output.clone();
-                surface.next_buffer(
-                    &self.space,
-                    self.start_time,
-                    &mut renderer,
-                    self.seat.get_pointer().as_ref(),
-                    &self.clock,
-                    self.cursor_status.clone(),
-                );
+                    dmabuf_feedback,
+                    node,
+                    device.render_node,
+                    original_gamma,
+                ) {
+                    Ok(surface) => surface,
+                    Err(err) => {
+                        tracing::error!("Failed to set up {}: {}", connector.output.name(), err);
+                        failed.push(connector.output.clone());
+                        broken.push((node, crtc));
+                        continue;
+                    }
+                };
+                // Its first frame is drawn by `render_idle_surfaces`.
                 device.surfaces.insert(crtc, surface);
-                self.map_output_on_the_right(output);
+                gamma_changed = true;
             }
-            DrmScanEvent::Disconnected {
-                crtc: Some(crtc), ..
-            } => {
-                device.surfaces.remove(&crtc);
+        }
+
+        for (node, crtc) in broken {
+            self.crtc_failed(node, crtc);
+        }
+        // Tried again once the user turns it back on, rather than on every iteration.
+        for output in &no_crtc {
+            let config = OutputConfig {
+                enable: false,
+                ..self.output_config(output)
+            };
+            self.output_overrides.insert(output.name(), config);
+        }
+        if !no_crtc.is_empty() {
+            self.configure_outputs();
+        }
+        // The CRTCs that have been given back may be what pending connectors are waiting for.
+        released.dedup();
+        for node in released {
+            let pending = self
+                .backend_data
+                .devices
+                .get_mut(&node)
+                .map(|device| std::mem::take(&mut device.pending))
+                .unwrap_or_default();
+            for connector in pending {
+                self.connect_connector(node, connector);
             }
-            _ => (),
         }
+        // New CRTCs start with their original gamma ramp.
+        if gamma_changed {
+            self.refresh_gamma();
+        }
+
+        failed
+    }
+
+    /// Answer the output configurations that clients want tested. The CRTCs of the outputs that
+    /// are on test their new mode, the others only need it to be one of their connector. Whether a
+    /// CRTC is left for an output that is turned on is only known when it is applied.
+    pub fn test_drm_output_configs(&mut self) {
+        for test in std::mem::take(&mut self.pending_output_tests) {
+            if self.test_drm_output_config(&test.configs) {
+                test.configuration.succeeded();
+            } else {
+                test.configuration.failed();
+            }
+        }
+    }
+
+    fn test_drm_output_config(&self, configs: &[(Output, OutputConfig)]) -> bool {
+        configs
+            .iter()
+            .filter(|(_, config)| config.enable)
+            .all(|(output, config)| {
+                let mode = match config.mode {
+                    Some(mode) => mode.find(output),
+                    None => output.current_mode(),
+                };
+                let mode = match mode {
+                    Some(mode) => mode,
+                    None => return false,
+                };
+
+                let connector = self.backend_data.devices.values().find_map(|device| {
+                    let disabled = device
+                        .disabled
+                        .iter()
+                        .find(|connector| connector.output == *output)
+                        .map(|connector| (None, connector));
+                    device
+                        .connectors
+                        .iter()
+                        .find(|(_, connector)| connector.output == *output)
+                        .map(|(crtc, connector)| (device.surfaces.get(crtc), connector))
+                        .or(disabled)
+                });
+                let (surface, connector) = match connector {
+                    Some(connector) => connector,
+                    None => return false,
+                };
+                let mode = match connector
+                    .info
+                    .modes()
+                    .iter()
+                    .find(|m| Mode::from(**m) == mode)
+                {
+                    Some(mode) => *mode,
+                    None => return false,
+                };
+                surface.map_or(true, |surface| surface.test_mode(mode))
+            })
+    }
+
+    /// Give up on the current state of a CRTC that failed to be set up or to show frames. It is
+    /// set up again from scratch after a delay that grows with each failure in a row, and its
+    /// output is turned off after too many, until it is turned back on by the user.
+    fn crtc_failed(&mut self, node: DrmNode, crtc: crtc::Handle) {
+        let device = match self.backend_data.devices.get_mut(&node) {
+            Some(device) => device,
+            None => return,
+        };
+        device.surfaces.remove(&crtc);
+        let output = match device.connectors.get(&crtc) {
+            Some(connector) => connector.output.clone(),
+            None => return,
+        };
+        let failures = device.failures.entry(crtc).or_insert(CrtcFailures {
+            count: 0,
+            retry_at: Instant::now(),
+        });
+        failures.count += 1;
+
+        if failures.count >= MAX_CRTC_FAILURES {
+            tracing::error!(
+                "{} failed {} times in a row, turning it off",
+                output.name(),
+                failures.count
+            );
+            let config = OutputConfig {
+                enable: false,
+                ..self.output_config(&output)
+            };
+            self.output_overrides.insert(output.name(), config);
+            self.configure_outputs();
+            return;
+        }
+
+        let delay = Duration::from_secs(failures.count as u64);
+        failures.retry_at = Instant::now() + delay;
+        tracing::warn!("Trying {} again in {:?}", output.name(), delay);
+        // Wakes the event loop up, for `apply_drm_output_config` to try again.
+        self.backend_data
+            .event_loop_handle
+            .insert_source(Timer::from_duration(delay), |_, _, _| TimeoutAction::Drop)
+            .ok();
     }
 }
@@ -3,9 +3,14 @@ use std::{collections::HashSet, time::Duration};
 use crate::{
     backend::Error,
     cursor::{CursorElement, PointerRenderElement},
+    handlers::{send_layer_frames, LockState, LOCKED_COLOR},
+    output::{queue_redraw, take_presentation_feedback, OutputSerial},
     state::State,
+    window::WindowElement,
+};
+use drm::control::{
+    self, connector, crtc, property, Device as ControlDevice, ModeTypeFlags, ResourceHandle,
 };
-use drm::control::{connector, crtc, ModeTypeFlags};
 use drm_fourcc::{DrmFormat, DrmFourcc};
 
 use smithay::{
@@ -14,127 +19,457 @@ use smithay::{
             dmabuf::Dmabuf,
             gbm::{GbmAllocator, GbmBufferFlags, GbmDevice},
         },
-        drm::{DrmDevice, DrmDeviceFd, GbmBufferedSurface},
+        drm::{compositor::DrmCompositor, DrmDevice, DrmDeviceFd, DrmNode, DrmSurface},
         renderer::{
-            damage::OutputDamageTracker, element::AsRenderElements, Bind, ImportAll, ImportMem,
-            Renderer,
+            element::{surface::WaylandSurfaceRenderElement, AsRenderElements},
+            gles::GlesTexture,
+            Bind, ExportMem, ImportAll, ImportMem, Offscreen, Renderer, Texture,
+        },
+    },
+    desktop::{
+        layer_map_for_output,
+        space::{space_render_elements, SpaceElement, SpaceRenderElements},
+        utils::{
+            select_dmabuf_feedback, surface_primary_scanout_output, with_surfaces_surface_tree,
+            OutputPresentationFeedback,
         },
+        Space,
     },
-    desktop::{space::render_output, utils::surface_primary_scanout_output, Space, Window},
     input::pointer::{CursorImageStatus, PointerHandle},
     output::{Mode, Output, PhysicalProperties, Scale, Subpixel},
-    reexports::wayland_server::DisplayHandle,
-    utils::{Clock, Monotonic, Transform},
-    wayland::compositor::{self, SurfaceData},
+    reexports::{
+        wayland_protocols::{
+            wp::linux_dmabuf::zv1::server::zwp_linux_dmabuf_feedback_v1::TrancheFlags,
+            xdg::shell::server::xdg_toplevel,
+        },
+        wayland_server::protocol::wl_surface::WlSurface,
+    },
+    render_elements,
+    utils::{Clock, Logical, Monotonic, Point, Transform},
+    wayland::{
+        compositor::{self, SurfaceAttributes, SurfaceData},
+        dmabuf::{DmabufFeedback, DmabufFeedbackBuilder},
+        shell::wlr_layer::Layer,
+    },
 };
 use smithay_drm_extras::edid::EdidInfo;
 use std::time::Instant;
 
 use super::DrmData;
 
+/// Composites the outputs of a device. Client buffers that fit are put on the primary or an
+/// overlay plane of the CRTC instead, and the cursor on its cursor plane. Each frame carries the
+/// presentation feedback of its surfaces until its VBlank.
+pub type GbmDrmCompositor = DrmCompositor<
+    GbmAllocator<DrmDeviceFd>,
+    GbmDevice<DrmDeviceFd>,
+    Option<OutputPresentationFeedback>,
+    DrmDeviceFd,
+>;
+
+render_elements! {
+    pub OutputRenderElement<R> where R: ImportAll + ImportMem;
+    Pointer = PointerRenderElement<R>,
+    Space = SpaceRenderElements<R, WaylandSurfaceRenderElement<R>>,
+    Surface = WaylandSurfaceRenderElement<R>,
+}
+
 pub struct OutputSurface {
-    pub gbm_surface: GbmBufferedSurface<GbmAllocator<DrmDeviceFd>, ()>,
+    pub compositor: GbmDrmCompositor,
     pub output: Output,
-    pub damage_tracked_renderer: OutputDamageTracker,
+    /// The mode the CRTC is driven with.
+    pub mode: control::Mode,
     pub cursor: CursorElement,
+    /// The `VRR_ENABLED` property of the CRTC, if the monitor is capable of variable refresh.
+    vrr_enabled: Option<property::Handle>,
+    /// Whether variable refresh is on.
+    pub vrr: bool,
+    /// A frame has been queued and its VBlank has not come yet.
+    pub frame_pending: bool,
+    /// The pending frame was drawn while the session is locked. The locker is only told once it
+    /// is on screen, see `State::lock_frame_rendered`.
+    pub locked_frame_pending: bool,
+    /// Frame callbacks will be sent after a refresh cycle, as nothing has been drawn.
+    pub frame_callbacks_scheduled: bool,
+    /// The last frame showed an animated cursor, which has to be redrawn even without damage.
+    pub cursor_animated: bool,
+    /// The windows the last frame showed and where, to tell when some have been mapped, moved,
+    /// raised or unmapped, which their clients don't commit for.
+    drawn_windows: Vec<(WindowElement, Point<i32, Logical>)>,
+    /// How many frames in a row could not be drawn or queued.
+    pub failed_frames: u32,
+    /// The gamma ramp of the CRTC before the compositor touched it, if it could be read.
+    original_gamma: Option<Vec<u16>>,
+    /// The gamma ramp set by a client or the night light, `None` for the original one.
+    pub gamma: Option<Vec<u16>>,
+    /// `None` if the feedback could not be built, then clients get the default one.
+    pub dmabuf_feedback: Option<SurfaceDmabufFeedback>,
+}
+
+/// The dmabuf feedback sent to the surfaces shown on an output.
+pub struct SurfaceDmabufFeedback {
+    /// For surfaces that are composited: buffers the renderer of the device can read.
+    pub render: DmabufFeedback,
+    /// For surfaces that could be scanned out: buffers the planes of the CRTC can show first.
+    pub scanout: DmabufFeedback,
+}
+
+impl SurfaceDmabufFeedback {
+    /// Build on the default feedback of the primary GPU, preferring the renderer of the device
+    /// driving the output, and the formats its planes support for scanout.
+    fn new(
+        default: DmabufFeedbackBuilder,
+        drm_surface: &DrmSurface,
+        node: DrmNode,
+        render_node: DrmNode,
+        render_formats: &HashSet<DrmFormat>,
+    ) -> Option<Self> {
+        let planes = drm_surface.planes();
+        let plane_formats = std::iter::once(&planes.primary)
+            .chain(planes.overlay.iter())
+            .flat_map(|plane| {
+                drm_surface
+                    .supported_formats(plane.handle)
+                    .unwrap_or_default()
+            })
+            .filter(|format| render_formats.contains(format))
+            .collect::<HashSet<_>>();
+
+        let render = default
+            .clone()
+            .add_preference_tranche(render_node.dev_id(), None, render_formats.clone())
+            .build()
+            .ok()?;
+        let scanout = default
+            .add_preference_tranche(node.dev_id(), Some(TrancheFlags::Scanout), plane_formats)
+            .add_preference_tranche(render_node.dev_id(), None, render_formats.clone())
+            .build()
+            .ok()?;
+
+        Some(Self { render, scanout })
+    }
+}
+
+/// Create the output of a connector, with every mode it supports. The preferred mode is the
+/// current one until the config says otherwise.
+pub fn create_output(drm: &DrmDevice, connector: &connector::Info) -> Output {
+    let name = connector_name(connector);
+
+    let (make, model) = EdidInfo::for_connector(drm, connector.handle())
+        .map(|info| (info.manufacturer, info.model))
+        .unwrap_or(("Unknown".into(), "Unknown".into()));
+
+    let (width, height) = connector.size().unwrap_or((0, 0));
+    let output = Output::new(
+        name,
+        PhysicalProperties {
+            size: (width as i32, height as i32).into(),
+            subpixel: Subpixel::Unknown,
+            make,
+            model,
+        },
+    );
+    if let Some(serial) = edid_serial(drm, connector.handle()) {
+        output
+            .user_data()
+            .insert_if_missing_threadsafe(|| OutputSerial(serial));
+    }
+
+    for mode in connector.modes() {
+        output.add_mode(Mode::from(*mode));
+    }
+    // If no mode is marked with PREFERRED, the first one is used.
+    let preferred_mode = connector
+        .modes()
+        .iter()
+        .find(|m| m.mode_type().contains(ModeTypeFlags::PREFERRED))
+        .or_else(|| connector.modes().first());
+    if let Some(mode) = preferred_mode {
+        output.set_preferred(Mode::from(*mode));
+        output.change_current_state(
+            Some(Mode::from(*mode)),
+            Some(Transform::Normal),
+            // Set from the config, which may make it fractional.
+            Some(Scale::Fractional(1.0)),
+            None,
+        );
+    }
+
+    output
+}
+
+/// The name of a connector, like `DP-1`, which is also the name of its output.
+pub fn connector_name(connector: &connector::Info) -> String {
+    format!(
+        "{}-{}",
+        connector.interface().as_str(),
+        connector.interface_id()
+    )
+}
+
+/// The handle and value of a property of a DRM object.
+fn find_property(
+    drm: &DrmDevice,
+    object: impl ResourceHandle,
+    name: &str,
+) -> Option<(property::Handle, property::RawValue)> {
+    let properties = drm.get_properties(object).ok()?;
+    let (handles, values) = properties.as_props_and_values();
+    handles.iter().zip(values).find_map(|(handle, value)| {
+        let info = drm.get_property(*handle).ok()?;
+        (info.name().to_str() == Ok(name)).then_some((*handle, *value))
+    })
+}
+
+/// The number of entries per channel of the gamma ramp of a CRTC, 0 if it has none.
+pub fn gamma_size(drm: &DrmDevice, crtc: crtc::Handle) -> usize {
+    drm.get_crtc(crtc)
+        .map_or(0, |info| info.gamma_length() as usize)
+}
+
+/// The gamma ramp of a CRTC, red then green then blue.
+pub fn read_gamma(drm: &DrmDevice, crtc: crtc::Handle) -> Option<Vec<u16>> {
+    let size = gamma_size(drm, crtc);
+    if size == 0 {
+        return None;
+    }
+    let mut ramp = vec![0; size * 3];
+    let (red, rest) = ramp.split_at_mut(size);
+    let (green, blue) = rest.split_at_mut(size);
+    drm.get_gamma(crtc, red, green, blue).ok()?;
+    Some(ramp)
+}
+
+/// The serial number of the monitor on a connector, read from its EDID. The text descriptor is
+/// preferred over the number in the header, which many monitors leave empty.
+fn edid_serial(drm: &DrmDevice, connector: connector::Handle) -> Option<String> {
+    let (_, blob) = find_property(drm, connector, "EDID")?;
+    let edid = drm.get_property_blob(blob).ok()?;
+    serial_from_edid(&edid)
+}
+
+fn serial_from_edid(edid: &[u8]) -> Option<String> {
+    if edid.len() < 128 {
+        return None;
+    }
+
+    // The four 18-byte display descriptors, 0xff is the serial number.
+    let text = (54..126).step_by(18).find_map(|offset| {
+        let descriptor = &edid[offset..offset + 18];
+        (descriptor[..3] == [0, 0, 0] && descriptor[3] == 0xff).then(|| {
+            String::from_utf8_lossy(&descriptor[5..])
+                .trim_end_matches(|c: char| c == '\n' || c == ' ' || c == '\0')
+                .to_string()
+        })
+    });
+
+    text.filter(|serial| !serial.is_empty()).or_else(|| {
+        let number = u32::from_le_bytes([edid[12], edid[13], edid[14], edid[15]]);
+        (number != 0).then(|| number.to_string())
+    })
 }
 
 impl OutputSurface {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        dh: &DisplayHandle,
         crtc: crtc::Handle,
         connector: &connector::Info,
+        mode: control::Mode,
+        output: Output,
         color_formats: &[DrmFourcc],
         renderer_formats: HashSet<DrmFormat>,
         drm: &DrmDevice,
         gbm: GbmDevice<DrmDeviceFd>,
+        dmabuf_feedback: DmabufFeedbackBuilder,
+        node: DrmNode,
+        render_node: DrmNode,
+        original_gamma: Option<Vec<u16>>,
     ) -> Result<Self, Error> {
-        // Find the position of the preferred mode.
-        // If no mode is marked with PREFERRED, the first (index 0) will be selected.
-        let mode_id = connector
-            .modes()
-            .iter()
-            .position(|m| m.mode_type().contains(ModeTypeFlags::PREFERRED))
-            .unwrap_or(0);
-
-        let preferred_mode = connector.modes()[mode_id];
-
         let drm_surface = drm
-            .create_surface(crtc, preferred_mode, &[connector.handle()])
+            .create_surface(crtc, mode, &[connector.handle()])
             .or_else(|_| {
                 tracing::error!("Failed to create drm surface");
                 Err(Error::DrmSurfaceCreateFailure)
             })?;
 
-        let gbm_surface = GbmBufferedSurface::new(
+        let vrr_capable = find_property(drm, connector.handle(), "vrr_capable")
+            .map_or(false, |(_, value)| value == 1);
+        let vrr_enabled = vrr_capable
+            .then(|| find_property(drm, crtc, "VRR_ENABLED"))
+            .flatten()
+            .map(|(handle, _)| handle);
+
+        let dmabuf_feedback = SurfaceDmabufFeedback::new(
+            dmabuf_feedback,
+            &drm_surface,
+            node,
+            render_node,
+            &renderer_formats,
+        );
+        if dmabuf_feedback.is_none() {
+            tracing::warn!("Failed to build the dmabuf feedback of {}", output.name());
+        }
+
+        let compositor = DrmCompositor::new(
+            &output,
             drm_surface,
-            GbmAllocator::new(gbm, GbmBufferFlags::RENDERING | GbmBufferFlags::SCANOUT),
+            None,
+            GbmAllocator::new(
+                gbm.clone(),
+                GbmBufferFlags::RENDERING | GbmBufferFlags::SCANOUT,
+            ),
+            gbm.clone(),
             color_formats,
             renderer_formats,
+            drm.cursor_size(),
+            Some(gbm),
         )
         .or_else(|_| {
-            tracing::error!("Failed to create gbm surface");
-            Err(Error::GbmSurfaceCreateFailure)
+            tracing::error!("Failed to create drm compositor");
+            Err(Error::DrmCompositorCreateFailure)
         })?;
 
-        let name = format!(
-            "{}-{}",
-            connector.interface().as_str(),
-            connector.interface_id()
-        );
-
-        let (make, model) = EdidInfo::for_connector(drm, connector.handle())
-            .map(|info| (info.manufacturer, info.model))
-            .unwrap_or(("Unknown".into(), "Unknown".into()));
-
-        let (width, height) = connector.size().unwrap_or((0, 0));
-        let output = Output::new(
-            name,
-            PhysicalProperties {
-                size: (width as i32, height as i32).into(),
-                subpixel: Subpixel::Unknown,
-                make,
-                model,
-            },
-        );
-
-        let output_mode = Mode::from(preferred_mode);
-        output.set_preferred(output_mode);
-        output.create_global::<State<DrmData>>(&dh);
-        output.change_current_state(
-            Some(output_mode),
-            Some(Transform::Normal),
-            // TODO: Scale will be set here.
-            Some(Scale::Integer(1)),
-            None,
-        );
-
-        let damage_tracked_renderer = OutputDamageTracker::from_output(&output);
-
+        queue_redraw(&output);
         Ok(Self {
-            gbm_surface,
+            compositor,
             output,
-            damage_tracked_renderer,
+            mode,
             cursor: CursorElement::new().map_err(|err| Error::CursorLoadError(err))?,
+            vrr_enabled,
+            vrr: false,
+            frame_pending: false,
+            locked_frame_pending: false,
+            frame_callbacks_scheduled: false,
+            cursor_animated: false,
+            drawn_windows: Vec::new(),
+            failed_frames: 0,
+            original_gamma,
+            gamma: None,
+            dmabuf_feedback,
         })
     }
 
-    /// Draw a frame and queue the buffer.
+    /// Set the gamma ramp of the CRTC, red then green then blue, or put back the one it had
+    /// before the compositor touched it if `gamma` is `None`. Returns whether it worked.
+    pub fn set_gamma(&mut self, drm: &DrmDevice, gamma: Option<Vec<u16>>) -> bool {
+        if let Err(err) = self.write_gamma(drm, gamma.as_deref()) {
+            tracing::warn!("Failed to set the gamma of {}: {}", self.output.name(), err);
+            return false;
+        }
+        self.gamma = gamma;
+        true
+    }
+
+    /// Set the gamma ramp of the CRTC again, whatever the last DRM master left.
+    pub fn restore_gamma(&self, drm: &DrmDevice) {
+        if let Err(err) = self.write_gamma(drm, self.gamma.as_deref()) {
+            tracing::warn!(
+                "Failed to restore the gamma of {}: {}",
+                self.output.name(),
+                err
+            );
+        }
+    }
+
+    /// The identity stands in for the original ramp if it could not be read.
+    fn write_gamma(&self, drm: &DrmDevice, gamma: Option<&[u16]>) -> std::io::Result<()> {
+        let size = gamma_size(drm, self.compositor.crtc());
+        if size < 2 {
+            return Ok(());
+        }
+        let identity;
+        let ramp = match gamma.or(self.original_gamma.as_deref()) {
+            Some(ramp) => ramp,
+            None => {
+                identity = (0..3)
+                    .flat_map(|_| (0..size).map(|i| (i * u16::MAX as usize / (size - 1)) as u16))
+                    .collect::<Vec<_>>();
+                &identity
+            }
+        };
+        if ramp.len() != size * 3 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the gamma ramp does not fit the CRTC",
+            ));
+        }
+
+        let (red, rest) = ramp.split_at(size);
+        let (green, blue) = rest.split_at(size);
+        drm.set_gamma(self.compositor.crtc(), red, green, blue)
+    }
+
+    /// Whether the CRTC could be driven with a mode. The device tests it with a test-only commit,
+    /// the mode only becomes pending if it passes, and is put back right away.
+    pub fn test_mode(&self, mode: control::Mode) -> bool {
+        if mode == self.mode {
+            return true;
+        }
+        let surface = self.compositor.surface();
+        match surface.use_mode(mode) {
+            Ok(()) => {
+                if let Err(err) = surface.use_mode(self.mode) {
+                    tracing::warn!(
+                        "Failed to restore the mode of {}: {}",
+                        self.output.name(),
+                        err
+                    );
+                }
+                true
+            }
+            Err(err) => {
+                tracing::debug!("{} rejects {:?}: {}", self.output.name(), mode, err);
+                false
+            }
+        }
+    }
+
+    /// Turn variable refresh on or off. Left off on monitors that are not capable of it.
+    pub fn set_vrr(&mut self, drm: &DrmDevice, vrr: bool) {
+        let property = match self.vrr_enabled {
+            Some(property) => property,
+            None => return,
+        };
+        if self.vrr == vrr {
+            return;
+        }
+        match drm.set_property(self.compositor.crtc(), property, vrr as property::RawValue) {
+            Ok(()) => self.vrr = vrr,
+            Err(err) => tracing::warn!(
+                "Failed to turn {} variable refresh on {}: {}",
+                if vrr { "on" } else { "off" },
+                self.output.name(),
+                err
+            ),
+        }
+    }
+
+    /// Draw a frame and queue it, then send frame callbacks. The cursor is left to the cursor plane
+    /// if it fits, so that when only the pointer has moved, nothing is redrawn. Returns whether a
+    /// frame was queued, there is none when nothing has changed.
+    #[allow(clippy::too_many_arguments)]
     pub fn next_buffer<R>(
         &mut self,
-        space: &Space<Window>,
+        space: &Space<WindowElement>,
         start_time: Instant,
         renderer: &mut R,
         pointer: Option<&PointerHandle<State<DrmData>>>,
         clock: &Clock<Monotonic>,
         cursor_status: CursorImageStatus,
-    ) where
-        R: Renderer + ImportAll + ImportMem + Bind<Dmabuf>,
-        R::TextureId: 'static + Clone,
+        clear_color: [f32; 4],
+        lock: &LockState,
+    ) -> bool
+    where
+        R: Renderer
+            + ImportAll
+            + ImportMem
+            + ExportMem
+            + Bind<Dmabuf>
+            + Bind<GlesTexture>
+            + Offscreen<GlesTexture>,
+        R::TextureId: Texture + Clone + 'static,
+        R::Error: Send + Sync + 'static,
     {
-        let dmabuf = self.gbm_surface.next_buffer().unwrap().0;
-        renderer.bind(dmabuf).unwrap();
-
         let cursor_elements = match pointer {
             Some(pointer) => {
                 if space
@@ -160,25 +495,95 @@ impl OutputSurface {
             None => Vec::new(),
         };
 
-        let res = render_output::<_, PointerRenderElement<R>, _, _>(
-            &self.output,
+        self.cursor_animated = !cursor_elements.is_empty() && self.cursor.is_animated();
+        self.drawn_windows = self.shown_windows(space);
+
+        // The cursor comes first, the compositor only puts the topmost element on the cursor plane.
+        let mut elements = cursor_elements
+            .into_iter()
+            .map(OutputRenderElement::from)
+            .collect::<Vec<_>>();
+        let clear_color =
+            match lock.render_elements::<_, PointerRenderElement<R>>(renderer, &self.output) {
+                Some(lock_elements) => {
+                    elements.extend(lock_elements.into_iter().map(OutputRenderElement::from));
+                    LOCKED_COLOR
+                }
+                None => match self.fullscreen_window(space) {
+                    // Only what is above the window is drawn along with it, so that its
+                    // buffer can be scanned out.
+                    Some(window) => {
+                        elements.extend(self.fullscreen_render_elements(renderer, space, &window));
+                        clear_color
+                    }
+                    None => {
+                        let space_elements =
+                            space_render_elements(renderer, [space], &self.output, 1.0)
+                                .unwrap_or_default();
+                        elements.extend(space_elements.into_iter().map(OutputRenderElement::from));
+                        clear_color
+                    }
+                },
+            };
+
+        let res = match self.compositor.render_frame::<_, _, GlesTexture>(
             renderer,
-            1.0,
-            0,
-            [space],
-            cursor_elements.as_slice(),
-            &mut self.damage_tracked_renderer,
-            [0.1, 0.1, 0.1, 1.0],
-        )
-        .unwrap();
+            &elements,
+            clear_color,
+        ) {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::error!("Failed to render {}: {}", self.output.name(), err);
+                self.failed_frames += 1;
+                return false;
+            }
+        };
 
-        for window in space.elements() {
-            let output = compositor::with_states(window.toplevel().wl_surface(), |states| {
-                let data = states.data_map.get::<SurfaceData>().unwrap();
-                surface_primary_scanout_output(window.toplevel().wl_surface(), data)
-            });
+        // Also finds out which output each surface is mostly on, for its frame callbacks.
+        let feedback = take_presentation_feedback(&self.output, space, &res.states);
+
+        if let Some(feedback) = &self.dmabuf_feedback {
+            for window in space.elements() {
+                if self.is_primary_output(window) {
+                    window.send_dmabuf_feedback(
+                        &self.output,
+                        |_, _| Some(self.output.clone()),
+                        |surface, _| {
+                            select_dmabuf_feedback(
+                                surface,
+                                &res.states,
+                                &feedback.render,
+                                &feedback.scanout,
+                            )
+                        },
+                    );
+                }
+            }
+        }
+
+        if res.is_empty {
+            self.failed_frames = 0;
+            return false;
+        }
+        if let Err(err) = self.compositor.queue_frame(Some(feedback)) {
+            tracing::error!("Failed to queue a frame on {}: {}", self.output.name(), err);
+            self.failed_frames += 1;
+            return false;
+        }
+        self.failed_frames = 0;
+        self.send_frame_callbacks(space, start_time, lock);
+        true
+    }
 
-            if output == Some(self.output.clone()) {
+    /// Let the surfaces shown on the output draw their next frame.
+    pub fn send_frame_callbacks(
+        &self,
+        space: &Space<WindowElement>,
+        start_time: Instant,
+        lock: &LockState,
+    ) {
+        for window in space.elements() {
+            if self.is_primary_output(window) {
                 window.send_frame(
                     &self.output,
                     start_time.elapsed(),
@@ -187,7 +592,176 @@ impl OutputSurface {
                 );
             }
         }
+        send_layer_frames(&self.output, start_time.elapsed());
+        lock.send_frames(&self.output, start_time.elapsed());
+    }
+
+    /// Whether the output has to be drawn again after a while even though nothing has been
+    /// damaged: surfaces on it wait for frame callbacks, or the cursor is animated.
+    pub fn wants_frame(&self, space: &Space<WindowElement>, lock: &LockState) -> bool {
+        if self.cursor_animated {
+            return true;
+        }
+
+        let mut waiting = false;
+        let mut check = |_: &WlSurface, states: &SurfaceData| {
+            waiting |= !states
+                .cached_state
+                .current::<SurfaceAttributes>()
+                .frame_callbacks
+                .is_empty();
+        };
+        for window in space.elements() {
+            if self.is_primary_output(window) {
+                window.with_surfaces(&mut check);
+            }
+        }
+        for layer in layer_map_for_output(&self.output).layers() {
+            layer.with_surfaces(&mut check);
+        }
+        if let Some(surface) = lock.surface(&self.output) {
+            with_surfaces_surface_tree(surface.wl_surface(), &mut check);
+        }
+        waiting
+    }
+
+    /// The windows on the output and where they are, from the bottom up.
+    fn shown_windows(
+        &self,
+        space: &Space<WindowElement>,
+    ) -> Vec<(WindowElement, Point<i32, Logical>)> {
+        space
+            .elements_for_output(&self.output)
+            .filter_map(|window| Some((window.clone(), space.element_location(window)?)))
+            .collect()
+    }
+
+    /// Whether windows have been mapped, moved, raised or unmapped since the last frame.
+    pub fn windows_changed(&self, space: &Space<WindowElement>) -> bool {
+        self.shown_windows(space) != self.drawn_windows
+    }
+
+    /// Whether a window is mostly shown on this output, which is then the one to send it frame
+    /// callbacks and feedback.
+    fn is_primary_output(&self, window: &WindowElement) -> bool {
+        let output = window.wl_surface().and_then(|surface| {
+            compositor::with_states(&surface, |states| {
+                surface_primary_scanout_output(&surface, states)
+            })
+        });
+        output.as_ref() == Some(&self.output)
+    }
+
+    /// The fullscreen window of the output, if it is the topmost window there.
+    pub fn fullscreen_window(&self, space: &Space<WindowElement>) -> Option<WindowElement> {
+        let output_geometry = space.output_geometry(&self.output)?;
+        space
+            .elements()
+            .filter(|window| {
+                space
+                    .element_geometry(window)
+                    .map_or(false, |geometry| geometry.overlaps(output_geometry))
+            })
+            .last()
+            .filter(|window| window.has_state(xdg_toplevel::State::Fullscreen))
+            .cloned()
+    }
+
+    /// A fullscreen window with the overlay layer on top of it.
+    fn fullscreen_render_elements<R>(
+        &self,
+        renderer: &mut R,
+        space: &Space<WindowElement>,
+        window: &WindowElement,
+    ) -> Vec<OutputRenderElement<R>>
+    where
+        R: Renderer + ImportAll + ImportMem,
+        R::TextureId: Texture + Clone + 'static,
+    {
+        let scale = self.output.current_scale().fractional_scale();
+        let output_location = space
+            .output_geometry(&self.output)
+            .map(|geometry| geometry.loc)
+            .unwrap_or_default();
+
+        let layer_map = layer_map_for_output(&self.output);
+        let mut elements = layer_map
+            .layers_on(Layer::Overlay)
+            .rev()
+            .flat_map(|layer| {
+                let location = layer_map
+                    .layer_geometry(layer)
+                    .map(|geometry| geometry.loc)
+                    .unwrap_or_default();
+                layer.render_elements::<OutputRenderElement<R>>(
+                    renderer,
+                    location.to_physical_precise_round(scale),
+                    scale.into(),
+                    1.0,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(location) = space.element_location(window) {
+            let location = location - window.geometry().loc - output_location;
+            elements.extend(window.render_elements::<OutputRenderElement<R>>(
+                renderer,
+                location.to_physical_precise_round(scale),
+                scale.into(),
+                1.0,
+            ));
+        }
+        elements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serial_from_edid;
+
+    /// An EDID block with a serial number in its header and a display descriptor.
+    fn edid(number: u32, descriptor: [u8; 18]) -> Vec<u8> {
+        let mut edid = vec![0; 128];
+        edid[..8].copy_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+        edid[12..16].copy_from_slice(&number.to_le_bytes());
+        edid[72..90].copy_from_slice(&descriptor);
+        edid
+    }
+
+    fn serial_descriptor(text: &[u8]) -> [u8; 18] {
+        let mut descriptor = [0; 18];
+        descriptor[3] = 0xff;
+        descriptor[5..].fill(b' ');
+        descriptor[5..5 + text.len()].copy_from_slice(text);
+        descriptor
+    }
+
+    #[test]
+    fn serial_descriptor_wins() {
+        let edid = edid(1234, serial_descriptor(b"ABC123\n"));
+        assert_eq!(serial_from_edid(&edid).as_deref(), Some("ABC123"));
+    }
+
+    #[test]
+    fn header_number_without_descriptor() {
+        let edid = edid(1234, [0; 18]);
+        assert_eq!(serial_from_edid(&edid).as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn header_number_with_empty_descriptor() {
+        let edid = edid(1234, serial_descriptor(b"\n"));
+        assert_eq!(serial_from_edid(&edid).as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn no_serial() {
+        assert_eq!(serial_from_edid(&edid(0, [0; 18])), None);
+    }
 
-        self.gbm_surface.queue_buffer(None, res.damage, ()).ok();
+    #[test]
+    fn truncated() {
+        let edid = edid(1234, serial_descriptor(b"ABC123\n"));
+        assert_eq!(serial_from_edid(&edid[..100]), None);
     }
 }
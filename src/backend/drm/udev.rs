@@ -1,4 +1,7 @@
-use std::{os::fd::FromRawFd, path::PathBuf};
+use std::{
+    os::fd::{FromRawFd, RawFd},
+    path::Path,
+};
 
 use crate::{
     backend::drm::{Device, DrmData},
@@ -10,27 +13,48 @@ use smithay::{
             dmabuf::DmabufAllocator,
             gbm::{GbmAllocator, GbmBufferFlags, GbmDevice},
         },
-        drm::{DrmDevice, DrmDeviceFd, DrmNode},
+        drm::{DrmDevice, DrmDeviceFd, DrmError, DrmNode},
         egl::{EGLDevice, EGLDisplay},
-        session::Session,
+        session::{libseat, Session},
         udev::UdevEvent,
     },
-    reexports::{nix::fcntl::OFlag, wayland_server::DisplayHandle},
+    reexports::nix::fcntl::OFlag,
     utils::DeviceFd,
 };
 use smithay_drm_extras::drm_scanner::DrmScanner;
 
+/// Why a GPU could not be used. It is left alone, the others keep going.
+#[derive(Debug, thiserror::Error)]
+enum DeviceError {
+    #[error("Failed to open the device: {0}")]
+    OpenFailure(libseat::Error),
+
+    #[error("Failed to create the DRM device: {0}")]
+    DrmDeviceCreateFailure(DrmError),
+
+    #[error("Failed to create the GBM device: {0}")]
+    GbmDeviceCreateFailure(std::io::Error),
+
+    #[error("Failed to add the renderer of the device: {0}")]
+    RendererAddFailure(String),
+
+    #[error("Failed to insert the events of the device to the event loop")]
+    SourceInsertFailure,
+}
+
 impl State<DrmData> {
-    pub fn on_udev_event(&mut self, dh: &DisplayHandle, event: UdevEvent) {
+    pub fn on_udev_event(&mut self, event: UdevEvent) {
         match event {
             UdevEvent::Added { device_id, path } => {
                 if let Ok(node) = DrmNode::from_dev_id(device_id) {
-                    self.on_device_added(dh, node, path);
+                    if let Err(err) = self.on_device_added(node, &path) {
+                        tracing::error!("Skipping GPU {}: {}", path.display(), err);
+                    }
                 }
             }
             UdevEvent::Changed { device_id } => {
                 if let Ok(node) = DrmNode::from_dev_id(device_id) {
-                    self.on_device_changed(dh, node);
+                    self.on_device_changed(node);
                 }
             }
             UdevEvent::Removed { device_id } => {
@@ -41,73 +65,128 @@ impl State<DrmData> {
         }
     }
 
-    fn on_device_added(&mut self, dh: &DisplayHandle, node: DrmNode, path: PathBuf) {
+    fn on_device_added(&mut self, node: DrmNode, path: &Path) -> Result<(), DeviceError> {
         let fd = self
             .backend_data
             .session
             .open(
-                &path,
+                path,
                 OFlag::O_RDWR | OFlag::O_CLOEXEC | OFlag::O_NOCTTY | OFlag::O_NONBLOCK,
             )
-            .unwrap();
-        let fd = DrmDeviceFd::new(unsafe { DeviceFd::from_raw_fd(fd) });
+            .map_err(DeviceError::OpenFailure)?;
+
+        // Whatever has been created is dropped on the way out if a later step fails, the device
+        // is handed back to the session here.
+        let result = self.init_device(node, fd);
+        if result.is_err() {
+            if let Err(err) = self.backend_data.session.close(fd) {
+                tracing::warn!("Failed to close {}: {}", path.display(), err);
+            }
+        }
+        result
+    }
 
-        let (drm, drm_notifier) = DrmDevice::new(fd, false).unwrap();
+    /// Set up a GPU that has been opened.
+    fn init_device(&mut self, node: DrmNode, raw_fd: RawFd) -> Result<(), DeviceError> {
+        let fd = DrmDeviceFd::new(unsafe { DeviceFd::from_raw_fd(raw_fd) });
+        let (drm, drm_notifier) =
+            DrmDevice::new(fd, false).map_err(DeviceError::DrmDeviceCreateFailure)?;
 
-        let gbm = GbmDevice::new(drm.device_fd().clone()).unwrap();
+        let gbm =
+            GbmDevice::new(drm.device_fd().clone()).map_err(DeviceError::GbmDeviceCreateFailure)?;
         let gbm_allocator = GbmAllocator::new(gbm.clone(), GbmBufferFlags::RENDERING);
 
-        let render_node =
-            match EGLDevice::device_for_display(&EGLDisplay::new(gbm.clone()).unwrap())
-                .ok()
-                .and_then(|x| x.try_get_render_node().ok().flatten())
-            {
-                Some(node) => node,
-                None => node,
-            };
+        let render_node = EGLDisplay::new(gbm.clone())
+            .ok()
+            .and_then(|display| EGLDevice::device_for_display(&display).ok())
+            .and_then(|x| x.try_get_render_node().ok().flatten())
+            .unwrap_or(node);
 
         self.backend_data
             .gpu_manager
             .as_mut()
             .add_node(render_node, gbm.clone())
-            .unwrap();
+            .map_err(|err| DeviceError::RendererAddFailure(err.to_string()))?;
 
-        self.backend_data
-            .event_loop_handle
-            .insert_source(drm_notifier, move |event, _, data| {
-                data.state.on_drm_event(node, event);
-            })
-            .unwrap();
+        let notifier = match self.backend_data.event_loop_handle.insert_source(
+            drm_notifier,
+            move |event, metadata, data| {
+                data.state.on_drm_event(node, event, metadata);
+            },
+        ) {
+            Ok(notifier) => notifier,
+            Err(_) => {
+                self.backend_data
+                    .gpu_manager
+                    .as_mut()
+                    .remove_node(&render_node);
+                return Err(DeviceError::SourceInsertFailure);
+            }
+        };
 
         self.backend_data.devices.insert(
             node,
             Device {
+                fd: raw_fd,
                 drm,
                 gbm,
                 gbm_allocator: DmabufAllocator(gbm_allocator),
+                connectors: Default::default(),
+                disabled: Vec::new(),
                 surfaces: Default::default(),
+                pending: Vec::new(),
+                failures: Default::default(),
+                original_gamma: Default::default(),
                 render_node,
                 drm_scanner: DrmScanner::new(),
+                notifier,
             },
         );
 
-        self.on_device_changed(dh, node);
+        self.on_device_changed(node);
+        Ok(())
     }
 
-    fn on_device_changed(&mut self, dh: &DisplayHandle, node: DrmNode) {
+    /// Look for connectors that have been plugged in or out.
+    pub fn on_device_changed(&mut self, node: DrmNode) {
         if let Some(device) = self.backend_data.devices.get_mut(&node) {
             for event in device.drm_scanner.scan_connectors(&device.drm) {
-                self.on_drm_connector_event(dh, node, event);
+                self.on_drm_connector_event(node, event);
             }
         }
     }
 
+    /// Tear down a GPU that is gone, with the outputs of its connectors.
     fn on_device_removed(&mut self, node: DrmNode) {
-        if let Some(device) = self.backend_data.devices.get_mut(&node) {
-            self.backend_data
-                .gpu_manager
-                .as_mut()
-                .remove_node(&device.render_node);
+        let mut device = match self.backend_data.devices.remove(&node) {
+            Some(device) => device,
+            None => return,
+        };
+        if device.render_node == self.backend_data.primary_gpu {
+            tracing::error!("The primary GPU {} has been removed", node);
+        }
+
+        // The CRTCs go first, while the device is still there to turn them off.
+        device.surfaces.clear();
+        for (_, connector) in device.connectors.drain() {
+            self.remove_drm_output(&connector.output);
+        }
+        for connector in device.disabled.drain(..) {
+            self.remove_drm_output(&connector.output);
+        }
+
+        self.backend_data.event_loop_handle.remove(device.notifier);
+        self.backend_data
+            .gpu_manager
+            .as_mut()
+            .remove_node(&device.render_node);
+
+        // Handed back to the session once nothing uses it anymore, so that it can be opened again
+        // when it comes back.
+        let fd = device.fd;
+        drop(device);
+        if let Err(err) = self.backend_data.session.close(fd) {
+            tracing::warn!("Failed to close GPU {}: {}", node, err);
         }
     }
 }
@@ -0,0 +1,379 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    time::Duration,
+};
+
+use smithay::{
+    backend::{
+        allocator::Fourcc,
+        egl::{EGLContext, EGLDevice, EGLDisplay},
+        renderer::{
+            damage::OutputDamageTracker,
+            element::surface::WaylandSurfaceRenderElement,
+            gles::{GlesRenderbuffer, GlesRenderer},
+            Bind, ExportMem, Offscreen,
+        },
+    },
+    desktop::space::render_output,
+    output::{Mode, Output, PhysicalProperties, Subpixel},
+    reexports::{
+        calloop::{
+            self,
+            generic::Generic,
+            timer::{TimeoutAction, Timer},
+            EventLoop, Interest, PostAction,
+        },
+        wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
+        wayland_server::Display,
+    },
+    utils::{Monotonic, Physical, Rectangle, Size, Transform},
+};
+
+use crate::{
+    backend::Error,
+    data::Data,
+    handlers::{send_layer_frames, LOCKED_COLOR},
+    init_wayland_socket,
+    output::{refresh_interval, take_presentation_feedback},
+    state::State,
+};
+
+/// Number of virtual outputs created when `ALIOTH_HEADLESS_OUTPUTS` is not set.
+const DEFAULT_OUTPUT_COUNT: usize = 1;
+
+/// Size of every virtual output when `ALIOTH_HEADLESS_SIZE` is not set.
+const DEFAULT_OUTPUT_SIZE: (i32, i32) = (1920, 1080);
+
+/// A virtual output, rendered into an offscreen buffer.
+struct HeadlessOutput {
+    output: Output,
+    damage_tracker: OutputDamageTracker,
+    buffer: GlesRenderbuffer,
+    /// Number of frames that have been dumped so far.
+    frame_count: u64,
+}
+
+pub struct HeadlessData {
+    renderer: GlesRenderer,
+    outputs: Vec<HeadlessOutput>,
+    /// Where rendered frames are written to, if anywhere.
+    dump_dir: Option<PathBuf>,
+}
+
+pub fn run_headless_backend(
+    socket_name: Option<&str>,
+    config_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    let mut event_loop = EventLoop::<Data<HeadlessData>>::try_new().or_else(|_| {
+        tracing::error!("Failed to create event loop");
+        Err(Error::EventLoopCreateFailure)
+    })?;
+
+    // Create a Wayland display.
+    // Displays are all about the Wayland protocol and do no rendering.
+    let mut display = Display::<State<HeadlessData>>::new().or_else(|_| {
+        tracing::error!("Failed to create display");
+        Err(Error::DisplayCreateFailure)
+    })?;
+
+    // Create a Unix socket for clients to connect to.
+    let socket = init_wayland_socket(&mut event_loop, socket_name).or_else(|_| {
+        tracing::error!("Failed to create Wayland socket");
+        Err(Error::SocketCreateFailure)
+    })?;
+
+    // Insert the display to the event loop.
+    event_loop
+        .handle()
+        .insert_source(
+            Generic::new(
+                display.backend().poll_fd().as_raw_fd(),
+                Interest::READ,
+                calloop::Mode::Level,
+            ),
+            |_, _, data| {
+                // Handle the events from the display, once.
+                if let Err(err) = data.display.dispatch_clients(&mut data.state) {
+                    tracing::error!("Failed to dispatch clients: {}", err);
+                }
+                // Then we continue listening for other events.
+                Ok(PostAction::Continue)
+            },
+        )
+        .or_else(|_| {
+            tracing::error!("Failed to insert the display to the event loop");
+            Err(Error::SourceInsertFailure)
+        })?;
+
+    let mut renderer = init_software_renderer()?;
+
+    let size = output_size_from_env();
+    let count = std::env::var("ALIOTH_HEADLESS_OUTPUTS")
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_OUTPUT_COUNT);
+
+    let mut outputs = Vec::with_capacity(count);
+    for index in 1..=count {
+        let buffer = Offscreen::<GlesRenderbuffer>::create_buffer(
+            &mut renderer,
+            Fourcc::Abgr8888,
+            (size.w, size.h).into(),
+        )
+        .or_else(|_| {
+            tracing::error!("Failed to create offscreen buffer");
+            Err(Error::RendererCreateFailure)
+        })?;
+
+        let output = Output::new(
+            format!("HEADLESS-{}", index),
+            PhysicalProperties {
+                size: (0, 0).into(),
+                subpixel: Subpixel::Unknown,
+                make: "alioth".into(),
+                model: "Alioth Headless Output".into(),
+            },
+        );
+        let damage_tracker = OutputDamageTracker::from_output(&output);
+
+        outputs.push(HeadlessOutput {
+            output,
+            damage_tracker,
+            buffer,
+            frame_count: 0,
+        });
+    }
+
+    let dump_dir = std::env::var_os("ALIOTH_HEADLESS_DUMP_DIR").map(PathBuf::from);
+    if let Some(dir) = &dump_dir {
+        std::fs::create_dir_all(dir).ok();
+    }
+
+    let backend_data = HeadlessData {
+        renderer,
+        outputs,
+        dump_dir,
+    };
+    let mut state = State::new(&display, &mut event_loop, backend_data, config_path)
+        .map_err(|err| Error::StateCreateFailure(err))?;
+
+    let mode = Mode {
+        size,
+        refresh: state.config.refresh_rate,
+    };
+    let new_outputs: Vec<Output> = state
+        .backend_data
+        .outputs
+        .iter()
+        .map(|headless| headless.output.clone())
+        .collect();
+    for output in new_outputs {
+        output.change_current_state(Some(mode), Some(Transform::Normal), None, None);
+        output.set_preferred(mode);
+        state.add_output(output);
+    }
+
+    // Render all outputs at a fixed rate.
+    event_loop
+        .handle()
+        .insert_source(Timer::immediate(), |_, _, data| {
+            data.state.render_headless_outputs();
+
+            data.state.space.refresh();
+            data.state.popups.cleanup();
+            if let Err(err) = data.display.flush_clients() {
+                tracing::warn!("Failed to flush clients: {}", err);
+            }
+
+            TimeoutAction::ToDuration(Duration::from_micros(
+                1_000_000_000 / data.state.config.refresh_rate.max(1) as u64,
+            ))
+        })
+        .map_err(|_| Error::SourceInsertFailure)?;
+
+    std::env::set_var("WAYLAND_DISPLAY", &socket);
+    tracing::info!("Listening on Wayland socket {}", socket);
+
+    let mut data = Data { display, state };
+    event_loop
+        .run(None, &mut data, |data| {
+            data.state.refresh_output_management(&[]);
+            data.state.refresh_x11_windows();
+            data.state.refresh_surface_scales();
+            data.state.refresh_ext_workspace();
+            if let Err(err) = data.display.flush_clients() {
+                tracing::warn!("Failed to flush clients: {}", err);
+            }
+        })
+        .or_else(|_| {
+            tracing::error!("Failed to run the event loop");
+            Err(Error::EventLoopRunFailure)
+        })?;
+
+    Ok(())
+}
+
+impl State<HeadlessData> {
+    /// Draw a frame on every virtual output and send frame callbacks.
+    fn render_headless_outputs(&mut self) {
+        let backend_data = &mut self.backend_data;
+        let mut rendered = Vec::new();
+
+        for headless in backend_data.outputs.iter_mut() {
+            if let Err(err) = backend_data.renderer.bind(headless.buffer.clone()) {
+                tracing::error!("Failed to bind offscreen buffer: {}", err);
+                continue;
+            }
+
+            let renderer = &mut backend_data.renderer;
+            let res = match self
+                .lock
+                .render_elements::<_, WaylandSurfaceRenderElement<GlesRenderer>>(
+                    renderer,
+                    &headless.output,
+                ) {
+                Some(elements) => {
+                    headless
+                        .damage_tracker
+                        .render_output(renderer, 0, &elements, LOCKED_COLOR)
+                }
+                None => render_output::<_, WaylandSurfaceRenderElement<GlesRenderer>, _, _>(
+                    &headless.output,
+                    renderer,
+                    1.0,
+                    0,
+                    [&self.space],
+                    &[],
+                    &mut headless.damage_tracker,
+                    self.config.background_color,
+                ),
+            };
+            let res = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    tracing::error!("Failed to render {}: {:?}", headless.output.name(), err);
+                    continue;
+                }
+            };
+            rendered.push(headless.output.clone());
+            // Nothing is shown anywhere, the frame counts as presented once it is drawn.
+            take_presentation_feedback(&headless.output, &self.space, &res.states)
+                .presented::<_, Monotonic>(
+                    self.clock.now(),
+                    refresh_interval(&headless.output),
+                    0,
+                    wp_presentation_feedback::Kind::empty(),
+                );
+
+            // Only frames that actually changed are worth being written out.
+            if res.damage.is_some() {
+                if let Some(dir) = &backend_data.dump_dir {
+                    let path = dir.join(format!(
+                        "{}-{:06}.ppm",
+                        headless.output.name(),
+                        headless.frame_count
+                    ));
+                    if let Err(err) =
+                        dump_frame(&mut backend_data.renderer, &headless.output, &path)
+                    {
+                        tracing::error!("Failed to dump frame to {}: {}", path.display(), err);
+                    }
+                    headless.frame_count += 1;
+                }
+            }
+
+            for window in self.space.elements() {
+                window.send_frame(
+                    &headless.output,
+                    self.start_time.elapsed(),
+                    Some(Duration::ZERO),
+                    |_, _| Some(headless.output.clone()),
+                );
+            }
+            send_layer_frames(&headless.output, self.start_time.elapsed());
+            self.lock
+                .send_frames(&headless.output, self.start_time.elapsed());
+        }
+
+        for output in rendered {
+            self.lock_frame_rendered(&output);
+        }
+    }
+}
+
+/// Create a GLES renderer on top of Mesa's software EGL device (llvmpipe), so no GPU is required.
+/// Smithay has no pixman renderer at the pinned revision, so machines running the headless
+/// backend, CI included, need Mesa with `EGL_MESA_device_software`.
+fn init_software_renderer() -> Result<GlesRenderer, Error> {
+    let device = EGLDevice::enumerate()
+        .or_else(|_| {
+            tracing::error!("Failed to enumerate EGL devices");
+            Err(Error::NoSoftwareRendererFound)
+        })?
+        .find(|device| {
+            device
+                .extensions()
+                .iter()
+                .any(|ext| ext == "EGL_MESA_device_software")
+        })
+        .ok_or_else(|| {
+            tracing::error!("No software EGL device found");
+            Error::NoSoftwareRendererFound
+        })?;
+
+    let display = EGLDisplay::new(device).or_else(|_| {
+        tracing::error!("Failed to create EGL display");
+        Err(Error::RendererCreateFailure)
+    })?;
+    let context = EGLContext::new(&display).or_else(|_| {
+        tracing::error!("Failed to create EGL context");
+        Err(Error::RendererCreateFailure)
+    })?;
+
+    unsafe { GlesRenderer::new(context) }.or_else(|_| {
+        tracing::error!("Failed to create renderer");
+        Err(Error::RendererCreateFailure)
+    })
+}
+
+/// Parse `ALIOTH_HEADLESS_SIZE`, which looks like `1280x720`.
+fn output_size_from_env() -> Size<i32, Physical> {
+    std::env::var("ALIOTH_HEADLESS_SIZE")
+        .ok()
+        .and_then(|size| {
+            let (w, h) = size.split_once('x')?;
+            Some((w.trim().parse::<i32>().ok()?, h.trim().parse::<i32>().ok()?))
+        })
+        .filter(|(w, h)| *w > 0 && *h > 0)
+        .unwrap_or(DEFAULT_OUTPUT_SIZE)
+        .into()
+}
+
+/// Read back the currently bound buffer and write it as a binary PPM image.
+fn dump_frame(
+    renderer: &mut GlesRenderer,
+    output: &Output,
+    path: &PathBuf,
+) -> Result<(), anyhow::Error> {
+    let size = output
+        .current_mode()
+        .map(|mode| mode.size)
+        .unwrap_or_default();
+    let region = Rectangle::from_loc_and_size((0, 0), (size.w, size.h));
+
+    let mapping = renderer.copy_framebuffer(region, Fourcc::Abgr8888)?;
+    let pixels = renderer.map_texture(&mapping)?;
+
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", size.w, size.h)?;
+    // Abgr8888 is laid out as R, G, B, A in memory. PPM has no alpha channel.
+    for pixel in pixels.chunks_exact(4) {
+        file.write_all(&pixel[..3])?;
+    }
+    file.flush()?;
+
+    Ok(())
+}
@@ -1,12 +1,19 @@
 mod drm;
+mod headless;
 mod winit;
+mod x11;
+
+use std::path::PathBuf;
 
 use crate::{
     cursor,
     state::{self},
 };
 
-use self::{drm::run_drm_backend, winit::run_winit_backend};
+use self::{
+    drm::run_drm_backend, headless::run_headless_backend, winit::run_winit_backend,
+    x11::run_x11_backend,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -16,14 +23,17 @@ pub enum Error {
     #[error("Failed to create Wayland display")]
     DisplayCreateFailure,
 
+    #[error("Failed to create drm compositor")]
+    DrmCompositorCreateFailure,
+
     #[error("Failed to create drm surface")]
     DrmSurfaceCreateFailure,
 
     #[error("Failed to create event loop")]
     EventLoopCreateFailure,
 
-    #[error("Failed to create gbm surface")]
-    GbmSurfaceCreateFailure,
+    #[error("Failed to run event loop")]
+    EventLoopRunFailure,
 
     #[error("Failed to create GPU manager")]
     GpuManagerCreateFailure,
@@ -31,9 +41,15 @@ pub enum Error {
     #[error("No GPU found")]
     NoGPUFound,
 
+    #[error("No software renderer found")]
+    NoSoftwareRendererFound,
+
     #[error("Failed to get the path of the primary GPU")]
     PrimaryGPUGetFailure,
 
+    #[error("Failed to create renderer")]
+    RendererCreateFailure,
+
     #[error("Failed to initialize session")]
     SessionInitFailure,
 
@@ -51,18 +67,88 @@ pub enum Error {
 
     #[error("Failed to initialize Udev backend")]
     UdevInitFailure,
+
+    #[error("Failed to initialize Winit backend")]
+    WinitInitFailure,
+
+    #[error("Failed to initialize X11 backend")]
+    X11InitFailure,
 }
 
-pub fn run_backend_auto() -> Result<(), Box<dyn std::error::Error>> {
-    if let Ok(wayland_display) = std::env::var("WAYLAND_DISPLAY") {
-        if wayland_display != "" {
-            run_winit_backend()?;
-        } else {
-            run_drm_backend()?;
+impl Error {
+    /// The exit code of the process when the compositor fails with this error, so that session
+    /// scripts can tell the failure modes apart:
+    ///
+    /// * 1: the event loop could not be set up or run.
+    /// * 3: the Wayland display or socket could not be created.
+    /// * 4: the session, seat or input devices could not be acquired.
+    /// * 5: no usable GPU or renderer was found.
+    /// * 6: the outputs could not be set up.
+    /// * 7: the compositor state could not be created.
+    ///
+    /// 2 is left out on purpose, since it is used for command-line usage errors.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::EventLoopCreateFailure
+            | Error::EventLoopRunFailure
+            | Error::SourceInsertFailure => 1,
+            Error::DisplayCreateFailure | Error::SocketCreateFailure => 3,
+            Error::LibinputAssignSeatFailure
+            | Error::SessionInitFailure
+            | Error::UdevInitFailure => 4,
+            Error::GpuManagerCreateFailure
+            | Error::NoGPUFound
+            | Error::NoSoftwareRendererFound
+            | Error::PrimaryGPUGetFailure
+            | Error::RendererCreateFailure
+            | Error::WinitInitFailure
+            | Error::X11InitFailure => 5,
+            Error::CursorLoadError(_)
+            | Error::DrmCompositorCreateFailure
+            | Error::DrmSurfaceCreateFailure => 6,
+            Error::StateCreateFailure(_) => 7,
         }
-    } else {
-        run_drm_backend()?;
     }
+}
 
-    Ok(())
+/// The backends that can be chosen from the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// Run on bare metal, from a TTY.
+    Drm,
+    /// Run as a window inside another Wayland compositor.
+    Winit,
+    /// Run as one or more windows inside an X server.
+    X11,
+    /// Run without any display or GPU, using a software renderer.
+    Headless,
+}
+
+/// Run the given backend. If `backend` is `None`, it is guessed from the environment.
+/// The headless backend is never guessed, it has to be asked for explicitly.
+pub fn run_backend(
+    backend: Option<Backend>,
+    socket_name: Option<&str>,
+    config_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    match backend.unwrap_or_else(guess_backend) {
+        Backend::Drm => run_drm_backend(socket_name, config_path),
+        Backend::Winit => run_winit_backend(socket_name, config_path),
+        Backend::X11 => run_x11_backend(socket_name, config_path),
+        Backend::Headless => run_headless_backend(socket_name, config_path),
+    }
+}
+
+/// Use the Winit backend inside another Wayland compositor, the X11 one inside an X server, and
+/// the DRM one otherwise.
+fn guess_backend() -> Backend {
+    let is_set = |var| matches!(std::env::var(var), Ok(value) if value != "");
+
+    if is_set("WAYLAND_DISPLAY") {
+        Backend::Winit
+    } else if is_set("DISPLAY") {
+        Backend::X11
+    } else {
+        Backend::Drm
+    }
 }
@@ -1,4 +1,4 @@
-use std::{os::fd::AsRawFd, time::Duration};
+use std::{os::fd::AsRawFd, path::PathBuf, time::Duration};
 
 use smithay::{
     backend::{
@@ -17,18 +17,31 @@ use smithay::{
             timer::{TimeoutAction, Timer},
             EventLoop, Interest, PostAction,
         },
+        wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
         wayland_server::Display,
     },
-    utils::{Rectangle, Transform},
+    utils::{Monotonic, Rectangle, Transform},
 };
 
-use crate::{backend::Error, data::Data, init_wayland_socket, input::Action, state::State};
+use crate::{
+    action::Action,
+    backend::Error,
+    data::Data,
+    handlers::{send_layer_frames, LOCKED_COLOR},
+    init_wayland_socket,
+    output::{refresh_interval, take_presentation_feedback},
+    state::State,
+};
 use smithay::backend::winit;
 
-const REFRESH_RATE: i32 = 60_000;
-
-pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
-    let mut event_loop = EventLoop::<Data<()>>::try_new()?;
+pub fn run_winit_backend(
+    socket_name: Option<&str>,
+    config_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    let mut event_loop = EventLoop::<Data<()>>::try_new().or_else(|_| {
+        tracing::error!("Failed to create event loop");
+        Err(Error::EventLoopCreateFailure)
+    })?;
     // Create a Wayland display.
     // Displays are all about the Wayland protocol and do no rendering.
     let mut display = Display::<State<()>>::new().or_else(|_| {
@@ -37,7 +50,7 @@ pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
     })?;
 
     // Create a Unix socket for clients to connect to.
-    let socket = init_wayland_socket(&mut event_loop).or_else(|_| {
+    let socket = init_wayland_socket(&mut event_loop, socket_name).or_else(|_| {
         tracing::error!("Failed to create Wayland socket");
         Err(Error::SocketCreateFailure)
     })?;
@@ -54,7 +67,9 @@ pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
             ),
             |_, _, data| {
                 // Handle the events from the display, once.
-                data.display.dispatch_clients(&mut data.state).unwrap();
+                if let Err(err) = data.display.dispatch_clients(&mut data.state) {
+                    tracing::error!("Failed to dispatch clients: {}", err);
+                }
                 // Then we continue listening for other events.
                 Ok(PostAction::Continue)
             },
@@ -64,17 +79,18 @@ pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
             Err(Error::SourceInsertFailure)
         })?;
 
-    let dh = display.handle();
-    let mut state =
-        State::new(&display, &mut event_loop, ()).map_err(|e| Error::StateCreateFailure(e))?;
+    let mut state = State::new(&display, &mut event_loop, (), config_path)
+        .map_err(|e| Error::StateCreateFailure(e))?;
 
-    let (mut backend, mut winit) = winit::init::<GlesRenderer>()?;
+    let (mut backend, mut winit) = winit::init::<GlesRenderer>().or_else(|_| {
+        tracing::error!("Failed to initialize Winit backend");
+        Err(Error::WinitInitFailure)
+    })?;
 
     let size = backend.window_size().physical_size;
     let mode = output::Mode {
         size,
-        // Aka 60Hz.
-        refresh: REFRESH_RATE,
+        refresh: state.config.refresh_rate,
     };
 
     // Properties of the output.
@@ -88,17 +104,10 @@ pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
 
     // Create an output.
     let output = output::Output::new("alioth".to_string(), physical_properties);
-    // An output is also a global object.
-    output.create_global::<State<()>>(&dh);
-    output.change_current_state(
-        Some(mode),
-        Some(Transform::Flipped180),
-        None,
-        Some((0, 0).into()),
-    );
+    output.change_current_state(Some(mode), Some(Transform::Flipped180), None, None);
     // Set the preferred mode of the output.
     output.set_preferred(mode);
-    state.space.map_output(&output, (0, 0));
+    state.add_output(output.clone());
 
     let mut damage_tracker = OutputDamageTracker::from_output(&output);
 
@@ -114,7 +123,7 @@ pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
                     output.change_current_state(
                         Some(output::Mode {
                             size,
-                            refresh: REFRESH_RATE,
+                            refresh: state.config.refresh_rate,
                         }),
                         None,
                         None,
@@ -124,11 +133,11 @@ pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
                 WinitEvent::Input(event) => {
                     let action = state.handle_input(event);
                     match action {
-                        // It doesn't make sense to change VT on the Winit backend.
-                        Action::ChangeVt(_) | Action::None => (),
                         Action::Quit => {
                             state.loop_signal.stop();
                         }
+                        // It doesn't make sense to change VT on the Winit backend.
+                        _ => (),
                     }
                 }
                 _ => (),
@@ -141,18 +150,37 @@ pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
             let damage = Rectangle::from_loc_and_size((0, 0), size);
 
             backend.bind().unwrap();
-            render_output::<_, WaylandSurfaceRenderElement<GlesRenderer>, _, _>(
-                &output,
-                backend.renderer(),
-                1.0,
-                0,
-                [&state.space],
-                &[],
-                &mut damage_tracker,
-                [0.1, 0.1, 0.1, 1.0],
-            )
-            .unwrap();
+            let renderer = backend.renderer();
+            let res = match state
+                .lock
+                .render_elements::<_, WaylandSurfaceRenderElement<GlesRenderer>>(renderer, &output)
+            {
+                Some(elements) => damage_tracker
+                    .render_output(renderer, 0, &elements, LOCKED_COLOR)
+                    .unwrap(),
+                None => render_output::<_, WaylandSurfaceRenderElement<GlesRenderer>, _, _>(
+                    &output,
+                    renderer,
+                    1.0,
+                    0,
+                    [&state.space],
+                    &[],
+                    &mut damage_tracker,
+                    state.config.background_color,
+                )
+                .unwrap(),
+            };
             backend.submit(Some(&[damage])).unwrap();
+            state.lock_frame_rendered(&output);
+
+            // The host compositor does not tell when the frame is shown, it is about now.
+            take_presentation_feedback(&output, &state.space, &res.states)
+                .presented::<_, Monotonic>(
+                    state.clock.now(),
+                    refresh_interval(&output),
+                    0,
+                    wp_presentation_feedback::Kind::Vsync,
+                );
 
             for window in state.space.elements() {
                 window.send_frame(
@@ -162,19 +190,37 @@ pub fn run_winit_backend() -> Result<(), Box<dyn std::error::Error>> {
                     |_, _| Some(output.clone()),
                 );
             }
+            send_layer_frames(&output, state.start_time.elapsed());
+            state.lock.send_frames(&output, state.start_time.elapsed());
 
             state.space.refresh();
             state.popups.cleanup();
-            display.flush_clients().unwrap();
+            if let Err(err) = display.flush_clients() {
+                tracing::warn!("Failed to flush clients: {}", err);
+            }
 
             TimeoutAction::ToDuration(Duration::from_millis(16))
-        })?;
+        })
+        .map_err(|_| Error::SourceInsertFailure)?;
 
     std::env::set_var("WAYLAND_DISPLAY", &socket);
 
     // Pack event loop data.
     let mut data = Data { display, state };
-    event_loop.run(None, &mut data, |_| {})?;
+    event_loop
+        .run(None, &mut data, |data| {
+            data.state.refresh_output_management(&[]);
+            data.state.refresh_x11_windows();
+            data.state.refresh_surface_scales();
+            data.state.refresh_ext_workspace();
+            if let Err(err) = data.display.flush_clients() {
+                tracing::warn!("Failed to flush clients: {}", err);
+            }
+        })
+        .or_else(|_| {
+            tracing::error!("Failed to run the event loop");
+            Err(Error::EventLoopRunFailure)
+        })?;
 
     Ok(())
 }
@@ -0,0 +1,463 @@
+use std::{collections::HashSet, os::fd::AsRawFd, path::PathBuf, time::Duration};
+
+use smithay::{
+    backend::{
+        allocator::{
+            dmabuf::{Dmabuf, DmabufAllocator},
+            gbm::{GbmAllocator, GbmBufferFlags, GbmDevice},
+            Format,
+        },
+        drm::{DrmNode, NodeType},
+        egl::{EGLContext, EGLDisplay},
+        input::InputEvent,
+        renderer::{
+            damage::OutputDamageTracker, element::surface::WaylandSurfaceRenderElement,
+            gles::GlesRenderer, Bind, ImportDma,
+        },
+        x11::{Window as X11Window, WindowBuilder, X11Backend, X11Event, X11Input, X11Surface},
+    },
+    delegate_dmabuf,
+    desktop::space::render_output,
+    output::{Mode, Output, PhysicalProperties, Subpixel},
+    reexports::{
+        calloop::{self, generic::Generic, EventLoop, Interest, PostAction},
+        wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
+        wayland_server::{Display, DisplayHandle},
+    },
+    utils::{DeviceFd, Monotonic, Transform},
+    wayland::dmabuf::{
+        DmabufFeedbackBuilder, DmabufGlobal, DmabufHandler, DmabufState, ImportError,
+    },
+};
+
+use crate::{
+    action::Action,
+    backend::Error,
+    data::Data,
+    handlers::{send_layer_frames, LOCKED_COLOR},
+    init_wayland_socket,
+    ipc::Event,
+    output::{refresh_interval, take_presentation_feedback},
+    state::State,
+};
+
+/// Number of host windows created when `ALIOTH_X11_OUTPUTS` is not set.
+const DEFAULT_OUTPUT_COUNT: usize = 1;
+
+/// A host window, shown as an output.
+struct X11Output {
+    window: X11Window,
+    surface: X11Surface,
+    output: Output,
+    damage_tracker: OutputDamageTracker,
+}
+
+pub struct X11Data {
+    renderer: GlesRenderer,
+    outputs: Vec<X11Output>,
+    dmabuf_state: DmabufState,
+}
+
+pub fn run_x11_backend(
+    socket_name: Option<&str>,
+    config_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    let mut event_loop = EventLoop::<Data<X11Data>>::try_new().or_else(|_| {
+        tracing::error!("Failed to create event loop");
+        Err(Error::EventLoopCreateFailure)
+    })?;
+
+    // Create a Wayland display.
+    // Displays are all about the Wayland protocol and do no rendering.
+    let mut display = Display::<State<X11Data>>::new().or_else(|_| {
+        tracing::error!("Failed to create display");
+        Err(Error::DisplayCreateFailure)
+    })?;
+
+    // Create a Unix socket for clients to connect to.
+    let socket = init_wayland_socket(&mut event_loop, socket_name).or_else(|_| {
+        tracing::error!("Failed to create Wayland socket");
+        Err(Error::SocketCreateFailure)
+    })?;
+
+    // Insert the display to the event loop.
+    event_loop
+        .handle()
+        .insert_source(
+            Generic::new(
+                display.backend().poll_fd().as_raw_fd(),
+                Interest::READ,
+                calloop::Mode::Level,
+            ),
+            |_, _, data| {
+                // Handle the events from the display, once.
+                if let Err(err) = data.display.dispatch_clients(&mut data.state) {
+                    tracing::error!("Failed to dispatch clients: {}", err);
+                }
+                // Then we continue listening for other events.
+                Ok(PostAction::Continue)
+            },
+        )
+        .or_else(|_| {
+            tracing::error!("Failed to insert the display to the event loop");
+            Err(Error::SourceInsertFailure)
+        })?;
+
+    // Connect to the X server.
+    let backend = X11Backend::new().or_else(|_| {
+        tracing::error!("Failed to initialize X11 backend");
+        Err(Error::X11InitFailure)
+    })?;
+    let handle = backend.handle();
+
+    // Find the GPU the X server renders on.
+    let (node, fd) = handle.drm_node().or_else(|_| {
+        tracing::error!("Failed to get the DRM node of the X server");
+        Err(Error::NoGPUFound)
+    })?;
+    let gbm = GbmDevice::new(DeviceFd::from(fd)).or_else(|_| {
+        tracing::error!("Failed to create GBM device");
+        Err(Error::NoGPUFound)
+    })?;
+    let egl = EGLDisplay::new(gbm.clone()).or_else(|_| {
+        tracing::error!("Failed to create EGL display");
+        Err(Error::RendererCreateFailure)
+    })?;
+    let context = EGLContext::new(&egl).or_else(|_| {
+        tracing::error!("Failed to create EGL context");
+        Err(Error::RendererCreateFailure)
+    })?;
+    let render_formats = context.dmabuf_render_formats().clone();
+    let modifiers = render_formats
+        .iter()
+        .map(|format| format.modifier)
+        .collect::<HashSet<_>>();
+    let renderer = unsafe { GlesRenderer::new(context) }.or_else(|_| {
+        tracing::error!("Failed to create renderer");
+        Err(Error::RendererCreateFailure)
+    })?;
+
+    let count = std::env::var("ALIOTH_X11_OUTPUTS")
+        .ok()
+        .and_then(|x| x.parse::<usize>().ok())
+        .filter(|count| *count > 0)
+        .unwrap_or(DEFAULT_OUTPUT_COUNT);
+
+    // Create the host windows, each with its own surface to render into.
+    let mut outputs = Vec::with_capacity(count);
+    for index in 1..=count {
+        let window = WindowBuilder::new()
+            .title(&format!("Alioth ({})", index))
+            .build(&handle)
+            .or_else(|_| {
+                tracing::error!("Failed to create X11 window");
+                Err(Error::X11InitFailure)
+            })?;
+        let surface = handle
+            .create_surface(
+                &window,
+                DmabufAllocator(GbmAllocator::new(gbm.clone(), GbmBufferFlags::RENDERING)),
+                modifiers.iter().cloned(),
+            )
+            .or_else(|_| {
+                tracing::error!("Failed to create X11 surface");
+                Err(Error::X11InitFailure)
+            })?;
+
+        let output = Output::new(
+            format!("X11-{}", index),
+            PhysicalProperties {
+                size: (0, 0).into(),
+                subpixel: Subpixel::Unknown,
+                make: "alioth".into(),
+                model: "Alioth X11 Output".into(),
+            },
+        );
+        let damage_tracker = OutputDamageTracker::from_output(&output);
+
+        outputs.push(X11Output {
+            window,
+            surface,
+            output,
+            damage_tracker,
+        });
+    }
+
+    let backend_data = X11Data {
+        renderer,
+        outputs,
+        dmabuf_state: DmabufState::new(),
+    };
+    let mut state = State::new(&display, &mut event_loop, backend_data, config_path)
+        .map_err(|err| Error::StateCreateFailure(err))?;
+
+    let dh = display.handle();
+    state.init_x11_dmabuf_global(&dh, node, render_formats.into_iter().collect());
+
+    let new_outputs: Vec<(X11Window, Output)> = state
+        .backend_data
+        .outputs
+        .iter()
+        .map(|x11| (x11.window.clone(), x11.output.clone()))
+        .collect();
+    for (window, output) in new_outputs {
+        let size = window.size();
+        let mode = Mode {
+            size: (size.w as i32, size.h as i32).into(),
+            refresh: state.config.refresh_rate,
+        };
+        output.change_current_state(Some(mode), Some(Transform::Normal), None, None);
+        output.set_preferred(mode);
+        state.add_output(output);
+    }
+
+    // Dispatch X11 events.
+    event_loop
+        .handle()
+        .insert_source(backend, |event, _, data| {
+            let state = &mut data.state;
+
+            match event {
+                X11Event::CloseRequested { window_id } => {
+                    state.remove_x11_output(window_id);
+                    if state.backend_data.outputs.is_empty() {
+                        state.loop_signal.stop();
+                    }
+                }
+                X11Event::Resized {
+                    new_size,
+                    window_id,
+                } => {
+                    let refresh = state.config.refresh_rate;
+                    if let Some(x11) = state.backend_data.find_output(window_id) {
+                        x11.output.change_current_state(
+                            Some(Mode {
+                                size: (new_size.w as i32, new_size.h as i32).into(),
+                                refresh,
+                            }),
+                            None,
+                            None,
+                            None,
+                        );
+                    }
+                    // The outputs on its right move along.
+                    state.configure_outputs();
+                    state.render_x11_output(window_id);
+                }
+                X11Event::Refresh { window_id } | X11Event::PresentCompleted { window_id } => {
+                    state.render_x11_output(window_id);
+                }
+                // The pointer is positioned relative to the host window it is inside of, so it
+                // has to be mapped to the matching output.
+                X11Event::Input(InputEvent::PointerMotionAbsolute { event }) => {
+                    let output = event.window().and_then(|window| {
+                        state
+                            .backend_data
+                            .find_output(window.as_ref().id())
+                            .map(|x11| x11.output.clone())
+                    });
+                    if let Some(output) = output {
+                        state.pointer_motion_absolute::<X11Input, _>(&event, &output);
+                    }
+                }
+                X11Event::Input(event) => {
+                    let action = state.handle_input(event);
+                    match action {
+                        Action::Quit => {
+                            state.loop_signal.stop();
+                        }
+                        // It doesn't make sense to change VT on the X11 backend.
+                        _ => (),
+                    }
+                }
+            }
+
+            state.space.refresh();
+            state.popups.cleanup();
+            if let Err(err) = data.display.flush_clients() {
+                tracing::warn!("Failed to flush clients: {}", err);
+            }
+        })
+        .map_err(|_| Error::SourceInsertFailure)?;
+
+    // Draw the first frames. Following ones are driven by `PresentCompleted`.
+    let window_ids: Vec<u32> = state
+        .backend_data
+        .outputs
+        .iter()
+        .map(|x11| x11.window.id())
+        .collect();
+    for window_id in window_ids {
+        state.render_x11_output(window_id);
+    }
+
+    std::env::set_var("WAYLAND_DISPLAY", &socket);
+
+    let mut data = Data { display, state };
+    event_loop
+        .run(None, &mut data, |data| {
+            data.state.refresh_output_management(&[]);
+            data.state.refresh_x11_windows();
+            data.state.refresh_surface_scales();
+            data.state.refresh_ext_workspace();
+            if let Err(err) = data.display.flush_clients() {
+                tracing::warn!("Failed to flush clients: {}", err);
+            }
+        })
+        .or_else(|_| {
+            tracing::error!("Failed to run the event loop");
+            Err(Error::EventLoopRunFailure)
+        })?;
+
+    Ok(())
+}
+
+impl X11Data {
+    fn find_output(&mut self, window_id: u32) -> Option<&mut X11Output> {
+        self.outputs
+            .iter_mut()
+            .find(|x11| x11.window.id() == window_id)
+    }
+}
+
+impl State<X11Data> {
+    /// Advertise the render node of the host GPU, so that clients can allocate buffers on it.
+    fn init_x11_dmabuf_global(&mut self, dh: &DisplayHandle, node: DrmNode, formats: Vec<Format>) {
+        let render_node = node
+            .node_with_type(NodeType::Render)
+            .and_then(|node| node.ok())
+            .unwrap_or(node);
+
+        match DmabufFeedbackBuilder::new(render_node.dev_id(), formats.clone()).build() {
+            Ok(feedback) => {
+                self.backend_data
+                    .dmabuf_state
+                    .create_global_with_default_feedback::<Self>(dh, &feedback);
+            }
+            Err(_) => {
+                tracing::warn!("Failed to build dmabuf feedback, falling back to dmabuf v3");
+                self.backend_data
+                    .dmabuf_state
+                    .create_global::<Self>(dh, formats);
+            }
+        }
+    }
+
+    /// Draw a frame on the output of a host window and submit it.
+    fn render_x11_output(&mut self, window_id: u32) {
+        let backend_data = &mut self.backend_data;
+        let x11 = match backend_data
+            .outputs
+            .iter_mut()
+            .find(|x11| x11.window.id() == window_id)
+        {
+            Some(x11) => x11,
+            None => return,
+        };
+
+        let (buffer, age) = match x11.surface.buffer() {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                tracing::error!("Failed to allocate buffer: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = backend_data.renderer.bind(buffer) {
+            tracing::error!("Failed to bind buffer: {}", err);
+            return;
+        }
+
+        let renderer = &mut backend_data.renderer;
+        let res = match self
+            .lock
+            .render_elements::<_, WaylandSurfaceRenderElement<GlesRenderer>>(renderer, &x11.output)
+        {
+            Some(elements) => {
+                x11.damage_tracker
+                    .render_output(renderer, age as usize, &elements, LOCKED_COLOR)
+            }
+            None => render_output::<_, WaylandSurfaceRenderElement<GlesRenderer>, _, _>(
+                &x11.output,
+                renderer,
+                1.0,
+                age as usize,
+                [&self.space],
+                &[],
+                &mut x11.damage_tracker,
+                self.config.background_color,
+            ),
+        };
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                tracing::error!("Failed to render {}: {:?}", x11.output.name(), err);
+                x11.surface.reset_buffers();
+                return;
+            }
+        };
+
+        if let Err(err) = x11.surface.submit() {
+            tracing::error!("Failed to submit buffer: {}", err);
+            x11.surface.reset_buffers();
+        }
+        // The X server does not tell when the frame is shown, it is about now.
+        take_presentation_feedback(&x11.output, &self.space, &res.states)
+            .presented::<_, Monotonic>(
+                self.clock.now(),
+                refresh_interval(&x11.output),
+                0,
+                wp_presentation_feedback::Kind::Vsync,
+            );
+
+        for window in self.space.elements() {
+            window.send_frame(
+                &x11.output,
+                self.start_time.elapsed(),
+                Some(Duration::ZERO),
+                |_, _| Some(x11.output.clone()),
+            );
+        }
+        send_layer_frames(&x11.output, self.start_time.elapsed());
+        self.lock
+            .send_frames(&x11.output, self.start_time.elapsed());
+
+        let output = x11.output.clone();
+        self.lock_frame_rendered(&output);
+    }
+
+    /// Unmap the output of a closed host window.
+    fn remove_x11_output(&mut self, window_id: u32) {
+        if let Some(position) = self
+            .backend_data
+            .outputs
+            .iter()
+            .position(|x11| x11.window.id() == window_id)
+        {
+            let x11 = self.backend_data.outputs.remove(position);
+            self.remove_output(&x11.output);
+            self.emit_ipc_event(Event::OutputRemoved {
+                name: x11.output.name(),
+            });
+        }
+    }
+}
+
+impl DmabufHandler for State<X11Data> {
+    fn dmabuf_state(&mut self) -> &mut DmabufState {
+        &mut self.backend_data.dmabuf_state
+    }
+
+    fn dmabuf_imported(
+        &mut self,
+        _global: &DmabufGlobal,
+        dmabuf: Dmabuf,
+    ) -> Result<(), ImportError> {
+        // Only accept buffers the renderer is able to sample from.
+        self.backend_data
+            .renderer
+            .import_dmabuf(&dmabuf, None)
+            .map(|_| ())
+            .map_err(|_| ImportError::Failed)
+    }
+}
+delegate_dmabuf!(State<X11Data>);
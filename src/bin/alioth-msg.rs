@@ -0,0 +1,111 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+
+// Not every message is needed here.
+#[allow(dead_code)]
+#[path = "../ipc/protocol.rs"]
+mod protocol;
+
+use protocol::{Request, Response, SOCKET_ENV};
+
+/// Send a message to a running Alioth compositor and print the reply as JSON.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// Path to the IPC socket. Defaults to `$ALIOTH_SOCKET`.
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List all windows.
+    Windows,
+    /// List all outputs.
+    Outputs,
+    /// List all workspaces.
+    Workspaces,
+    /// List all input devices.
+    Inputs,
+    /// Ask a window to close.
+    Close { id: u64 },
+    /// Raise a window and give it keyboard focus.
+    Focus { id: u64 },
+    /// Exit the compositor.
+    Quit,
+    /// Print events as they happen, one JSON object per line.
+    Subscribe,
+}
+
+impl From<Command> for Request {
+    fn from(value: Command) -> Self {
+        match value {
+            Command::Windows => Request::Windows,
+            Command::Outputs => Request::Outputs,
+            Command::Workspaces => Request::Workspaces,
+            Command::Inputs => Request::InputDevices,
+            Command::Close { id } => Request::CloseWindow { id },
+            Command::Focus { id } => Request::FocusWindow { id },
+            Command::Quit => Request::Quit,
+            Command::Subscribe => Request::Subscribe,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("alioth-msg: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<ExitCode, anyhow::Error> {
+    let socket = cli
+        .socket
+        .or_else(|| std::env::var_os(SOCKET_ENV).map(PathBuf::from))
+        .ok_or_else(|| anyhow::anyhow!("${} is not set, is Alioth running?", SOCKET_ENV))?;
+    let subscribe = matches!(cli.command, Command::Subscribe);
+
+    let mut stream = UnixStream::connect(&socket)?;
+    let mut request = serde_json::to_vec(&Request::from(cli.command))?;
+    request.push(b'\n');
+    stream.write_all(&request)?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let line = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("The compositor closed the connection"))??;
+
+    match serde_json::from_str::<Response>(&line)? {
+        Response::Error { message } => {
+            eprintln!("alioth-msg: {}", message);
+            return Ok(ExitCode::FAILURE);
+        }
+        Response::Ok if subscribe => {
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            for line in lines {
+                writeln!(stdout, "{}", line?)?;
+                stdout.flush()?;
+            }
+        }
+        Response::Ok => (),
+        response => println!("{}", serde_json::to_string_pretty(&response)?),
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,759 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use inotify::{Inotify, WatchMask};
+use serde::{de::Error as _, Deserialize, Deserializer};
+use smithay::{
+    input::keyboard::{xkb, Keysym, ModifiersState},
+    output::{Mode, Output},
+    reexports::calloop::{self, generic::Generic, EventLoop, Interest, PostAction},
+    utils::Transform,
+};
+
+use crate::{action::Action, data::Data, output::output_description, state::State};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to read config file: {0}")]
+    ReadFailure(std::io::Error),
+
+    #[error("Failed to parse config file: {0}")]
+    ParseFailure(toml::de::Error),
+}
+
+/// The configuration of the compositor, read from `$XDG_CONFIG_HOME/alioth/config.toml`.
+///
+/// ```toml
+/// background-color = [0.1, 0.1, 0.1, 1.0]
+/// refresh-rate = 60000
+///
+/// [keyboard]
+/// repeat-delay = 200
+/// repeat-rate = 200
+///
+/// [bindings]
+/// "Ctrl+Alt+BackSpace" = "quit"
+/// "Super+Return" = "spawn foot"
+/// "Super+Shift+q" = "close-window"
+/// "Super+f" = "toggle-fullscreen"
+/// "Super+1" = "workspace 1"
+/// "Super+Shift+1" = "move-to-workspace 1"
+/// "Super+d" = { action = "shell rofi -show drun", on = "release" }
+///
+/// [outputs."DP-1"]
+/// mode = "2560x1440@144"
+/// position = [0, 0]
+/// scale = 1.5
+/// vrr = "fullscreen"
+///
+/// [outputs."Dell Inc. DELL U2720Q 8GK0X83"]
+/// transform = "90"
+///
+/// [outputs.HDMI-A-1]
+/// enable = false
+///
+/// [night-light]
+/// temperature = 4000
+/// sunset = "20:00"
+/// sunrise = "07:00"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// The colour of the background, in RGBA.
+    pub background_color: [f32; 4],
+    /// Refresh rate of outputs without a real mode (Winit, X11 and headless), in mHz.
+    /// Only takes effect on restart.
+    pub refresh_rate: i32,
+    pub keyboard: KeyboardConfig,
+    pub bindings: Bindings,
+    pub outputs: OutputConfigs,
+    /// Warmer colours at night, on the DRM backend. Off without this section.
+    pub night_light: Option<NightLightConfig>,
+    /// Run X11 clients through Xwayland. Only takes effect on restart.
+    pub xwayland: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            background_color: [0.1, 0.1, 0.1, 1.0],
+            refresh_rate: 60_000,
+            keyboard: KeyboardConfig::default(),
+            bindings: Bindings::default(),
+            outputs: OutputConfigs::default(),
+            night_light: None,
+            xwayland: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct KeyboardConfig {
+    /// Delay before a held key starts repeating, in milliseconds.
+    pub repeat_delay: i32,
+    /// Number of repeats per second.
+    pub repeat_rate: i32,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        Self {
+            repeat_delay: 200,
+            repeat_rate: 200,
+        }
+    }
+}
+
+/// Settings of outputs, keyed by their connector name (e.g. `DP-1`) or by the make, model and
+/// serial number from their EDID, separated by spaces. When both match, the EDID one wins, so that
+/// a monitor keeps its settings whatever port it is plugged into.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OutputConfigs(pub HashMap<String, OutputConfig>);
+
+impl OutputConfigs {
+    /// The settings of an output. Outputs without any use the defaults.
+    pub fn find(&self, output: &Output) -> OutputConfig {
+        let description = output_description(output);
+        self.0
+            .get(&description)
+            .or_else(|| self.0.get(&output.name()))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Disabled outputs are turned off and not used at all.
+    pub enable: bool,
+    /// The preferred mode of the output if not set. Only DRM outputs have modes to choose from.
+    pub mode: Option<ModeConfig>,
+    /// Where the top-left corner of the output goes, in logical pixels. Outputs without a position
+    /// are put to the right of the others.
+    pub position: Option<[i32; 2]>,
+    /// Fractional scales like 1.25 are fine: clients that support `wp_fractional_scale_v1` render
+    /// at exactly that scale, the others are scaled down from the next integer one.
+    #[serde(deserialize_with = "deserialize_scale")]
+    pub scale: Option<f64>,
+    pub transform: Option<OutputTransform>,
+    /// Variable refresh rate, on monitors that support it. Only the DRM backend has it.
+    pub vrr: VrrMode,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            mode: None,
+            position: None,
+            scale: None,
+            transform: None,
+            vrr: VrrMode::Never,
+        }
+    }
+}
+
+/// Scales have to be finite and positive, as output management clients are told too.
+fn deserialize_scale<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(scale) if !scale.is_finite() || scale <= 0.0 => {
+            Err(D::Error::custom(format!("invalid scale `{}`", scale)))
+        }
+        scale => Ok(scale),
+    }
+}
+
+/// When an output refreshes as soon as a frame is ready rather than at a fixed rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VrrMode {
+    Always,
+    Never,
+    /// Only while a fullscreen window, like a game or a video player, covers the output.
+    Fullscreen,
+}
+
+/// A mode written as `<width>x<height>` or `<width>x<height>@<refresh rate in Hz>`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ModeConfig {
+    pub width: i32,
+    pub height: i32,
+    /// In mHz.
+    pub refresh: Option<i32>,
+}
+
+impl ModeConfig {
+    /// The mode of an output that fits best. Without a refresh rate, the preferred mode wins if it
+    /// has the right size, then the fastest one.
+    pub fn find(&self, output: &Output) -> Option<Mode> {
+        let modes = output
+            .modes()
+            .into_iter()
+            .filter(|mode| mode.size == (self.width, self.height).into());
+
+        match self.refresh {
+            // Rates like 59.94 Hz are often written as 60.
+            Some(refresh) => modes
+                .min_by_key(|mode| (mode.refresh - refresh).abs())
+                .filter(|mode| (mode.refresh - refresh).abs() <= 500),
+            None => {
+                let preferred = output.preferred_mode();
+                modes.max_by_key(|mode| (Some(*mode) == preferred, mode.refresh))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for ModeConfig {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid mode `{}`", value);
+
+        let (size, refresh) = match value.split_once('@') {
+            Some((size, refresh)) => (size, Some(refresh)),
+            None => (value.as_str(), None),
+        };
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let refresh = match refresh {
+            Some(refresh) => Some(
+                refresh
+                    .parse::<f64>()
+                    .map(|hz| (hz * 1000.0).round() as i32)
+                    .map_err(|_| invalid())?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+            refresh,
+        })
+    }
+}
+
+/// When and how much the colours of the outputs are warmed up. Clients like gammastep take over
+/// while they control the gamma of an output.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NightLightConfig {
+    /// The colour temperature at night, in Kelvin. Daylight is 6500.
+    pub temperature: u32,
+    /// When it starts getting warmer, in local time.
+    pub sunset: TimeOfDay,
+    /// When it is back to daylight, in local time.
+    pub sunrise: TimeOfDay,
+    /// How long getting warmer after sunset and colder before sunrise takes, in minutes.
+    pub transition: u32,
+}
+
+impl Default for NightLightConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 4000,
+            sunset: TimeOfDay(20 * 60),
+            sunrise: TimeOfDay(7 * 60),
+            transition: 30,
+        }
+    }
+}
+
+/// A time written as `<hours>:<minutes>`, stored in minutes since midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(pub u32);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid time `{}`", value);
+
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        let hours = hours.parse::<u32>().map_err(|_| invalid())?;
+        let minutes = minutes.parse::<u32>().map_err(|_| invalid())?;
+        if hours >= 24 || minutes >= 60 {
+            return Err(invalid());
+        }
+        Ok(Self(hours * 60 + minutes))
+    }
+}
+
+/// Rotation counter-clockwise, then flipping around the vertical axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputTransform {
+    Normal,
+    #[serde(rename = "90")]
+    Rotate90,
+    #[serde(rename = "180")]
+    Rotate180,
+    #[serde(rename = "270")]
+    Rotate270,
+    Flipped,
+    #[serde(rename = "flipped-90")]
+    Flipped90,
+    #[serde(rename = "flipped-180")]
+    Flipped180,
+    #[serde(rename = "flipped-270")]
+    Flipped270,
+}
+
+impl From<OutputTransform> for Transform {
+    fn from(value: OutputTransform) -> Self {
+        match value {
+            OutputTransform::Normal => Transform::Normal,
+            OutputTransform::Rotate90 => Transform::_90,
+            OutputTransform::Rotate180 => Transform::_180,
+            OutputTransform::Rotate270 => Transform::_270,
+            OutputTransform::Flipped => Transform::Flipped,
+            OutputTransform::Flipped90 => Transform::Flipped90,
+            OutputTransform::Flipped180 => Transform::Flipped180,
+            OutputTransform::Flipped270 => Transform::Flipped270,
+        }
+    }
+}
+
+impl From<Transform> for OutputTransform {
+    fn from(value: Transform) -> Self {
+        match value {
+            Transform::Normal => OutputTransform::Normal,
+            Transform::_90 => OutputTransform::Rotate90,
+            Transform::_180 => OutputTransform::Rotate180,
+            Transform::_270 => OutputTransform::Rotate270,
+            Transform::Flipped => OutputTransform::Flipped,
+            Transform::Flipped90 => OutputTransform::Flipped90,
+            Transform::Flipped180 => OutputTransform::Flipped180,
+            Transform::Flipped270 => OutputTransform::Flipped270,
+        }
+    }
+}
+
+/// Modifiers that have to be held for a binding to trigger.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub logo: bool,
+}
+
+impl Modifiers {
+    /// Whether exactly these modifiers are held.
+    pub fn matches(&self, state: &ModifiersState) -> bool {
+        self.ctrl == state.ctrl
+            && self.alt == state.alt
+            && self.shift == state.shift
+            && self.logo == state.logo
+    }
+}
+
+/// When the action of a binding runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    /// When the key is pressed.
+    #[default]
+    Press,
+    /// When the key is released.
+    Release,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyBinding {
+    pub modifiers: Modifiers,
+    pub keysym: Keysym,
+    pub action: Action,
+    pub trigger: Trigger,
+}
+
+/// The right-hand side of a binding, either just an action or an action with its trigger.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum BindingValue {
+    Action(String),
+    Detailed {
+        action: String,
+        #[serde(default)]
+        on: Trigger,
+    },
+}
+
+/// Keyboard shortcuts, written as `"Modifier+...+Key" = "action"`, or as
+/// `"Modifier+...+Key" = { action = "action", on = "release" }` to run the action when the key is
+/// released.
+///
+/// Available actions are:
+///
+/// * `spawn <program> [<argument>...]`, arguments can be quoted like in a shell
+/// * `shell <command line>`, run with `sh -c`
+/// * `close-window`
+/// * `focus next|previous|left|right|up|down`
+/// * `move-window left|right|up|down`
+/// * `toggle-fullscreen`
+/// * `toggle-maximize`
+/// * `workspace <name>|next|previous`, creating the workspace if needed
+/// * `move-to-workspace <name>|next|previous`
+/// * `new-workspace`
+/// * `destroy-workspace`, moving its windows to the workspace shown instead
+/// * `change-vt <number>`
+/// * `quit` (or `exit`)
+/// * `none`, to disable a key combination
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "HashMap<String, BindingValue>")]
+pub struct Bindings(pub Vec<KeyBinding>);
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self(vec![KeyBinding {
+            modifiers: Modifiers {
+                ctrl: true,
+                alt: true,
+                ..Default::default()
+            },
+            keysym: xkb::KEY_BackSpace,
+            action: Action::Quit,
+            trigger: Trigger::Press,
+        }])
+    }
+}
+
+impl TryFrom<HashMap<String, BindingValue>> for Bindings {
+    type Error = String;
+
+    fn try_from(value: HashMap<String, BindingValue>) -> Result<Self, Self::Error> {
+        let mut bindings = Vec::with_capacity(value.len());
+        let mut errors = Vec::new();
+
+        for (keys, value) in value {
+            let (action, trigger) = match value {
+                BindingValue::Action(action) => (action, Trigger::Press),
+                BindingValue::Detailed { action, on } => (action, on),
+            };
+            match parse_binding(&keys, &action, trigger) {
+                Ok(binding) => bindings.push(binding),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Self(bindings))
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+/// Parse a binding like `"Ctrl+Alt+BackSpace" = "quit"`.
+fn parse_binding(keys: &str, action: &str, trigger: Trigger) -> Result<KeyBinding, String> {
+    let mut modifiers = Modifiers::default();
+    let mut parts = keys.split('+').map(str::trim).collect::<Vec<_>>();
+
+    let key = parts
+        .pop()
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| format!("binding `{}` has no key", keys))?;
+
+    for part in parts {
+        match part.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => modifiers.ctrl = true,
+            "alt" | "mod1" => modifiers.alt = true,
+            "shift" => modifiers.shift = true,
+            "super" | "logo" | "mod4" => modifiers.logo = true,
+            _ => return Err(format!("unknown modifier `{}` in binding `{}`", part, keys)),
+        }
+    }
+
+    let keysym = xkb::keysym_from_name(key, xkb::KEYSYM_CASE_INSENSITIVE);
+    if keysym == xkb::KEY_NoSymbol {
+        return Err(format!("unknown key `{}` in binding `{}`", key, keys));
+    }
+
+    let action = action
+        .parse::<Action>()
+        .map_err(|err| format!("{} in binding `{}`", err, keys))?;
+
+    Ok(KeyBinding {
+        modifiers,
+        keysym,
+        action,
+        trigger,
+    })
+}
+
+impl Config {
+    /// The default location of the config file, if it can be determined.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+        Some(config_home.join("alioth").join("config.toml"))
+    }
+
+    /// Read and check the config file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path).map_err(Error::ReadFailure)?;
+        toml::from_str(&content).map_err(Error::ParseFailure)
+    }
+
+    /// Read the config file, falling back to the default config if it is missing or broken.
+    pub fn load_or_default(path: Option<&Path>) -> Self {
+        let path = match path {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        match Self::load(path) {
+            Ok(config) => {
+                tracing::info!("Loaded config from {}", path.display());
+                config
+            }
+            Err(Error::ReadFailure(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("{} not found, using the default config", path.display());
+                Self::default()
+            }
+            Err(err) => {
+                tracing::error!("{}, using the default config", err);
+                Self::default()
+            }
+        }
+    }
+}
+
+impl<BackendData> State<BackendData> {
+    /// Re-read the config file. If it is broken, the current config is kept.
+    pub fn reload_config(&mut self) {
+        let path = match &self.config_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        match Config::load(path) {
+            Ok(config) => {
+                tracing::info!("Reloaded config from {}", path.display());
+                self.config = config;
+                // The file wins over what output management clients have set since.
+                self.output_overrides.clear();
+                self.apply_config();
+            }
+            Err(err) => {
+                tracing::error!("{}, keeping the current config", err);
+            }
+        }
+    }
+
+    /// Apply the parts of the config that are not read on demand.
+    fn apply_config(&mut self) {
+        if let Some(keyboard) = self.seat.get_keyboard() {
+            keyboard.change_repeat_info(
+                self.config.keyboard.repeat_rate,
+                self.config.keyboard.repeat_delay,
+            );
+        }
+        self.configure_outputs();
+    }
+}
+
+/// Watch the directory of the config file and reload the config whenever the file is written.
+/// The directory is watched instead of the file itself, because many editors replace the file on
+/// saving. It is created if it is missing, so that a config written later is picked up too.
+pub fn init_config_watcher<BackendData>(
+    event_loop: &mut EventLoop<Data<BackendData>>,
+    path: &Path,
+) -> Result<(), anyhow::Error> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("{} has no parent directory", path.display()))?;
+    let file_name: OsString = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file", path.display()))?
+        .to_owned();
+
+    std::fs::create_dir_all(dir)?;
+    let mut inotify = Inotify::init()?;
+    inotify.watches().add(
+        dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+    )?;
+
+    event_loop
+        .handle()
+        .insert_source(
+            Generic::new(inotify, Interest::READ, calloop::Mode::Level),
+            move |_, inotify, data| {
+                let mut buffer = [0; 4096];
+                let changed = match inotify.read_events(&mut buffer) {
+                    Ok(events) => events
+                        .into_iter()
+                        .any(|event| event.name == Some(file_name.as_os_str())),
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => false,
+                    // Returning the error would remove the watcher for good.
+                    Err(err) => {
+                        tracing::warn!("Failed to read config file events: {}", err);
+                        false
+                    }
+                };
+
+                if changed {
+                    data.state.reload_config();
+                }
+
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to insert the config watcher into the event loop"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(value: &str) -> Result<ModeConfig, String> {
+        ModeConfig::try_from(value.to_string())
+    }
+
+    #[test]
+    fn mode_without_refresh() {
+        assert_eq!(
+            mode("1920x1080"),
+            Ok(ModeConfig {
+                width: 1920,
+                height: 1080,
+                refresh: None,
+            })
+        );
+    }
+
+    #[test]
+    fn mode_with_refresh() {
+        assert_eq!(
+            mode("2560x1440@59.94"),
+            Ok(ModeConfig {
+                width: 2560,
+                height: 1440,
+                refresh: Some(59_940),
+            })
+        );
+    }
+
+    #[test]
+    fn bad_modes() {
+        for value in [
+            "",
+            "1920",
+            "1920x",
+            "x1080",
+            "1920x1080x60",
+            "1920x1080@",
+            "1920x1080@sixty",
+            "1920 x 1080",
+        ] {
+            assert!(mode(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn actions() {
+        use crate::{action::Direction, workspace::WorkspaceTarget};
+
+        let spawn = |args: &[&str]| Action::Spawn(args.iter().map(|arg| arg.to_string()).collect());
+        for (value, action) in [
+            ("quit", Action::Quit),
+            ("  exit ", Action::Quit),
+            ("change-vt 2", Action::ChangeVt(2)),
+            ("spawn foot", spawn(&["foot"])),
+            (
+                r#"spawn foot -T "My terminal""#,
+                spawn(&["foot", "-T", "My terminal"]),
+            ),
+            ("spawn 'a b' c\\ d", spawn(&["a b", "c d"])),
+            ("spawn foot # a comment", spawn(&["foot"])),
+            (
+                "shell grim - | wl-copy",
+                Action::Shell("grim - | wl-copy".to_string()),
+            ),
+            ("focus next", Action::FocusNext),
+            ("focus previous", Action::FocusPrevious),
+            ("focus left", Action::FocusWindow(Direction::Left)),
+            ("move-window down", Action::MoveWindow(Direction::Down)),
+            ("workspace next", Action::Workspace(WorkspaceTarget::Next)),
+            (
+                "workspace mail",
+                Action::Workspace(WorkspaceTarget::Named("mail".to_string())),
+            ),
+            (
+                "move-to-workspace previous",
+                Action::MoveToWorkspace(WorkspaceTarget::Previous),
+            ),
+        ] {
+            assert_eq!(value.parse::<Action>(), Ok(action), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn bad_actions() {
+        for value in [
+            "",
+            "jump",
+            "quit now",
+            "change-vt two",
+            "spawn",
+            "spawn #foo",
+            r#"spawn "" #x"#,
+            r#"spawn "foot"#,
+            "shell",
+            "focus",
+            "focus sideways",
+            "focus left right",
+            "move-window",
+            "workspace",
+            "workspace a b",
+        ] {
+            assert!(value.parse::<Action>().is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn scales() {
+        let output = |value: &str| toml::from_str::<OutputConfig>(&format!("scale = {}", value));
+        assert_eq!(output("1.25").unwrap().scale, Some(1.25));
+        for value in ["0", "0.0", "-1.0", "inf", "-inf", "nan"] {
+            assert!(output(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+
+    fn time(value: &str) -> Result<TimeOfDay, String> {
+        TimeOfDay::try_from(value.to_string())
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(time("0:00"), Ok(TimeOfDay(0)));
+        assert_eq!(time("07:30"), Ok(TimeOfDay(7 * 60 + 30)));
+        assert_eq!(time("23:59"), Ok(TimeOfDay(23 * 60 + 59)));
+    }
+
+    #[test]
+    fn bad_times() {
+        for value in [
+            "", "24:00", "12:60", "1x2", "12", "12:", ":30", "-1:00", "12:30:00",
+        ] {
+            assert!(time(value).is_err(), "{:?} was accepted", value);
+        }
+    }
+}
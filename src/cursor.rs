@@ -1,4 +1,13 @@
-use std::{collections::BTreeMap, fs::File, io::Read, ops::Bound, time::Duration};
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::Read,
+    ops::Bound,
+    sync::Mutex,
+    time::Duration,
+};
 
 use drm_fourcc::DrmFourcc;
 use smithay::{
@@ -10,9 +19,10 @@ use smithay::{
         },
         ImportAll, ImportMem, Renderer, Texture,
     },
-    input::pointer::CursorImageStatus,
+    input::pointer::{CursorImageAttributes, CursorImageStatus},
     render_elements,
-    utils::{Clock, Monotonic, Transform},
+    utils::{Clock, Logical, Monotonic, Physical, Point, Scale, Transform},
+    wayland::compositor,
 };
 use xcursor::{
     parser::{parse_xcursor, Image},
@@ -26,11 +36,16 @@ pub enum Error {
 }
 
 pub struct CursorElement {
-    default: BTreeMap<u64, Image>,
+    /// The frames of the default cursor by nominal size, so that scaled outputs get a sharp one.
+    /// They are keyed by the total delay up to their end.
+    default: BTreeMap<u32, BTreeMap<u64, Image>>,
     total_delay: u64,
     current_delay: u64,
     status: CursorImageStatus,
     size: u32,
+    /// The frames that have been imported, by nominal size and delay. They are kept so that the
+    /// cursor is not seen as damaged on every frame. Typed by the renderer that imported them.
+    textures: RefCell<HashMap<(u32, u64), Box<dyn Any>>>,
 }
 
 impl CursorElement {
@@ -57,19 +72,21 @@ impl CursorElement {
             .map_err(|_| Error::CursorIconLoadFailure)?;
 
         let cursor_images = match parse_xcursor(&cursor_data) {
-            Some(images) => images
-                .into_iter()
-                .filter(|image| image.width == size && image.height == size),
+            Some(images) => images,
             None => return Err(Error::CursorIconLoadFailure),
         };
 
-        let mut default = BTreeMap::new();
-
-        let mut total_delay = 0;
+        let mut default = BTreeMap::<_, BTreeMap<_, _>>::new();
         for image in cursor_images {
-            total_delay += image.delay as u64;
-            default.insert(total_delay, image);
+            let frames = default.entry(image.size).or_default();
+            let delay = frames.keys().next_back().copied().unwrap_or(0) + image.delay as u64;
+            frames.insert(delay, image);
         }
+        // The animation follows the size that is asked for.
+        let total_delay = match default.get(&size) {
+            Some(frames) => frames.keys().next_back().copied().unwrap_or(0),
+            None => return Err(Error::CursorIconLoadFailure),
+        };
 
         Ok(Self {
             default,
@@ -77,17 +94,30 @@ impl CursorElement {
             current_delay: 0,
             status: CursorImageStatus::Default,
             size,
+            textures: RefCell::new(HashMap::new()),
         })
     }
 
     pub fn update_animation_status(&mut self, clock: &Clock<Monotonic>) {
         let current_duration = Duration::from(clock.now());
-        self.current_delay = self.total_delay % current_duration.as_millis() as u64;
+        self.current_delay = match self.total_delay {
+            0 => 0,
+            total_delay => current_duration.as_millis() as u64 % total_delay,
+        };
     }
 
     pub fn set_status(&mut self, status: CursorImageStatus) {
         self.status = status;
     }
+
+    /// Whether the default cursor has more than one frame, so that it has to be redrawn over time.
+    pub fn is_animated(&self) -> bool {
+        matches!(self.status, CursorImageStatus::Default)
+            && self
+                .default
+                .get(&self.size)
+                .map_or(false, |frames| frames.len() > 1)
+    }
 }
 
 render_elements! {
@@ -105,45 +135,89 @@ where
     fn render_elements<E: From<Self::RenderElement>>(
         &self,
         renderer: &mut R,
-        location: smithay::utils::Point<i32, smithay::utils::Physical>,
-        scale: smithay::utils::Scale<f64>,
+        location: Point<i32, Physical>,
+        scale: Scale<f64>,
         alpha: f32,
     ) -> Vec<E> {
         match &self.status {
             CursorImageStatus::Hidden => vec![],
             CursorImageStatus::Default => {
-                let image = self
+                // The smallest image that doesn't have to be scaled up, or else the biggest one.
+                let wanted = (self.size as f64 * scale.x).ceil() as u32;
+                let (nominal_size, frames) = self
                     .default
+                    .range(wanted..)
+                    .next()
+                    .or_else(|| self.default.iter().next_back())
+                    .unwrap();
+                let (delay, image) = frames
                     .range((Bound::Included(self.current_delay), Bound::Unbounded))
                     .next()
-                    .unwrap()
-                    .1;
-
-                let texture = renderer
-                    .import_memory(
-                        image.pixels_rgba.as_slice(),
-                        DrmFourcc::Abgr8888,
-                        (self.size as i32, self.size as i32).into(),
-                        false,
-                    )
+                    .or_else(|| frames.iter().next_back())
                     .unwrap();
 
-                let buffer =
-                    TextureBuffer::from_texture(renderer, texture, 1, Transform::Normal, None);
+                let mut textures = self.textures.borrow_mut();
+                let key = (*nominal_size, *delay);
+                let buffer = match textures
+                    .get(&key)
+                    .and_then(|buffer| buffer.downcast_ref::<TextureBuffer<T>>())
+                {
+                    Some(buffer) => buffer.clone(),
+                    None => {
+                        let texture = renderer
+                            .import_memory(
+                                image.pixels_rgba.as_slice(),
+                                DrmFourcc::Abgr8888,
+                                (image.width as i32, image.height as i32).into(),
+                                false,
+                            )
+                            .unwrap();
+                        let buffer = TextureBuffer::from_texture(
+                            renderer,
+                            texture,
+                            1,
+                            Transform::Normal,
+                            None,
+                        );
+                        textures.insert(key, Box::new(buffer.clone()));
+                        buffer
+                    }
+                };
+
+                // Images of other sizes are shown at the size that was asked for.
+                let ratio = self.size as f64 / image.size as f64;
+                let size = (
+                    (image.width as f64 * ratio).round() as i32,
+                    (image.height as f64 * ratio).round() as i32,
+                );
+                let hotspot = Point::<f64, Logical>::from((
+                    image.xhot as f64 * ratio,
+                    image.yhot as f64 * ratio,
+                ))
+                .to_physical(scale);
 
                 let element =
                     PointerRenderElement::<R>::from(TextureRenderElement::from_texture_buffer(
-                        location.to_f64(),
+                        location.to_f64() - hotspot,
                         &buffer,
                         None,
                         None,
-                        None,
+                        Some(size.into()),
                     ))
                     .into();
 
                 vec![element]
             }
             CursorImageStatus::Surface(surface) => {
+                let hotspot = compositor::with_states(surface, |states| {
+                    states
+                        .data_map
+                        .get::<Mutex<CursorImageAttributes>>()
+                        .map(|attributes| attributes.lock().unwrap().hotspot)
+                        .unwrap_or_default()
+                });
+                let location = location - hotspot.to_physical_precise_round(scale);
+
                 render_elements_from_surface_tree(renderer, surface, location, scale, alpha)
                     .into_iter()
                     .map(E::from)
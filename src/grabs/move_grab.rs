@@ -1,5 +1,4 @@
 use smithay::{
-    desktop::Window,
     input::{
         pointer::{
             AxisFrame, ButtonEvent, GrabStartData, PointerGrab, PointerInnerHandle,
@@ -10,11 +9,11 @@ use smithay::{
     utils::{Logical, Point},
 };
 
-use crate::state::State;
+use crate::{state::State, window::WindowElement};
 
 pub struct MoveSurfaceGrab<BackendData: 'static> {
     pub start_data: GrabStartData<State<BackendData>>,
-    pub window: Window,
+    pub window: WindowElement,
     pub initial_location: Point<i32, Logical>,
 }
 
@@ -69,6 +68,7 @@ impl<BackendData: 'static> PointerGrab<State<BackendData>> for MoveSurfaceGrab<B
 
         if !handle.current_pressed().contains(&0x110) {
             handle.unset_grab(data, event.serial, event.time);
+            data.drop_window(&self.window, handle.current_location());
         }
     }
 
@@ -1,7 +1,7 @@
 use std::cell::RefCell;
 
 use smithay::{
-    desktop::{Space, Window},
+    desktop::{space::SpaceElement, Space},
     input::{
         pointer::{
             AxisFrame, ButtonEvent, GrabStartData, MotionEvent, PointerGrab, PointerInnerHandle,
@@ -14,13 +14,12 @@ use smithay::{
         wayland_server::protocol::wl_surface::WlSurface,
     },
     utils::{Logical, Point, Rectangle, Size},
-    wayland::{compositor, shell::xdg::SurfaceCachedState},
+    wayland::compositor,
 };
 
-use crate::state::State;
+use crate::{state::State, window::WindowElement};
 
 bitflags::bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
     pub struct ResizeEdge: u32 {
         const TOP          = 0b0001;
         const BOTTOM       = 0b0010;
@@ -75,11 +74,7 @@ impl<BackenData> PointerGrab<State<BackenData>> for ResizeSurfaceGrab<BackenData
             new_window_height = (self.initial_geo.size.h as f64 + delta.y) as i32;
         }
 
-        let (min_size, max_size) =
-            compositor::with_states(self.window.toplevel().wl_surface(), |states| {
-                let data = states.cached_state.current::<SurfaceCachedState>();
-                (data.min_size, data.max_size)
-            });
+        let (min_size, max_size) = self.window.size_limits();
 
         let min_width = min_size.w.max(1);
         let min_height = min_size.h.max(1);
@@ -101,12 +96,29 @@ impl<BackenData> PointerGrab<State<BackenData>> for ResizeSurfaceGrab<BackenData
             new_window_height.max(min_height).min(max_height),
         ));
 
-        let toplevel = self.window.toplevel();
-        toplevel.with_pending_state(|state| {
-            state.states.set(xdg_toplevel::State::Resizing);
-            state.size = Some(self.last_window_size);
-        });
-        toplevel.send_pending_configure();
+        match &self.window {
+            WindowElement::Wayland(window) => {
+                let toplevel = window.toplevel();
+                toplevel.with_pending_state(|state| {
+                    state.states.set(xdg_toplevel::State::Resizing);
+                    state.size = Some(self.last_window_size);
+                });
+                toplevel.send_pending_configure();
+            }
+            // X11 windows take the size right away, so they can be moved along with it instead of
+            // waiting for the commit.
+            WindowElement::X11(_) => {
+                self.window.request_size(self.last_window_size);
+                let mut location = self.initial_geo.loc;
+                if self.edges.intersects(ResizeEdge::LEFT) {
+                    location.x += self.initial_geo.size.w - self.last_window_size.w;
+                }
+                if self.edges.intersects(ResizeEdge::TOP) {
+                    location.y += self.initial_geo.size.h - self.last_window_size.h;
+                }
+                data.space.map_element(self.window.clone(), location, true);
+            }
+        }
     }
 
     fn relative_motion(
@@ -133,19 +145,21 @@ impl<BackenData> PointerGrab<State<BackenData>> for ResizeSurfaceGrab<BackenData
         if !handle.current_pressed().contains(&0x110) {
             handle.unset_grab(data, event.serial, event.time);
 
-            let toplevel = self.window.toplevel();
-            toplevel.with_pending_state(|state| {
-                state.states.unset(xdg_toplevel::State::Resizing);
-                state.size = Some(self.last_window_size);
-            });
-            toplevel.send_pending_configure();
-
-            ResizeSurfaceState::with(toplevel.wl_surface(), |state| {
-                *state = ResizeSurfaceState::Finishing {
-                    edges: self.edges,
-                    initial_geo: self.initial_geo,
-                };
-            });
+            if let WindowElement::Wayland(window) = &self.window {
+                let toplevel = window.toplevel();
+                toplevel.with_pending_state(|state| {
+                    state.states.unset(xdg_toplevel::State::Resizing);
+                    state.size = Some(self.last_window_size);
+                });
+                toplevel.send_pending_configure();
+
+                ResizeSurfaceState::with(toplevel.wl_surface(), |state| {
+                    *state = ResizeSurfaceState::Finishing {
+                        edges: self.edges,
+                        initial_geo: self.initial_geo,
+                    };
+                });
+            }
         }
     }
 
@@ -165,7 +179,7 @@ impl<BackenData> PointerGrab<State<BackenData>> for ResizeSurfaceGrab<BackenData
 
 pub struct ResizeSurfaceGrab<BackendData: 'static> {
     start_data: GrabStartData<State<BackendData>>,
-    window: Window,
+    window: WindowElement,
 
     edges: ResizeEdge,
 
@@ -176,15 +190,17 @@ pub struct ResizeSurfaceGrab<BackendData: 'static> {
 impl<BackendData: 'static> ResizeSurfaceGrab<BackendData> {
     pub fn start(
         start_data: GrabStartData<State<BackendData>>,
-        window: Window,
+        window: WindowElement,
         edges: ResizeEdge,
         initial_window_rect: Rectangle<i32, Logical>,
     ) -> Self {
         let initial_geo = initial_window_rect;
 
-        ResizeSurfaceState::with(window.toplevel().wl_surface(), |state| {
-            *state = ResizeSurfaceState::Resizing { edges, initial_geo };
-        });
+        if let WindowElement::Wayland(window) = &window {
+            ResizeSurfaceState::with(window.toplevel().wl_surface(), |state| {
+                *state = ResizeSurfaceState::Resizing { edges, initial_geo };
+            });
+        }
 
         Self {
             start_data,
@@ -236,11 +252,8 @@ impl ResizeSurfaceState {
     }
 }
 
-pub fn handle_commit(space: &mut Space<Window>, surface: &WlSurface) -> Option<()> {
-    let window = space
-        .elements()
-        .find(|w| w.toplevel().wl_surface() == surface)
-        .cloned()?;
+pub fn handle_commit(space: &mut Space<WindowElement>, surface: &WlSurface) -> Option<()> {
+    let window = space.elements().find(|w| w.has_surface(surface)).cloned()?;
 
     let mut window_loc = space.element_location(&window)?;
     let geometry = window.geometry();
@@ -10,20 +10,33 @@ use smithay::{
         },
         shell::xdg::{XdgPopupSurfaceData, XdgToplevelSurfaceData},
     },
+    xwayland::{X11Wm, XWaylandClientData},
 };
 
-use crate::{data::ClientData, grabs::resize_grab, state::State};
+use crate::{
+    data::{ClientData, Data},
+    grabs::resize_grab,
+    ipc::Event,
+    output::queue_redraw,
+    state::State,
+    window::WindowElement,
+};
 
-impl<BackendData> CompositorHandler for State<BackendData> {
+impl<BackendData: 'static> CompositorHandler for State<BackendData> {
     fn compositor_state(&mut self) -> &mut CompositorState {
         &mut self.compositor_state
     }
 
     fn client_compositor_state<'a>(&self, client: &'a Client) -> &'a CompositorClientState {
+        if let Some(data) = client.get_data::<XWaylandClientData>() {
+            return &data.compositor_state;
+        }
         &client.get_data::<ClientData>().unwrap().compositor_state
     }
 
     fn commit(&mut self, surface: &WlSurface) {
+        // Lets the XWM associate the surfaces of Xwayland with their X11 windows.
+        X11Wm::commit_hook::<Data<BackendData>>(surface);
         on_commit_buffer_handler::<Self>(surface);
 
         if !is_sync_subsurface(surface) {
@@ -31,19 +44,28 @@ impl<BackendData> CompositorHandler for State<BackendData> {
             while let Some(parent) = get_parent(&root) {
                 root = parent;
             }
-            if let Some(window) = self
+            match self
                 .space
                 .elements()
-                .find(|w| w.toplevel().wl_surface() == &root)
+                .find(|w| w.has_surface(&root))
+                .cloned()
             {
-                window.on_commit();
+                // The outputs it was on are drawn again too, in case it has shrunk.
+                Some(window) => {
+                    let mut outputs = self.space.outputs_for_element(&window);
+                    window.on_commit();
+                    outputs.extend(self.space.outputs_for_element(&window));
+                    outputs.iter().for_each(queue_redraw);
+                }
+                // Popups, layer, lock and cursor surfaces.
+                None => self.queue_redraw_all(),
             }
         }
 
-        if let Some(window) = self
+        if let Some(WindowElement::Wayland(window)) = self
             .space
             .elements()
-            .find(|w| w.toplevel().wl_surface() == surface)
+            .find(|w| w.has_surface(surface))
             .cloned()
         {
             let initial_configure_sent = with_states(surface, |states| {
@@ -58,6 +80,10 @@ impl<BackendData> CompositorHandler for State<BackendData> {
 
             if !initial_configure_sent {
                 window.toplevel().send_configure();
+
+                // Only now are its title and app id known.
+                let window = self.window_info(&WindowElement::Wayland(window));
+                self.emit_ipc_event(Event::WindowOpened { window });
             }
         } else if let Some(popup) = self.popups.find_popup(surface) {
             let PopupKind::Xdg(ref popup) = popup;
@@ -73,6 +99,8 @@ impl<BackendData> CompositorHandler for State<BackendData> {
             if !initial_configure_sent {
                 popup.send_configure().unwrap();
             }
+        } else {
+            self.handle_layer_commit(surface);
         }
 
         resize_grab::handle_commit(&mut self.space, surface);
@@ -0,0 +1,51 @@
+use crate::{
+    delegate_ext_workspace,
+    protocols::ext_workspace::{
+        ExtWorkspaceHandler, ExtWorkspaceState, WorkspaceEntry, WorkspaceRequest,
+    },
+    state::State,
+    window::WindowElement,
+};
+
+impl<BackendData> ExtWorkspaceHandler for State<BackendData> {
+    fn ext_workspace_state(&mut self) -> &mut ExtWorkspaceState {
+        &mut self.ext_workspace_state
+    }
+
+    fn workspace_requests(&mut self, requests: Vec<WorkspaceRequest>) {
+        for request in requests {
+            match request {
+                WorkspaceRequest::Activate(name) => self.activate_workspace(&name),
+                WorkspaceRequest::Remove(name) => self.remove_workspace(&name),
+                // Empty workspaces only exist while shown, so a new one is shown right away.
+                WorkspaceRequest::Create { output, name } => {
+                    if self.space.outputs().any(|o| *o == output) {
+                        self.show_workspace_on(name, &output);
+                    }
+                }
+            }
+        }
+    }
+}
+delegate_ext_workspace!(@<BackendData: 'static> State<BackendData>);
+
+impl<BackendData> State<BackendData> {
+    /// Send the workspace panels what has changed. Called on every event loop iteration.
+    pub fn refresh_ext_workspace(&mut self) {
+        let outputs = self.space.outputs().cloned().collect::<Vec<_>>();
+        let workspaces = self
+            .workspaces
+            .iter()
+            .map(|workspace| WorkspaceEntry {
+                name: workspace.name.clone(),
+                output: workspace.output.clone(),
+                active: workspace.visible,
+                urgent: workspace.windows().any(WindowElement::is_urgent),
+                // Only the case while there is no output to show it on.
+                hidden: workspace.output.is_none(),
+            })
+            .collect::<Vec<_>>();
+        self.ext_workspace_state
+            .refresh::<Self>(&outputs, &workspaces);
+    }
+}
@@ -0,0 +1,85 @@
+use smithay::{
+    delegate_fractional_scale,
+    desktop::{layer_map_for_output, utils::with_surfaces_surface_tree, WindowSurfaceType},
+    output::Output,
+    reexports::wayland_server::protocol::wl_surface::WlSurface,
+    wayland::{
+        compositor::{get_parent, with_states, SurfaceData},
+        fractional_scale::{with_fractional_scale, FractionalScaleHandler},
+    },
+};
+
+use crate::state::State;
+
+impl<BackendData> FractionalScaleHandler for State<BackendData> {
+    fn new_fractional_scale(&mut self, surface: WlSurface) {
+        let mut root = surface.clone();
+        while let Some(parent) = get_parent(&root) {
+            root = parent;
+        }
+        // Surfaces that are not mapped yet will most likely end up on the first output.
+        let output = self
+            .output_for_surface(&root)
+            .or_else(|| self.space.outputs().next().cloned());
+
+        if let Some(output) = output {
+            with_states(&surface, |states| set_preferred_scale(&output, states));
+        }
+    }
+}
+delegate_fractional_scale!(@<BackendData: 'static> State<BackendData>);
+
+/// Tell a surface to render at the scale of an output. Nothing is sent if it is already at it.
+fn set_preferred_scale(output: &Output, states: &SurfaceData) {
+    with_fractional_scale(states, |fractional_scale| {
+        fractional_scale.set_preferred_scale(output.current_scale().fractional_scale());
+    });
+}
+
+impl<BackendData> State<BackendData> {
+    /// The output that a window, layer surface or lock surface is shown on.
+    fn output_for_surface(&self, surface: &WlSurface) -> Option<Output> {
+        if let Some(window) = self
+            .space
+            .elements()
+            .find(|window| window.has_surface(surface))
+        {
+            return self.space.outputs_for_element(window).into_iter().next();
+        }
+
+        self.space
+            .outputs()
+            .find(|output| {
+                layer_map_for_output(output)
+                    .layer_for_surface(surface, WindowSurfaceType::TOPLEVEL)
+                    .is_some()
+                    || self
+                        .lock
+                        .surface(output)
+                        .map_or(false, |lock| lock.wl_surface() == surface)
+            })
+            .cloned()
+    }
+
+    /// Send every surface the scale of the output it is on, which changes when the surface moves
+    /// to another output or the scale of its output is changed. Called on every event loop
+    /// iteration.
+    pub fn refresh_surface_scales(&mut self) {
+        for window in self.space.elements() {
+            if let Some(output) = self.space.outputs_for_element(window).first() {
+                window.with_surfaces(|_, states| set_preferred_scale(output, states));
+            }
+        }
+
+        for output in self.space.outputs() {
+            for layer in layer_map_for_output(output).layers() {
+                layer.with_surfaces(|_, states| set_preferred_scale(output, states));
+            }
+            if let Some(lock) = self.lock.surface(output) {
+                with_surfaces_surface_tree(lock.wl_surface(), |_, states| {
+                    set_preferred_scale(output, states)
+                });
+            }
+        }
+    }
+}
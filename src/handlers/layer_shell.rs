@@ -0,0 +1,174 @@
+use std::time::Duration;
+
+use smithay::{
+    delegate_layer_shell,
+    desktop::{layer_map_for_output, LayerSurface, WindowSurfaceType},
+    output::Output,
+    reexports::wayland_server::protocol::{wl_output::WlOutput, wl_surface::WlSurface},
+    utils::SERIAL_COUNTER,
+    wayland::{
+        compositor::with_states,
+        shell::wlr_layer::{
+            KeyboardInteractivity, Layer, LayerSurface as WlrLayerSurface, LayerSurfaceData,
+            WlrLayerShellHandler, WlrLayerShellState,
+        },
+    },
+};
+
+use crate::{output::queue_redraw, state::State};
+
+impl<BackendData> WlrLayerShellHandler for State<BackendData> {
+    fn shell_state(&mut self) -> &mut WlrLayerShellState {
+        &mut self.layer_shell_state
+    }
+
+    fn new_layer_surface(
+        &mut self,
+        surface: WlrLayerSurface,
+        output: Option<WlOutput>,
+        _layer: Layer,
+        namespace: String,
+    ) {
+        // Without a requested output, the surface goes to the one under the pointer.
+        let output = output
+            .as_ref()
+            .and_then(Output::from_resource)
+            .or_else(|| {
+                let pointer = self.seat.get_pointer()?;
+                self.space
+                    .output_under(pointer.current_location())
+                    .next()
+                    .cloned()
+            })
+            .or_else(|| self.space.outputs().next().cloned());
+        let output = match output {
+            Some(output) => output,
+            None => {
+                surface.send_close();
+                return;
+            }
+        };
+
+        let mut map = layer_map_for_output(&output);
+        if let Err(err) = map.map_layer(&LayerSurface::new(surface, namespace)) {
+            tracing::warn!("Failed to map layer surface: {}", err);
+        }
+    }
+
+    fn layer_destroyed(&mut self, surface: WlrLayerSurface) {
+        let had_focus = self
+            .seat
+            .get_keyboard()
+            .and_then(|keyboard| keyboard.current_focus())
+            .as_ref()
+            == Some(surface.wl_surface());
+
+        for output in self.space.outputs() {
+            let mut map = layer_map_for_output(output);
+            let layer = map
+                .layers()
+                .find(|layer| layer.layer_surface() == &surface)
+                .cloned();
+            if let Some(layer) = layer {
+                map.unmap_layer(&layer);
+                queue_redraw(output);
+            }
+        }
+
+        // Give the focus back to the topmost window.
+        if had_focus && !self.is_locked() {
+            match self.topmost_window() {
+                Some(window) => self.focus_window(window),
+                None => {
+                    if let Some(keyboard) = self.seat.get_keyboard() {
+                        keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+                    }
+                }
+            }
+        }
+    }
+}
+delegate_layer_shell!(@<BackendData: 'static> State<BackendData>);
+
+impl<BackendData> State<BackendData> {
+    /// Arrange and configure a layer surface when it commits. Other surfaces are ignored.
+    pub fn handle_layer_commit(&mut self, surface: &WlSurface) {
+        let output = match self
+            .space
+            .outputs()
+            .find(|output| {
+                layer_map_for_output(output)
+                    .layer_for_surface(surface, WindowSurfaceType::TOPLEVEL)
+                    .is_some()
+            })
+            .cloned()
+        {
+            Some(output) => output,
+            None => return,
+        };
+
+        let initial_configure_sent = with_states(surface, |states| {
+            states
+                .data_map
+                .get::<LayerSurfaceData>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .initial_configure_sent
+        });
+
+        let mut map = layer_map_for_output(&output);
+        // The exclusive zone or anchors may have changed.
+        map.arrange();
+        let layer = match map.layer_for_surface(surface, WindowSurfaceType::TOPLEVEL) {
+            Some(layer) => layer.clone(),
+            None => return,
+        };
+        drop(map);
+
+        if !initial_configure_sent {
+            layer.layer_surface().send_configure();
+        }
+
+        // Launchers and the like above the windows may take all keyboard input for themselves.
+        let state = layer.cached_state();
+        if state.keyboard_interactivity == KeyboardInteractivity::Exclusive
+            && matches!(state.layer, Layer::Top | Layer::Overlay)
+            && !self.is_locked()
+        {
+            if let Some(keyboard) = self.seat.get_keyboard() {
+                if keyboard.current_focus().as_ref() != Some(layer.wl_surface()) {
+                    keyboard.set_focus(
+                        self,
+                        Some(layer.wl_surface().clone()),
+                        SERIAL_COUNTER.next_serial(),
+                    );
+                }
+            }
+        }
+    }
+
+    /// The layer surface above the windows that takes all keyboard input, if any.
+    pub fn exclusive_layer(&self) -> Option<LayerSurface> {
+        self.space.outputs().find_map(|output| {
+            let map = layer_map_for_output(output);
+            let layer = map
+                .layers()
+                .filter(|layer| matches!(layer.layer(), Layer::Top | Layer::Overlay))
+                .find(|layer| {
+                    layer.cached_state().keyboard_interactivity == KeyboardInteractivity::Exclusive
+                })
+                .cloned();
+            layer
+        })
+    }
+}
+
+/// Layer surfaces are not part of the space, so their frame callbacks are sent separately.
+pub fn send_layer_frames(output: &Output, time: Duration) {
+    for layer in layer_map_for_output(output).layers() {
+        layer.send_frame(output, time, Some(Duration::ZERO), |_, _| {
+            Some(output.clone())
+        });
+    }
+}
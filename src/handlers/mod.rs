@@ -1,20 +1,37 @@
+use std::os::fd::OwnedFd;
+
 use smithay::{
-    delegate_data_device, delegate_output, delegate_shm,
-    reexports::wayland_server::protocol::wl_buffer::WlBuffer,
+    delegate_data_device, delegate_output, delegate_presentation, delegate_shm,
+    delegate_viewporter,
+    input::Seat,
+    reexports::wayland_server::protocol::{wl_buffer::WlBuffer, wl_data_source::WlDataSource},
     wayland::{
         buffer::BufferHandler,
         data_device::{
-            ClientDndGrabHandler, DataDeviceHandler, DataDeviceState, ServerDndGrabHandler,
+            with_source_metadata, ClientDndGrabHandler, DataDeviceHandler, DataDeviceState,
+            ServerDndGrabHandler,
         },
         shm::{ShmHandler, ShmState},
     },
+    xwayland::xwm::SelectionType,
 };
 
 use crate::state::State;
 
 mod compositor;
+mod ext_workspace;
+mod fractional_scale;
+mod layer_shell;
+mod output_management;
 mod seat;
+mod session_lock;
+mod xdg_activation;
 mod xdg_shell;
+mod xwayland;
+
+pub use layer_shell::send_layer_frames;
+pub use output_management::{PendingOutputConfiguration, PendingOutputTest};
+pub use session_lock::{LockState, LOCKED_COLOR};
 
 impl<BackendData> BufferHandler for State<BackendData> {
     fn buffer_destroyed(&mut self, _buffer: &WlBuffer) {}
@@ -27,6 +44,8 @@ impl<BackendData> ShmHandler for State<BackendData> {
 }
 delegate_shm!(@<BackendData: 'static> State<BackendData>);
 delegate_output!(@<BackendData: 'static> State<BackendData>);
+delegate_viewporter!(@<BackendData: 'static> State<BackendData>);
+delegate_presentation!(@<BackendData: 'static> State<BackendData>);
 
 impl<BackendData: 'static> ClientDndGrabHandler for State<BackendData> {}
 impl<BackendData: 'static> ServerDndGrabHandler for State<BackendData> {}
@@ -37,5 +56,41 @@ impl<BackendData: 'static> DataDeviceHandler for State<BackendData> {
     fn data_device_state(&self) -> &DataDeviceState {
         &self.data_device_state
     }
+
+    // The clipboard of Wayland clients is offered to X11 clients as well.
+    fn new_selection(&mut self, source: Option<WlDataSource>, _seat: Seat<Self>) {
+        let xwm = match self.xwm.as_mut() {
+            Some(xwm) => xwm,
+            None => return,
+        };
+        let mime_types = source.and_then(|source| {
+            with_source_metadata(&source, |metadata| metadata.mime_types.clone()).ok()
+        });
+        if let Err(err) = xwm.new_selection(SelectionType::Clipboard, mime_types) {
+            tracing::warn!("Failed to set the clipboard of Xwayland: {}", err);
+        }
+    }
+
+    // Called for the selections set by Xwayland, when a Wayland client pastes them.
+    fn send_selection(
+        &mut self,
+        mime_type: String,
+        fd: OwnedFd,
+        _seat: Seat<Self>,
+        _user_data: &Self::SelectionUserData,
+    ) {
+        let xwm = match self.xwm.as_mut() {
+            Some(xwm) => xwm,
+            None => return,
+        };
+        if let Err(err) = xwm.send_selection(
+            SelectionType::Clipboard,
+            mime_type,
+            fd,
+            self.loop_handle.clone(),
+        ) {
+            tracing::warn!("Failed to send the clipboard of Xwayland: {}", err);
+        }
+    }
 }
 delegate_data_device!(@<BackendData: 'static> State<BackendData>);
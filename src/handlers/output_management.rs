@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use smithay::{
+    output::Output,
+    reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_configuration_v1::ZwlrOutputConfigurationV1,
+};
+
+use crate::{
+    config::{ModeConfig, OutputConfig, OutputTransform},
+    delegate_output_management,
+    protocols::output_management::{
+        HeadConfiguration, OutputHead, OutputManagementHandler, OutputManagementState,
+    },
+    state::State,
+};
+
+/// An output configuration that a client wants tested, waiting for the backend to try it.
+pub struct PendingOutputTest {
+    pub configuration: ZwlrOutputConfigurationV1,
+    /// The settings the configuration asks for, for the outputs it sets.
+    pub configs: Vec<(Output, OutputConfig)>,
+}
+
+/// An applied output configuration, waiting for the backend to know whether it worked.
+pub struct PendingOutputConfiguration {
+    configuration: ZwlrOutputConfigurationV1,
+    /// The overrides to go back to if it didn't.
+    previous: HashMap<String, OutputConfig>,
+}
+
+impl<BackendData> OutputManagementHandler for State<BackendData> {
+    fn output_management_state(&mut self) -> &mut OutputManagementState {
+        &mut self.output_management_state
+    }
+
+    fn apply_output_configuration(
+        &mut self,
+        configuration: ZwlrOutputConfigurationV1,
+        heads: Vec<(Output, Option<HeadConfiguration>)>,
+        test: bool,
+    ) {
+        let configs = match self.output_configs_from_heads(heads) {
+            Some(configs) => configs,
+            None => {
+                configuration.failed();
+                return;
+            }
+        };
+        // Whether the modes work is only known once the backend tries them.
+        if test {
+            self.pending_output_tests.push(PendingOutputTest {
+                configuration,
+                configs,
+            });
+            return;
+        }
+        // The previous one could still be reverted, which would undo this one as well.
+        if self.pending_output_configuration.is_some() {
+            configuration.failed();
+            return;
+        }
+
+        let mut overrides = self.output_overrides.clone();
+        overrides.extend(
+            configs
+                .into_iter()
+                .map(|(output, config)| (output.name(), config)),
+        );
+        let previous = std::mem::replace(&mut self.output_overrides, overrides);
+        self.configure_outputs();
+        self.pending_output_configuration = Some(PendingOutputConfiguration {
+            configuration,
+            previous,
+        });
+    }
+}
+delegate_output_management!(@<BackendData: 'static> State<BackendData>);
+
+impl<BackendData> State<BackendData> {
+    /// Turn the configuration of a client into settings, keeping whatever it doesn't set. `None`
+    /// if it can't work at all.
+    fn output_configs_from_heads(
+        &self,
+        heads: Vec<(Output, Option<HeadConfiguration>)>,
+    ) -> Option<Vec<(Output, OutputConfig)>> {
+        // Nothing could be seen or typed into anymore.
+        if heads.iter().all(|(_, head)| head.is_none()) {
+            tracing::warn!("Refusing to disable every output");
+            return None;
+        }
+
+        heads
+            .into_iter()
+            .map(|(output, head)| {
+                let current = self.output_config(&output);
+                let head = match head {
+                    Some(head) => head,
+                    None => {
+                        let config = OutputConfig {
+                            enable: false,
+                            ..current
+                        };
+                        return Some((output, config));
+                    }
+                };
+
+                let mode = match head.mode {
+                    Some(mode) => {
+                        let config = ModeConfig {
+                            width: mode.size.w,
+                            height: mode.size.h,
+                            refresh: (mode.refresh > 0).then_some(mode.refresh),
+                        };
+                        // Custom modes have to be close to a real one.
+                        if config.find(&output).is_none() {
+                            tracing::warn!("Output {} has no mode {:?}", output.name(), config);
+                            return None;
+                        }
+                        Some(config)
+                    }
+                    None => current.mode,
+                };
+                let config = OutputConfig {
+                    enable: true,
+                    mode,
+                    position: head
+                        .position
+                        .map(|position| [position.x, position.y])
+                        .or(current.position),
+                    scale: head.scale.or(current.scale),
+                    transform: head
+                        .transform
+                        .map(OutputTransform::from)
+                        .or(current.transform),
+                    vrr: current.vrr,
+                };
+                Some((output, config))
+            })
+            .collect()
+    }
+
+    /// Settle the last applied output configuration now that the backend has tried it, then send
+    /// the output managers what has changed. `failed` are the outputs the backend could not set up
+    /// as asked. Called on every event loop iteration.
+    ///
+    /// Tests that the backend has not answered only needed the modes to be among the ones of their
+    /// outputs, which has been checked when they came in.
+    pub fn refresh_output_management(&mut self, failed: &[Output]) {
+        for test in self.pending_output_tests.drain(..) {
+            test.configuration.succeeded();
+        }
+
+        if let Some(pending) = self.pending_output_configuration.take() {
+            if failed.is_empty() {
+                pending.configuration.succeeded();
+            } else {
+                tracing::warn!("Failed to apply the output configuration, reverting it");
+                self.output_overrides = pending.previous;
+                self.configure_outputs();
+                pending.configuration.failed();
+            }
+        }
+
+        let heads = self
+            .outputs
+            .iter()
+            .map(|output| OutputHead {
+                output: output.clone(),
+                position: self.space.output_geometry(output).map(|geo| geo.loc),
+            })
+            .collect::<Vec<_>>();
+        self.output_management_state.refresh::<Self>(&heads);
+    }
+}
@@ -4,7 +4,10 @@ use smithay::{
     reexports::wayland_server::protocol::wl_surface::WlSurface,
 };
 
-use crate::state::State;
+use crate::{
+    ipc::{window_id, Event},
+    state::State,
+};
 
 impl<BackendData: 'static> SeatHandler for State<BackendData> {
     type KeyboardFocus = WlSurface;
@@ -16,6 +19,23 @@ impl<BackendData: 'static> SeatHandler for State<BackendData> {
 
     fn cursor_image(&mut self, _seat: &Seat<Self>, image: CursorImageStatus) {
         self.cursor_status = image;
+        if let Some(pointer) = self.seat.get_pointer() {
+            self.queue_redraw_at(pointer.current_location());
+        }
+    }
+
+    fn focus_changed(&mut self, _seat: &Seat<Self>, focused: Option<&WlSurface>) {
+        let window = focused.and_then(|surface| {
+            self.space
+                .elements()
+                .find(|window| window.has_surface(surface))
+        });
+        // The window has got the attention it asked for.
+        if let Some(window) = window {
+            window.set_urgent(false);
+        }
+        let id = window.map(window_id);
+        self.emit_ipc_event(Event::WindowFocused { id });
     }
 }
 delegate_seat!(@<BackendData: 'static> State<BackendData>);
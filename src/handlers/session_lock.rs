@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+use smithay::{
+    backend::renderer::{
+        element::surface::{render_elements_from_surface_tree, WaylandSurfaceRenderElement},
+        ImportAll, Renderer,
+    },
+    delegate_session_lock,
+    desktop::utils::send_frames_surface_tree,
+    output::Output,
+    reexports::wayland_server::{
+        protocol::{wl_output::WlOutput, wl_surface::WlSurface},
+        Resource,
+    },
+    utils::{IsAlive, Logical, Point, SERIAL_COUNTER},
+    wayland::session_lock::{
+        LockSurface, SessionLockHandler, SessionLockManagerState, SessionLocker,
+    },
+};
+
+use crate::state::State;
+
+/// Shown on outputs without a lock surface, e.g. after the locker has crashed. It is red so that
+/// it is obvious the locker is gone, and the session can only be unlocked by starting a new one.
+pub const LOCKED_COLOR: [f32; 4] = [0.6, 0.0, 0.0, 1.0];
+
+#[derive(Default)]
+pub enum LockState {
+    #[default]
+    Unlocked,
+    Locked {
+        /// Kept until every output has shown a locked frame, then the locker is told that the
+        /// session is locked.
+        pending: Option<PendingLock>,
+        surfaces: Vec<(Output, LockSurface)>,
+    },
+}
+
+pub struct PendingLock {
+    locker: SessionLocker,
+    rendered: Vec<Output>,
+}
+
+impl<BackendData> SessionLockHandler for State<BackendData> {
+    fn lock_state(&mut self) -> &mut SessionLockManagerState {
+        &mut self.session_lock_state
+    }
+
+    fn lock(&mut self, confirmation: SessionLocker) {
+        // A live locker keeps its lock, and dropping the confirmation tells the new one that it
+        // failed. If every lock surface is dead, the previous locker has crashed and the new one
+        // takes over.
+        if let LockState::Locked { surfaces, .. } = &self.lock {
+            if surfaces
+                .iter()
+                .any(|(_, surface)| surface.wl_surface().alive())
+            {
+                return;
+            }
+        }
+
+        self.lock = LockState::Locked {
+            pending: Some(PendingLock {
+                locker: confirmation,
+                rendered: Vec::new(),
+            }),
+            surfaces: Vec::new(),
+        };
+        self.queue_redraw_all();
+
+        // Clients lose the input right away, not only once the locker shows up.
+        if let Some(keyboard) = self.seat.get_keyboard() {
+            keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+        }
+        self.refresh_pointer_focus();
+    }
+
+    fn unlock(&mut self) {
+        self.lock = LockState::Unlocked;
+        self.queue_redraw_all();
+
+        match self.topmost_window() {
+            Some(window) => self.focus_window(window),
+            None => {
+                if let Some(keyboard) = self.seat.get_keyboard() {
+                    keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+                }
+            }
+        }
+        self.refresh_pointer_focus();
+    }
+
+    fn new_surface(&mut self, surface: LockSurface, output: WlOutput) {
+        let output = match Output::from_resource(&output) {
+            Some(output) => output,
+            None => return,
+        };
+        let size = match self.space.output_geometry(&output) {
+            Some(geo) => geo.size,
+            None => return,
+        };
+        let surfaces = match &mut self.lock {
+            LockState::Locked { surfaces, .. } => surfaces,
+            LockState::Unlocked => return,
+        };
+
+        surface.with_pending_state(|state| {
+            state.size = Some((size.w as u32, size.h as u32).into());
+        });
+        surface.send_configure();
+
+        surfaces.retain(|(o, _)| *o != output);
+        surfaces.push((output, surface.clone()));
+
+        // The first lock surface gets the keyboard, the others get it when clicked.
+        if let Some(keyboard) = self.seat.get_keyboard() {
+            if keyboard.current_focus().is_none() {
+                keyboard.set_focus(
+                    self,
+                    Some(surface.wl_surface().clone()),
+                    SERIAL_COUNTER.next_serial(),
+                );
+            }
+        }
+    }
+}
+delegate_session_lock!(@<BackendData: 'static> State<BackendData>);
+
+impl LockState {
+    /// The lock surface of an output, if the session is locked and the locker has provided one.
+    pub fn surface(&self, output: &Output) -> Option<&LockSurface> {
+        match self {
+            LockState::Locked { surfaces, .. } => surfaces
+                .iter()
+                .find(|(o, _)| o == output)
+                .map(|(_, surface)| surface),
+            LockState::Unlocked => None,
+        }
+    }
+
+    /// What to draw on an output while the session is locked: only its lock surface, on top of
+    /// `LOCKED_COLOR`. Returns `None` if the session is not locked.
+    pub fn render_elements<R, E>(&self, renderer: &mut R, output: &Output) -> Option<Vec<E>>
+    where
+        R: Renderer + ImportAll,
+        <R as Renderer>::TextureId: 'static,
+        E: From<WaylandSurfaceRenderElement<R>>,
+    {
+        if let LockState::Unlocked = self {
+            return None;
+        }
+
+        let elements = match self.surface(output) {
+            Some(surface) => {
+                let scale = output.current_scale().fractional_scale();
+                render_elements_from_surface_tree(
+                    renderer,
+                    surface.wl_surface(),
+                    (0, 0),
+                    scale,
+                    1.0,
+                )
+            }
+            None => Vec::new(),
+        };
+
+        Some(elements)
+    }
+
+    /// Send the frame callbacks of the lock surface of an output.
+    pub fn send_frames(&self, output: &Output, time: Duration) {
+        if let Some(surface) = self.surface(output) {
+            send_frames_surface_tree(
+                surface.wl_surface(),
+                output,
+                time,
+                Some(Duration::ZERO),
+                |_, _| Some(output.clone()),
+            );
+        }
+    }
+}
+
+impl<BackendData> State<BackendData> {
+    pub fn is_locked(&self) -> bool {
+        matches!(self.lock, LockState::Locked { .. })
+    }
+
+    /// The lock surface under a point, with its location.
+    pub fn lock_surface_under(
+        &self,
+        pos: Point<f64, Logical>,
+    ) -> Option<(WlSurface, Point<i32, Logical>)> {
+        let output = self.space.output_under(pos).next()?;
+        let output_loc = self.space.output_geometry(output)?.loc;
+        let surface = self.lock.surface(output)?.wl_surface().clone();
+        Some((surface, output_loc))
+    }
+
+    /// Called by the backends once a locked frame is on screen on an output. A frame that failed
+    /// to be drawn or queued must not count, since the desktop would still be shown. Once every
+    /// output has shown one, the locker is told that the session is locked.
+    pub fn lock_frame_rendered(&mut self, output: &Output) {
+        let pending = match &mut self.lock {
+            LockState::Locked { pending, .. } => pending,
+            LockState::Unlocked => return,
+        };
+
+        if let Some(lock) = pending {
+            if !lock.rendered.contains(output) {
+                lock.rendered.push(output.clone());
+            }
+            if self
+                .space
+                .outputs()
+                .all(|output| lock.rendered.contains(output))
+            {
+                if let Some(lock) = pending.take() {
+                    lock.locker.lock();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use smithay::{
+    delegate_xdg_activation,
+    reexports::wayland_server::protocol::wl_surface::WlSurface,
+    wayland::xdg_activation::{
+        XdgActivationHandler, XdgActivationState, XdgActivationToken, XdgActivationTokenData,
+    },
+};
+
+use crate::state::State;
+
+/// Tokens older than this are not allowed to move the focus anymore.
+const TOKEN_TIMEOUT_SECS: u64 = 10;
+
+impl<BackendData> XdgActivationHandler for State<BackendData> {
+    fn activation_state(&mut self) -> &mut XdgActivationState {
+        &mut self.xdg_activation_state
+    }
+
+    /// Focus a window on a shown workspace if the token is fresh. Otherwise the window is marked
+    /// as urgent, so that workspace panels can point the user to it.
+    fn request_activation(
+        &mut self,
+        token: XdgActivationToken,
+        token_data: XdgActivationTokenData,
+        surface: WlSurface,
+    ) {
+        let window = match self
+            .all_windows()
+            .find(|window| window.has_surface(&surface))
+            .cloned()
+        {
+            Some(window) => window,
+            None => return,
+        };
+
+        let fresh = token_data.timestamp.elapsed().as_secs() < TOKEN_TIMEOUT_SECS;
+        let visible = self
+            .workspace_of_window(&window)
+            .map_or(false, |workspace| workspace.visible);
+        if fresh && visible {
+            self.focus_window(window);
+        } else {
+            window.set_urgent(true);
+        }
+        self.xdg_activation_state.remove_request(&token);
+    }
+
+    fn destroy_activation(
+        &mut self,
+        _token: XdgActivationToken,
+        _token_data: XdgActivationTokenData,
+        _surface: WlSurface,
+    ) {
+    }
+}
+delegate_xdg_activation!(@<BackendData: 'static> State<BackendData>);
@@ -1,6 +1,6 @@
 use smithay::{
     delegate_xdg_shell,
-    desktop::{PopupKind, Window},
+    desktop::{space::SpaceElement, PopupKind, Window},
     input::{
         pointer::{Focus, GrabStartData},
         Seat,
@@ -8,19 +8,25 @@ use smithay::{
     reexports::{
         wayland_protocols::xdg::shell::server::xdg_toplevel,
         wayland_server::{
-            protocol::{wl_seat::WlSeat, wl_surface::WlSurface},
+            protocol::{wl_output::WlOutput, wl_seat::WlSeat, wl_surface::WlSurface},
             Resource,
         },
     },
     utils::{Rectangle, Serial},
-    wayland::shell::xdg::{
-        PopupSurface, PositionerState, ToplevelSurface, XdgShellHandler, XdgShellState,
+    wayland::{
+        compositor::with_states,
+        shell::xdg::{
+            PopupSurface, PositionerState, ToplevelSurface, XdgShellHandler, XdgShellState,
+            XdgToplevelSurfaceData,
+        },
     },
 };
 
 use crate::{
     grabs::{resize_grab::ResizeSurfaceGrab, MoveSurfaceGrab},
+    ipc::{window_id, Event},
     state::State,
+    window::WindowElement,
 };
 
 impl<BackendData> XdgShellHandler for State<BackendData> {
@@ -29,13 +35,29 @@ impl<BackendData> XdgShellHandler for State<BackendData> {
     }
 
     fn new_toplevel(&mut self, surface: ToplevelSurface) {
-        let window = Window::new(surface);
-        self.space.map_element(window, (0, 0), true);
+        self.map_new_window(WindowElement::Wayland(Window::new(surface)));
     }
 
-    fn toplevel_destroyed(&mut self, _surface: ToplevelSurface) {
+    fn toplevel_destroyed(&mut self, surface: ToplevelSurface) {
+        // Clients only hear about windows that have made their initial commit.
+        let initial_configure_sent = with_states(surface.wl_surface(), |states| {
+            states
+                .data_map
+                .get::<XdgToplevelSurfaceData>()
+                .map_or(false, |data| data.lock().unwrap().initial_configure_sent)
+        });
+        if let Some(window) = self
+            .window_for_toplevel(&surface)
+            .filter(|_| initial_configure_sent)
+        {
+            self.emit_ipc_event(Event::WindowClosed {
+                id: window_id(&window),
+            });
+        }
+
         self.popups.cleanup();
         self.space.refresh();
+        self.remove_dead_windows();
     }
 
     fn new_popup(&mut self, surface: PopupSurface, _positioner: PositionerState) {
@@ -53,7 +75,7 @@ impl<BackendData> XdgShellHandler for State<BackendData> {
             let window = self
                 .space
                 .elements()
-                .find(|w| w.toplevel().wl_surface() == wl_surface)
+                .find(|w| w.has_surface(wl_surface))
                 .unwrap()
                 .clone();
             let initial_location = self.space.element_location(&window).unwrap();
@@ -89,7 +111,7 @@ impl<BackendData> XdgShellHandler for State<BackendData> {
             let window = self
                 .space
                 .elements()
-                .find(|w| w.toplevel().wl_surface() == wl_surface)
+                .find(|w| w.has_surface(wl_surface))
                 .cloned()
                 .unwrap();
 
@@ -113,9 +135,63 @@ impl<BackendData> XdgShellHandler for State<BackendData> {
     }
 
     fn grab(&mut self, _surface: PopupSurface, _seat: WlSeat, _serial: Serial) {}
+
+    fn maximize_request(&mut self, surface: ToplevelSurface) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.set_toplevel_state(&window, xdg_toplevel::State::Maximized, true);
+        }
+    }
+
+    fn unmaximize_request(&mut self, surface: ToplevelSurface) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.set_toplevel_state(&window, xdg_toplevel::State::Maximized, false);
+        }
+    }
+
+    fn fullscreen_request(&mut self, surface: ToplevelSurface, _output: Option<WlOutput>) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.set_toplevel_state(&window, xdg_toplevel::State::Fullscreen, true);
+        }
+    }
+
+    fn unfullscreen_request(&mut self, surface: ToplevelSurface) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.set_toplevel_state(&window, xdg_toplevel::State::Fullscreen, false);
+        }
+    }
 }
 delegate_xdg_shell!(@<BackendData: 'static> State<BackendData>);
 
+impl<BackendData> State<BackendData> {
+    /// Show a new window and add it to the current workspace. It is placed at the top left of the
+    /// usable area of the output under the pointer, so that it is not covered by panels.
+    ///
+    /// IPC clients are told about it by the caller, once its title and app id are known.
+    pub fn map_new_window(&mut self, window: WindowElement) {
+        let location = self
+            .seat
+            .get_pointer()
+            .and_then(|pointer| {
+                self.space
+                    .output_under(pointer.current_location())
+                    .next()
+                    .cloned()
+            })
+            .or_else(|| self.space.outputs().next().cloned())
+            .and_then(|output| self.usable_area(&output))
+            .map(|area| area.loc)
+            .unwrap_or_default();
+        self.space.map_element(window.clone(), location, true);
+        self.add_window_to_workspace(&window);
+    }
+
+    fn window_for_toplevel(&self, surface: &ToplevelSurface) -> Option<WindowElement> {
+        self.all_windows()
+            .find(|w| w.has_surface(surface.wl_surface()))
+            .cloned()
+    }
+}
+
 fn check_grab<BackendData>(
     seat: &Seat<State<BackendData>>,
     surface: &WlSurface,
@@ -0,0 +1,359 @@
+use std::os::fd::OwnedFd;
+
+use smithay::{
+    desktop::space::SpaceElement,
+    input::pointer::{Focus, GrabStartData, PointerHandle},
+    reexports::wayland_protocols::xdg::shell::server::xdg_toplevel,
+    utils::{Logical, Rectangle, SERIAL_COUNTER},
+    wayland::data_device::{
+        clear_data_device_selection, current_data_device_selection_userdata,
+        request_data_device_client_selection, set_data_device_selection,
+    },
+    xwayland::{
+        xwm::{Reorder, ResizeEdge as X11ResizeEdge, SelectionType, XwmId},
+        X11Surface, X11Wm, XwmHandler,
+    },
+};
+
+use crate::{
+    data::Data,
+    grabs::{
+        resize_grab::{ResizeEdge, ResizeSurfaceGrab},
+        MoveSurfaceGrab,
+    },
+    ipc::{window_id, Event},
+    state::State,
+    window::{from_x11, set_x11_scale, x11_scale, WindowElement},
+};
+
+impl<BackendData: 'static> XwmHandler for Data<BackendData> {
+    fn xwm_state(&mut self, _xwm: XwmId) -> &mut X11Wm {
+        self.state.xwm.as_mut().unwrap()
+    }
+
+    fn new_window(&mut self, _xwm: XwmId, _window: X11Surface) {}
+
+    fn new_override_redirect_window(&mut self, _xwm: XwmId, _window: X11Surface) {}
+
+    fn map_window_request(&mut self, _xwm: XwmId, window: X11Surface) {
+        if let Err(err) = window.set_mapped(true) {
+            tracing::warn!("Failed to map X11 window: {}", err);
+            return;
+        }
+        // The size the client asked for is kept in X11 pixels.
+        set_x11_scale(&window, self.state.x11_scale());
+        let window = WindowElement::X11(window);
+        self.state.map_new_window(window.clone());
+
+        let window = self.state.window_info(&window);
+        self.state.emit_ipc_event(Event::WindowOpened { window });
+    }
+
+    fn mapped_override_redirect_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        let scale = self.state.x11_scale();
+        set_x11_scale(&window, scale);
+        let location = from_x11(window.geometry(), scale).loc;
+        self.state
+            .space
+            .map_element(WindowElement::X11(window), location, true);
+    }
+
+    fn unmapped_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        if let Some(element) = self.state.x11_window(&window) {
+            if self.state.workspace_of_window(&element).is_some() {
+                self.state.emit_ipc_event(Event::WindowClosed {
+                    id: window_id(&element),
+                });
+            }
+            self.state.space.unmap_elem(&element);
+            self.state.remove_window(&element);
+        }
+        if !window.is_override_redirect() {
+            if let Err(err) = window.set_mapped(false) {
+                tracing::warn!("Failed to unmap X11 window: {}", err);
+            }
+        }
+    }
+
+    fn destroyed_window(&mut self, _xwm: XwmId, _window: X11Surface) {}
+
+    fn configure_request(
+        &mut self,
+        _xwm: XwmId,
+        window: X11Surface,
+        _x: Option<i32>,
+        _y: Option<i32>,
+        w: Option<u32>,
+        h: Option<u32>,
+        _reorder: Option<Reorder>,
+    ) {
+        // Windows are placed by the compositor, only the size is up to the client.
+        let mut geometry = window.geometry();
+        if let Some(w) = w {
+            geometry.size.w = w as i32;
+        }
+        if let Some(h) = h {
+            geometry.size.h = h as i32;
+        }
+        if let Err(err) = window.configure(geometry) {
+            tracing::warn!("Failed to configure X11 window: {}", err);
+        }
+    }
+
+    // Override-redirect windows are moved along in `refresh_x11_windows`.
+    fn configure_notify(
+        &mut self,
+        _xwm: XwmId,
+        _window: X11Surface,
+        _geometry: Rectangle<i32, Logical>,
+        _above: Option<u32>,
+    ) {
+    }
+
+    fn maximize_request(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.set_x11_state(&window, xdg_toplevel::State::Maximized, true);
+    }
+
+    fn unmaximize_request(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.set_x11_state(&window, xdg_toplevel::State::Maximized, false);
+    }
+
+    fn fullscreen_request(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.set_x11_state(&window, xdg_toplevel::State::Fullscreen, true);
+    }
+
+    fn unfullscreen_request(&mut self, _xwm: XwmId, window: X11Surface) {
+        self.set_x11_state(&window, xdg_toplevel::State::Fullscreen, false);
+    }
+
+    fn resize_request(
+        &mut self,
+        _xwm: XwmId,
+        window: X11Surface,
+        _button: u32,
+        edges: X11ResizeEdge,
+    ) {
+        let state = &mut self.state;
+        let (pointer, start_data) = match state.x11_grab_start(&window) {
+            Some(grab) => grab,
+            None => return,
+        };
+        let element = match state.x11_window(&window) {
+            Some(element) => element,
+            None => return,
+        };
+        let location = match state.space.element_location(&element) {
+            Some(location) => location,
+            None => return,
+        };
+        let size = SpaceElement::geometry(&element).size;
+
+        let grab = ResizeSurfaceGrab::start(
+            start_data,
+            element,
+            resize_edge(edges),
+            Rectangle::from_loc_and_size(location, size),
+        );
+        pointer.set_grab(state, grab, SERIAL_COUNTER.next_serial(), Focus::Clear);
+    }
+
+    fn move_request(&mut self, _xwm: XwmId, window: X11Surface, _button: u32) {
+        let state = &mut self.state;
+        let (pointer, start_data) = match state.x11_grab_start(&window) {
+            Some(grab) => grab,
+            None => return,
+        };
+        let element = match state.x11_window(&window) {
+            Some(element) => element,
+            None => return,
+        };
+        let initial_location = match state.space.element_location(&element) {
+            Some(location) => location,
+            None => return,
+        };
+
+        let grab = MoveSurfaceGrab {
+            start_data,
+            window: element,
+            initial_location,
+        };
+        pointer.set_grab(state, grab, SERIAL_COUNTER.next_serial(), Focus::Clear);
+    }
+
+    // Only the focused X11 window may read the clipboard, like Wayland clients.
+    fn allow_selection_access(&mut self, xwm: XwmId, _selection: SelectionType) -> bool {
+        match self.state.focused_window() {
+            Some(WindowElement::X11(surface)) => surface.xwm_id() == Some(xwm),
+            _ => false,
+        }
+    }
+
+    fn send_selection(
+        &mut self,
+        _xwm: XwmId,
+        selection: SelectionType,
+        mime_type: String,
+        fd: OwnedFd,
+    ) {
+        match selection {
+            SelectionType::Clipboard => {
+                if let Err(err) =
+                    request_data_device_client_selection(&self.state.seat, mime_type, fd)
+                {
+                    tracing::warn!("Failed to send the clipboard to Xwayland: {}", err);
+                }
+            }
+            // There is no primary selection on the Wayland side to bridge to.
+            SelectionType::Primary => (),
+        }
+    }
+
+    fn new_selection(&mut self, _xwm: XwmId, selection: SelectionType, mime_types: Vec<String>) {
+        match selection {
+            SelectionType::Clipboard => set_data_device_selection(
+                &self.state.display_handle,
+                &self.state.seat,
+                mime_types,
+                (),
+            ),
+            SelectionType::Primary => (),
+        }
+    }
+
+    fn cleared_selection(&mut self, _xwm: XwmId, selection: SelectionType) {
+        match selection {
+            // Only the selection that came from X11 is cleared, not one owned by a Wayland client.
+            SelectionType::Clipboard => {
+                if current_data_device_selection_userdata(&self.state.seat).is_some() {
+                    clear_data_device_selection(&self.state.display_handle, &self.state.seat);
+                }
+            }
+            SelectionType::Primary => (),
+        }
+    }
+}
+
+impl<BackendData: 'static> Data<BackendData> {
+    fn set_x11_state(&mut self, window: &X11Surface, state: xdg_toplevel::State, set: bool) {
+        if let Some(element) = self.state.x11_window(window) {
+            self.state.set_toplevel_state(&element, state, set);
+        }
+    }
+}
+
+impl<BackendData> State<BackendData> {
+    /// How many X11 pixels make a logical pixel: the biggest output scale, so that X11 clients
+    /// render at the native resolution of the densest output and are only ever scaled down.
+    pub fn x11_scale(&self) -> f64 {
+        self.space
+            .outputs()
+            .map(|output| output.current_scale().fractional_scale())
+            .reduce(f64::max)
+            .unwrap_or(1.0)
+    }
+
+    /// Drop the windows of an Xwayland that has exited, the XWM won't unmap them anymore.
+    pub fn remove_x11_windows(&mut self) {
+        let windows: Vec<WindowElement> = self
+            .space
+            .elements()
+            .chain(self.all_windows())
+            .filter(|window| matches!(window, WindowElement::X11(_)))
+            .cloned()
+            .collect();
+        for window in windows {
+            if self.workspace_of_window(&window).is_some() {
+                self.emit_ipc_event(Event::WindowClosed {
+                    id: window_id(&window),
+                });
+            }
+            self.space.unmap_elem(&window);
+            self.remove_window(&window);
+        }
+    }
+
+    /// The element of an X11 window, on any workspace.
+    fn x11_window(&self, surface: &X11Surface) -> Option<WindowElement> {
+        self.space
+            .elements()
+            .chain(self.all_windows())
+            .find(|window| matches!(window, WindowElement::X11(s) if s == surface))
+            .cloned()
+    }
+
+    /// The pointer and its grab, if the X11 window has the pointer focus while a button is down.
+    fn x11_grab_start(
+        &self,
+        surface: &X11Surface,
+    ) -> Option<(PointerHandle<Self>, GrabStartData<Self>)> {
+        let pointer = self.seat.get_pointer()?;
+        let start_data = pointer.grab_start_data()?;
+        let (focus, _) = start_data.focus.as_ref()?;
+        if Some(focus) != surface.wl_surface().as_ref() {
+            return None;
+        }
+        Some((pointer, start_data))
+    }
+
+    /// Keep X11 windows in line with `space`. Managed windows are told where they have been moved,
+    /// override-redirect ones are put where they have moved themselves, and both are rescaled when
+    /// the X11 scale changes. Called on every event loop iteration.
+    pub fn refresh_x11_windows(&mut self) {
+        let scale = self.x11_scale();
+        let windows = self
+            .space
+            .elements()
+            .filter_map(|window| match window {
+                WindowElement::X11(surface) => Some((window.clone(), surface.clone())),
+                WindowElement::Wayland(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (window, surface) in windows {
+            let previous_scale = x11_scale(&surface);
+            set_x11_scale(&surface, scale);
+
+            if surface.is_override_redirect() {
+                let location = from_x11(surface.geometry(), scale).loc;
+                if self.space.element_location(&window) != Some(location) {
+                    self.space.map_element(window, location, false);
+                }
+                continue;
+            }
+
+            let location = match self.space.element_location(&window) {
+                Some(location) => location,
+                None => continue,
+            };
+            let mut geometry = surface.geometry();
+            geometry.loc = location.to_f64().upscale(scale).to_i32_round();
+            // The size is only touched when the scale changes, so that clients that resize in
+            // steps, like terminals, are not fought with.
+            if previous_scale != scale {
+                geometry.size = geometry
+                    .size
+                    .to_f64()
+                    .upscale(scale / previous_scale)
+                    .to_i32_round();
+            }
+            if geometry != surface.geometry() {
+                if let Err(err) = surface.configure(geometry) {
+                    tracing::warn!("Failed to configure X11 window: {}", err);
+                }
+            }
+        }
+    }
+}
+
+fn resize_edge(edge: X11ResizeEdge) -> ResizeEdge {
+    match edge {
+        X11ResizeEdge::Top => ResizeEdge::TOP,
+        X11ResizeEdge::Bottom => ResizeEdge::BOTTOM,
+        X11ResizeEdge::Left => ResizeEdge::LEFT,
+        X11ResizeEdge::Right => ResizeEdge::RIGHT,
+        X11ResizeEdge::TopLeft => ResizeEdge::TOP_LEFT,
+        X11ResizeEdge::TopRight => ResizeEdge::TOP_RIGHT,
+        X11ResizeEdge::BottomLeft => ResizeEdge::BOTTOM_LEFT,
+        X11ResizeEdge::BottomRight => ResizeEdge::BOTTOM_RIGHT,
+    }
+}
@@ -1,25 +1,27 @@
+use std::time::Duration;
+
 use smithay::{
     backend::input::{
-        AbsolutePositionEvent, Axis, AxisSource, ButtonState, Event, InputBackend, InputEvent,
-        KeyboardKeyEvent, PointerAxisEvent, PointerButtonEvent, PointerMotionEvent,
+        AbsolutePositionEvent, Axis, AxisSource, ButtonState, Device, DeviceCapability, Event,
+        InputBackend, InputEvent, KeyState, KeyboardKeyEvent, PointerAxisEvent, PointerButtonEvent,
+        PointerMotionEvent,
     },
+    desktop::layer_map_for_output,
     input::{
-        keyboard::{xkb, FilterResult, Keysym, ModifiersState},
+        keyboard::{xkb, FilterResult, KeysymHandle, ModifiersState},
         pointer::{AxisFrame, ButtonEvent, MotionEvent, RelativeMotionEvent},
     },
-    utils::SERIAL_COUNTER,
+    output::Output,
+    utils::{Logical, Point, Serial, SERIAL_COUNTER},
+    wayland::shell::wlr_layer::Layer,
 };
 
-use crate::state::State;
-
-pub enum Action {
-    /// Nothing to do, for example when a keyboard event is passed to the client.
-    None,
-    /// Ctrl-Alt-Fx, to change tty.
-    ChangeVt(i32),
-    /// Ctrl-Alt-Backspace, to exit the compositor.
-    Quit,
-}
+use crate::{
+    action::Action,
+    config::{Bindings, Trigger},
+    ipc::InputDeviceInfo,
+    state::State,
+};
 
 impl<BackendData> State<BackendData> {
     /// Whenever an input event is occurred, pass it to this function, no matter whether it is a
@@ -31,28 +33,32 @@ impl<BackendData> State<BackendData> {
         match event {
             // Handle keyboard events.
             InputEvent::Keyboard { event } => {
-                // Currently keyboard events are forwarded to clients.
+                // Keyboard events are forwarded to clients, unless they trigger a binding.
                 let serial = SERIAL_COUNTER.next_serial();
                 let time = Event::time_msec(&event);
+                let key_code = event.key_code();
+                let key_state = event.state();
+                match key_state {
+                    KeyState::Pressed => self.pressed_keys.insert(key_code),
+                    KeyState::Released => self.pressed_keys.remove(&key_code),
+                };
 
                 if let Some(keyboard) = self.seat.get_keyboard() {
                     let action = keyboard
                         .input::<Action, _>(
                             self,
-                            event.key_code(),
-                            event.state(),
+                            key_code,
+                            key_state,
                             serial,
                             time,
-                            |_, modifiers, handler| {
-                                let sym = handler.modified_sym();
-                                if let Some(action) = process_keyboard_shortcut(modifiers, sym) {
-                                    return FilterResult::Intercept(action);
-                                }
-                                FilterResult::Forward
+                            |state, modifiers, handler| {
+                                state.filter_keyboard_shortcut(
+                                    key_code, key_state, modifiers, &handler,
+                                )
                             },
                         )
                         .unwrap_or(Action::None);
-                    return action;
+                    return self.run_action(action);
                 }
             }
             // When a pointer moves, for the DRM backend.
@@ -86,6 +92,8 @@ impl<BackendData> State<BackendData> {
                             utime: event.time(),
                         },
                     );
+                    self.queue_redraw_at(new_location - event.delta());
+                    self.queue_redraw_at(new_location);
                 }
             }
             // When a pointer moves, for the Winit backend.
@@ -95,20 +103,7 @@ impl<BackendData> State<BackendData> {
                         Some(output) => output.clone(),
                         None => return Action::None,
                     };
-                    let output_geo = self.space.output_geometry(&output).unwrap();
-                    let pos = event.position_transformed(output_geo.size) + output_geo.loc.to_f64();
-
-                    let serial = SERIAL_COUNTER.next_serial();
-                    let under = self.surface_under_pointer(&pointer);
-                    pointer.motion(
-                        self,
-                        under,
-                        &MotionEvent {
-                            location: pos,
-                            serial,
-                            time: event.time_msec(),
-                        },
-                    );
+                    self.pointer_motion_absolute::<B, _>(&event, &output);
                 }
             }
             // When a mouse wheel rolls.
@@ -156,33 +151,7 @@ impl<BackendData> State<BackendData> {
                     let serial = SERIAL_COUNTER.next_serial();
 
                     if button_state == ButtonState::Pressed && !pointer.is_grabbed() {
-                        if let Some(window) = self
-                            .space
-                            .element_under(pointer.current_location())
-                            .map(|(w, _)| w.clone())
-                        {
-                            // Show the clicked window on the top.
-                            self.space.raise_element(&window, true);
-                            // If there is a keyboard, set its focus to the clicked window.
-                            if let Some(keyboard) = self.seat.get_keyboard() {
-                                keyboard.set_focus(
-                                    self,
-                                    Some(window.toplevel().wl_surface().clone()),
-                                    serial,
-                                );
-                            }
-                            for window in self.space.elements() {
-                                window.toplevel().send_pending_configure();
-                            }
-                        } else {
-                            for window in self.space.elements() {
-                                window.set_activated(false);
-                                window.toplevel().send_pending_configure();
-                            }
-                            if let Some(keyboard) = self.seat.get_keyboard() {
-                                keyboard.set_focus(self, None, serial);
-                            }
-                        }
+                        self.focus_under_pointer(pointer.current_location(), serial);
                     }
                     pointer.button(
                         self,
@@ -195,21 +164,233 @@ impl<BackendData> State<BackendData> {
                     );
                 }
             }
+            // Keep track of input devices, so that they can be listed over IPC.
+            InputEvent::DeviceAdded { device } => {
+                self.input_devices.push(InputDeviceInfo {
+                    id: device.id(),
+                    name: device.name(),
+                    keyboard: device.has_capability(DeviceCapability::Keyboard),
+                    pointer: device.has_capability(DeviceCapability::Pointer),
+                    touch: device.has_capability(DeviceCapability::Touch),
+                });
+            }
+            InputEvent::DeviceRemoved { device } => {
+                let id = device.id();
+                self.input_devices.retain(|info| info.id != id);
+            }
             _ => (),
         }
         Action::None
     }
+
+    /// Decide whether a key event is forwarded to the client or intercepted for a binding.
+    /// When a binding is triggered by a key press, the release of that key is intercepted as well,
+    /// so that clients never see half of a key stroke. Bindings triggered on release run their
+    /// action then.
+    fn filter_keyboard_shortcut(
+        &mut self,
+        key_code: u32,
+        key_state: KeyState,
+        modifiers: &ModifiersState,
+        handle: &KeysymHandle<'_>,
+    ) -> FilterResult<Action> {
+        match key_state {
+            KeyState::Pressed => {
+                let locked = self.is_locked();
+                match process_keyboard_shortcut(&self.config.bindings, locked, modifiers, handle) {
+                    Some((action, Trigger::Press)) => {
+                        self.intercepted_keys.insert(key_code, Action::None);
+                        FilterResult::Intercept(action)
+                    }
+                    Some((action, Trigger::Release)) => {
+                        self.intercepted_keys.insert(key_code, action);
+                        FilterResult::Intercept(Action::None)
+                    }
+                    None => FilterResult::Forward,
+                }
+            }
+            KeyState::Released => match self.intercepted_keys.remove(&key_code) {
+                Some(action) => FilterResult::Intercept(action),
+                None => FilterResult::Forward,
+            },
+        }
+    }
+
+    /// Release the keys that are still down as far as the keyboard knows, for when their releases
+    /// went elsewhere, like to another VT. Clients get the releases of the keys they saw pressed,
+    /// and bindings on release are not run.
+    pub fn release_pressed_keys(&mut self) {
+        let keyboard = match self.seat.get_keyboard() {
+            Some(keyboard) => keyboard,
+            None => return,
+        };
+        let time = Duration::from(self.clock.now()).as_millis() as u32;
+        for key_code in std::mem::take(&mut self.pressed_keys) {
+            let intercepted = self.intercepted_keys.remove(&key_code).is_some();
+            keyboard.input::<(), _>(
+                self,
+                key_code,
+                KeyState::Released,
+                SERIAL_COUNTER.next_serial(),
+                time,
+                move |_, _, _| {
+                    if intercepted {
+                        FilterResult::Intercept(())
+                    } else {
+                        FilterResult::Forward
+                    }
+                },
+            );
+        }
+    }
+
+    /// Move the pointer to an absolute position inside `output`.
+    /// Backends that know which output the event belongs to (e.g. with several host windows) can
+    /// call this directly instead of going through `handle_input`.
+    pub fn pointer_motion_absolute<B, E>(&mut self, event: &E, output: &Output)
+    where
+        B: InputBackend,
+        E: AbsolutePositionEvent<B>,
+    {
+        let pointer = match self.seat.get_pointer() {
+            Some(pointer) => pointer,
+            None => return,
+        };
+        let output_geo = match self.space.output_geometry(output) {
+            Some(geo) => geo,
+            None => return,
+        };
+        let pos = event.position_transformed(output_geo.size) + output_geo.loc.to_f64();
+
+        let serial = SERIAL_COUNTER.next_serial();
+        let under = self.surface_under_pointer(&pointer);
+        self.queue_redraw_at(pointer.current_location());
+        pointer.motion(
+            self,
+            under,
+            &MotionEvent {
+                location: pos,
+                serial,
+                time: event.time_msec(),
+            },
+        );
+        self.queue_redraw_at(pos);
+    }
+
+    /// Update the pointer focus without moving it, e.g. after the surface under it has changed.
+    pub fn refresh_pointer_focus(&mut self) {
+        let pointer = match self.seat.get_pointer() {
+            Some(pointer) => pointer,
+            None => return,
+        };
+
+        let under = self.surface_under_pointer(&pointer);
+        pointer.motion(
+            self,
+            under,
+            &MotionEvent {
+                location: pointer.current_location(),
+                serial: SERIAL_COUNTER.next_serial(),
+                time: self.start_time.elapsed().as_millis() as u32,
+            },
+        );
+    }
+
+    /// Give keyboard focus to what was clicked: a layer surface that accepts it, or a window,
+    /// which is raised as well. While a layer surface has exclusive keyboard interactivity, the
+    /// focus stays on it.
+    fn focus_under_pointer(&mut self, pos: Point<f64, Logical>, serial: Serial) {
+        let keyboard = match self.seat.get_keyboard() {
+            Some(keyboard) => keyboard,
+            None => return,
+        };
+        if self.is_locked() {
+            if let Some((surface, _)) = self.lock_surface_under(pos) {
+                keyboard.set_focus(self, Some(surface), serial);
+            }
+            return;
+        }
+        if self.exclusive_layer().is_some() {
+            return;
+        }
+
+        if let Some(output) = self.space.output_under(pos).next().cloned() {
+            let output_loc = self.space.output_geometry(&output).unwrap().loc;
+            let layer = {
+                let map = layer_map_for_output(&output);
+                let pos = pos - output_loc.to_f64();
+                map.layer_under(Layer::Overlay, pos)
+                    .or_else(|| map.layer_under(Layer::Top, pos))
+                    .or_else(|| {
+                        // Only fall through to the lower layers when no window is clicked.
+                        if self
+                            .space
+                            .element_under(pos + output_loc.to_f64())
+                            .is_some()
+                        {
+                            return None;
+                        }
+                        map.layer_under(Layer::Bottom, pos)
+                            .or_else(|| map.layer_under(Layer::Background, pos))
+                    })
+                    .cloned()
+            };
+            if let Some(layer) = layer {
+                if layer.can_receive_keyboard_focus() {
+                    keyboard.set_focus(self, Some(layer.wl_surface().clone()), serial);
+                }
+                return;
+            }
+        }
+
+        if let Some(window) = self.space.element_under(pos).map(|(w, _)| w.clone()) {
+            // Menus of X11 clients take care of their input themselves.
+            if window.is_override_redirect() {
+                return;
+            }
+            // Show the clicked window on the top.
+            self.space.raise_element(&window, true);
+            keyboard.set_focus(self, window.wl_surface(), serial);
+            for window in self.space.elements() {
+                window.send_pending_configure();
+            }
+        } else {
+            for window in self.space.elements() {
+                window.set_activated(false);
+                window.send_pending_configure();
+            }
+            keyboard.set_focus(self, None, serial);
+        }
+    }
 }
 
-/// Checks if a keyboard shortcut is tiggered.
-fn process_keyboard_shortcut(modifiers: &ModifiersState, keysym: Keysym) -> Option<Action> {
-    if keysym == xkb::KEY_BackSpace && modifiers.ctrl && modifiers.alt {
-        Some(Action::Quit)
-    } else if (xkb::KEY_XF86Switch_VT_1..=xkb::KEY_XF86Switch_VT_12).contains(&keysym) {
-        Some(Action::ChangeVt(
-            (keysym - xkb::KEY_XF86Switch_VT_1 + 1) as i32,
-        ))
-    } else {
-        None
+/// Checks if a keyboard shortcut is tiggered, and when it should run.
+fn process_keyboard_shortcut(
+    bindings: &Bindings,
+    locked: bool,
+    modifiers: &ModifiersState,
+    handle: &KeysymHandle<'_>,
+) -> Option<(Action, Trigger)> {
+    let keysym = handle.modified_sym();
+
+    // Switching VT is not configurable, so that there is always a way out.
+    if (xkb::KEY_XF86Switch_VT_1..=xkb::KEY_XF86Switch_VT_12).contains(&keysym) {
+        return Some((
+            Action::ChangeVt((keysym - xkb::KEY_XF86Switch_VT_1 + 1) as i32),
+            Trigger::Press,
+        ));
+    }
+    // Bindings could be used to get around a screen locker.
+    if locked {
+        return None;
     }
+
+    bindings
+        .0
+        .iter()
+        .find(|binding| {
+            binding.modifiers.matches(modifiers)
+                && (binding.keysym == keysym || handle.raw_syms().contains(&binding.keysym))
+        })
+        .map(|binding| (binding.action.clone(), binding.trigger))
 }
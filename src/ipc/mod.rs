@@ -0,0 +1,389 @@
+mod protocol;
+
+pub use protocol::*;
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use smithay::{
+    output::Output,
+    reexports::calloop::{
+        self, generic::Generic, EventLoop, Interest, LoopHandle, PostAction, RegistrationToken,
+    },
+    utils::{Logical, Rectangle},
+};
+
+use crate::{data::Data, state::State, window::WindowElement, workspace::WorkspaceTarget};
+
+/// A client that lets this much pile up without reading it is dropped.
+const MAX_OUTGOING_LEN: usize = 1 << 20;
+
+/// The IPC server, see `protocol` for the messages it understands.
+pub struct IpcState {
+    socket_path: PathBuf,
+    /// The connected clients, by an ID of their own.
+    clients: HashMap<u64, IpcClient>,
+    next_client_id: u64,
+}
+
+/// A connection to the IPC socket.
+struct IpcClient {
+    /// The stream messages are written to. It is read by the event source.
+    stream: UnixStream,
+    /// The event source reading requests, and writing what is left once the socket is writable.
+    source: RegistrationToken,
+    /// Whether it asked for a stream of events.
+    subscribed: bool,
+    /// Messages the socket has not taken yet.
+    outgoing: Vec<u8>,
+}
+
+impl Drop for IpcState {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.socket_path).ok();
+    }
+}
+
+/// A unique ID of a window, stored in its user data.
+struct WindowId(u64);
+
+static NEXT_WINDOW_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The ID clients refer to a window with. It is assigned the first time it is asked for.
+pub fn window_id(window: &WindowElement) -> u64 {
+    window
+        .user_data()
+        .insert_if_missing(|| WindowId(NEXT_WINDOW_ID.fetch_add(1, Ordering::Relaxed)));
+    window.user_data().get::<WindowId>().unwrap().0
+}
+
+/// Create the IPC socket in `$XDG_RUNTIME_DIR` and listen on it. The path of the socket is put in
+/// `$ALIOTH_SOCKET`, so that programs started by the compositor can find it.
+pub fn init_ipc_server<BackendData>(
+    event_loop: &mut EventLoop<Data<BackendData>>,
+) -> Result<IpcState, anyhow::Error> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let socket_path = runtime_dir.join(format!("alioth-ipc.{}.sock", std::process::id()));
+
+    // A stale socket may be left behind by a crashed compositor with the same PID.
+    std::fs::remove_file(&socket_path).ok();
+    let listener = UnixListener::bind(&socket_path)?;
+    listener.set_nonblocking(true)?;
+
+    let handle = event_loop.handle();
+    event_loop
+        .handle()
+        .insert_source(
+            Generic::new(listener, Interest::READ, calloop::Mode::Level),
+            move |_, listener, data| {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Err(err) = insert_ipc_client(&handle, &mut data.state, stream) {
+                                tracing::warn!("Failed to accept IPC client: {}", err);
+                            }
+                        }
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to insert the IPC socket into the event loop"))?;
+
+    std::env::set_var(SOCKET_ENV, &socket_path);
+    tracing::info!("Listening on IPC socket {}", socket_path.display());
+
+    Ok(IpcState {
+        socket_path,
+        clients: HashMap::new(),
+        next_client_id: 0,
+    })
+}
+
+/// Read requests from a client, one per line, and answer each of them. The socket is watched
+/// for writability as well, so that answers and events it could not take at once follow later.
+fn insert_ipc_client<BackendData>(
+    handle: &LoopHandle<'_, Data<BackendData>>,
+    state: &mut State<BackendData>,
+    stream: UnixStream,
+) -> Result<(), anyhow::Error> {
+    let ipc = match &mut state.ipc {
+        Some(ipc) => ipc,
+        None => return Ok(()),
+    };
+    stream.set_nonblocking(true)?;
+    let writer = stream.try_clone()?;
+    let id = ipc.next_client_id;
+    ipc.next_client_id += 1;
+
+    let mut buffer = Vec::new();
+    let source = handle
+        .insert_source(
+            // Edge-triggered, as the socket is writable most of the time.
+            Generic::new(stream, Interest::BOTH, calloop::Mode::Edge),
+            move |readiness, stream, data| {
+                if readiness.writable {
+                    data.state.flush_ipc_client(id);
+                }
+                if !readiness.readable {
+                    return Ok(PostAction::Continue);
+                }
+
+                let mut chunk = [0; 4096];
+                loop {
+                    match stream.read(&mut chunk) {
+                        // The client hung up.
+                        Ok(0) => {
+                            data.state.drop_ipc_client(id);
+                            return Ok(PostAction::Remove);
+                        }
+                        Ok(len) => buffer.extend_from_slice(&chunk[..len]),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                        Err(_) => {
+                            data.state.drop_ipc_client(id);
+                            return Ok(PostAction::Remove);
+                        }
+                    }
+                }
+
+                while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line = buffer.drain(..=end).collect::<Vec<_>>();
+                    let response = match serde_json::from_slice::<Request>(&line) {
+                        Ok(Request::Subscribe) => {
+                            if let Some(client) = data
+                                .state
+                                .ipc
+                                .as_mut()
+                                .and_then(|ipc| ipc.clients.get_mut(&id))
+                            {
+                                client.subscribed = true;
+                            }
+                            Response::Ok
+                        }
+                        Ok(request) => data.state.handle_ipc_request(request),
+                        Err(err) => Response::Error {
+                            message: format!("Invalid request: {}", err),
+                        },
+                    };
+                    data.state.send_ipc_message(id, &response);
+                }
+
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to insert the IPC client into the event loop"))?;
+
+    ipc.clients.insert(
+        id,
+        IpcClient {
+            stream: writer,
+            source,
+            subscribed: false,
+            outgoing: Vec::new(),
+        },
+    );
+    Ok(())
+}
+
+impl<BackendData> State<BackendData> {
+    fn handle_ipc_request(&mut self, request: Request) -> Response {
+        match request {
+            Request::Windows => Response::Windows {
+                windows: self
+                    .all_windows()
+                    .map(|window| self.window_info(window))
+                    .collect(),
+            },
+            Request::Outputs => Response::Outputs {
+                outputs: self
+                    .outputs
+                    .iter()
+                    .map(|output| self.output_info(output))
+                    .collect(),
+            },
+            Request::Workspaces => Response::Workspaces {
+                workspaces: self
+                    .workspaces
+                    .iter()
+                    .map(|workspace| WorkspaceInfo {
+                        name: workspace.name.clone(),
+                        output: workspace.output.as_ref().map(Output::name),
+                        visible: workspace.visible,
+                    })
+                    .collect(),
+            },
+            Request::InputDevices => Response::InputDevices {
+                devices: self.input_devices.clone(),
+            },
+            Request::CloseWindow { id } => match self.window_by_id(id) {
+                Some(window) => {
+                    window.send_close();
+                    Response::Ok
+                }
+                None => no_such_window(id),
+            },
+            Request::FocusWindow { id } => match self.window_by_id(id) {
+                Some(window) => {
+                    // A window on a hidden workspace brings its workspace along.
+                    if let Some(workspace) = self
+                        .workspace_of_window(&window)
+                        .filter(|workspace| !workspace.visible)
+                    {
+                        let target = WorkspaceTarget::Named(workspace.name.clone());
+                        self.switch_workspace(&target);
+                    }
+                    self.focus_window(window);
+                    Response::Ok
+                }
+                None => no_such_window(id),
+            },
+            Request::Quit => {
+                self.loop_signal.stop();
+                Response::Ok
+            }
+            // Subscriptions need the connection, so they are handled by the caller.
+            Request::Subscribe => Response::Error {
+                message: "Unexpected subscription".into(),
+            },
+        }
+    }
+
+    /// Send an event to every subscribed client.
+    pub fn emit_ipc_event(&mut self, event: Event) {
+        let subscribers = match &self.ipc {
+            Some(ipc) => ipc
+                .clients
+                .iter()
+                .filter(|(_, client)| client.subscribed)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>(),
+            None => return,
+        };
+
+        for id in subscribers {
+            self.send_ipc_message(id, &event);
+        }
+    }
+
+    /// Queue a message for a client, as a line of JSON, and write what its socket takes.
+    fn send_ipc_message<T: serde::Serialize>(&mut self, id: u64, message: &T) {
+        let client = match self.ipc.as_mut().and_then(|ipc| ipc.clients.get_mut(&id)) {
+            Some(client) => client,
+            None => return,
+        };
+        match serde_json::to_vec(message) {
+            Ok(line) => {
+                client.outgoing.extend_from_slice(&line);
+                client.outgoing.push(b'\n');
+            }
+            Err(err) => {
+                tracing::warn!("Failed to serialize an IPC message: {}", err);
+                return;
+            }
+        }
+        self.flush_ipc_client(id);
+    }
+
+    /// Write what is waiting for a client, as far as its socket takes it. The rest is written once
+    /// the socket is writable again. A client that lets too much pile up is dropped.
+    fn flush_ipc_client(&mut self, id: u64) {
+        let client = match self.ipc.as_mut().and_then(|ipc| ipc.clients.get_mut(&id)) {
+            Some(client) => client,
+            None => return,
+        };
+
+        while !client.outgoing.is_empty() {
+            match client.stream.write(&client.outgoing) {
+                Ok(0) => {
+                    self.drop_ipc_client(id);
+                    return;
+                }
+                Ok(len) => {
+                    client.outgoing.drain(..len);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                // Most likely, the client hung up.
+                Err(_) => {
+                    self.drop_ipc_client(id);
+                    return;
+                }
+            }
+        }
+
+        if client.outgoing.len() > MAX_OUTGOING_LEN {
+            tracing::warn!("Dropping an IPC client that does not read its messages");
+            self.drop_ipc_client(id);
+        }
+    }
+
+    /// Close the connection to a client.
+    fn drop_ipc_client(&mut self, id: u64) {
+        if let Some(client) = self.ipc.as_mut().and_then(|ipc| ipc.clients.remove(&id)) {
+            self.loop_handle.remove(client.source);
+        }
+    }
+
+    fn window_by_id(&self, id: u64) -> Option<WindowElement> {
+        self.all_windows()
+            .find(|window| window_id(window) == id)
+            .cloned()
+    }
+
+    pub fn window_info(&self, window: &WindowElement) -> WindowInfo {
+        let (title, app_id) = window.title_and_app_id();
+
+        WindowInfo {
+            id: window_id(window),
+            title,
+            app_id,
+            geometry: self.space.element_geometry(window).map(Geometry::from),
+            focused: self.focused_window().as_ref() == Some(window),
+            workspace: self
+                .workspace_of_window(window)
+                .map(|workspace| workspace.name.clone()),
+        }
+    }
+
+    pub fn output_info(&self, output: &Output) -> OutputInfo {
+        let properties = output.physical_properties();
+
+        OutputInfo {
+            name: output.name(),
+            make: properties.make,
+            model: properties.model,
+            geometry: self.space.output_geometry(output).map(Geometry::from),
+            mode: output
+                .current_mode()
+                .map(|mode| (mode.size.w, mode.size.h, mode.refresh)),
+            scale: output.current_scale().fractional_scale(),
+        }
+    }
+}
+
+impl From<Rectangle<i32, Logical>> for Geometry {
+    fn from(value: Rectangle<i32, Logical>) -> Self {
+        Self {
+            x: value.loc.x,
+            y: value.loc.y,
+            width: value.size.w,
+            height: value.size.h,
+        }
+    }
+}
+
+fn no_such_window(id: u64) -> Response {
+    Response::Error {
+        message: format!("No window with ID {}", id),
+    }
+}
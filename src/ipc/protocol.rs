@@ -0,0 +1,116 @@
+//! Messages exchanged over the IPC socket. Every message is a single line of JSON.
+//!
+//! This file is shared with `alioth-msg`, so it must not depend on anything else in the crate.
+
+use serde::{Deserialize, Serialize};
+
+/// Environment variable holding the path of the IPC socket.
+pub const SOCKET_ENV: &str = "ALIOTH_SOCKET";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Request {
+    /// List all windows.
+    Windows,
+    /// List all outputs.
+    Outputs,
+    /// List all workspaces.
+    Workspaces,
+    /// List all input devices.
+    InputDevices,
+    /// Ask a window to close.
+    CloseWindow { id: u64 },
+    /// Raise a window and give it keyboard focus.
+    FocusWindow { id: u64 },
+    /// Exit the compositor.
+    Quit,
+    /// Turn the connection into a stream of events. Only an `ok` response is sent, followed by
+    /// one event per line.
+    Subscribe,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Error { message: String },
+    Windows { windows: Vec<WindowInfo> },
+    Outputs { outputs: Vec<OutputInfo> },
+    Workspaces { workspaces: Vec<WorkspaceInfo> },
+    InputDevices { devices: Vec<InputDeviceInfo> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event {
+    WindowOpened {
+        window: WindowInfo,
+    },
+    WindowClosed {
+        id: u64,
+    },
+    /// `id` is `None` when no window has focus anymore.
+    WindowFocused {
+        id: Option<u64>,
+    },
+    OutputAdded {
+        output: OutputInfo,
+    },
+    OutputRemoved {
+        name: String,
+    },
+    /// A workspace has become the one shown on an output.
+    WorkspaceShown {
+        name: String,
+        output: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Geometry {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowInfo {
+    pub id: u64,
+    pub title: Option<String>,
+    pub app_id: Option<String>,
+    /// `None` if the window is not mapped, e.g. because its workspace is hidden.
+    pub geometry: Option<Geometry>,
+    pub focused: bool,
+    pub workspace: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputInfo {
+    pub name: String,
+    pub make: String,
+    pub model: String,
+    /// Position and size in the global, logical coordinate space. `None` if the output is not
+    /// mapped.
+    pub geometry: Option<Geometry>,
+    /// Size of the current mode, in physical pixels, and its refresh rate in mHz.
+    pub mode: Option<(i32, i32, i32)>,
+    pub scale: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceInfo {
+    pub name: String,
+    /// The output the workspace is, or will be, shown on.
+    pub output: Option<String>,
+    pub visible: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputDeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub keyboard: bool,
+    pub pointer: bool,
+    pub touch: bool,
+}
@@ -1,24 +1,65 @@
-use backend::run_backend_auto;
+use backend::{run_backend, Backend};
+use clap::Parser;
+use config::Config;
 use data::{ClientData, Data};
 use smithay::{reexports::calloop::EventLoop, wayland::socket::ListeningSocketSource};
+use tracing_subscriber::filter::LevelFilter;
 
-use std::sync::Arc;
+use std::{path::PathBuf, process::ExitCode, sync::Arc};
 
+mod action;
 mod backend;
+mod config;
 mod cursor;
 mod data;
 mod grabs;
 mod handlers;
 mod input;
+mod ipc;
+mod night_light;
+mod output;
+mod protocols;
 mod state;
+mod window;
 mod workspace;
 
+/// The Alioth Desktop Environment.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// The backend to run on. Guessed from the environment if not given.
+    #[arg(long, value_enum, env = "ALIOTH_BACKEND")]
+    backend: Option<Backend>,
+
+    /// Name of the Wayland socket to listen on, e.g. `wayland-1`. Picked automatically if not
+    /// given.
+    #[arg(long)]
+    socket: Option<String>,
+
+    /// The most verbose level of logs to print (off, error, warn, info, debug or trace).
+    #[arg(long, default_value_t = LevelFilter::INFO)]
+    log_level: LevelFilter,
+
+    /// Path to the config file. Defaults to `$XDG_CONFIG_HOME/alioth/config.toml`.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Check the config file for errors and exit.
+    #[arg(long)]
+    check_config: bool,
+}
+
 /// Create a Unix socket for the Wayland server.
+/// If `name` is `None`, a free name will be chosen automatically.
 fn init_wayland_socket<BackendData>(
     event_loop: &mut EventLoop<Data<BackendData>>,
+    name: Option<&str>,
 ) -> Result<String, anyhow::Error> {
     // Create the socket.
-    let socket = ListeningSocketSource::new_auto()?;
+    let socket = match name {
+        Some(name) => ListeningSocketSource::with_name(name)?,
+        None => ListeningSocketSource::new_auto()?,
+    };
     // Get the socket name to be returned.
     let name = socket.socket_name().to_string_lossy().to_string();
 
@@ -42,12 +83,48 @@ fn init_wayland_socket<BackendData>(
     Ok(name)
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
     // Initialize logger.
-    tracing_subscriber::fmt().init();
+    tracing_subscriber::fmt()
+        .with_max_level(cli.log_level)
+        .init();
+
+    let config_path = cli.config.or_else(Config::default_path);
+
+    if cli.check_config {
+        return check_config(config_path);
+    }
+
+    // Run the requested backend, or decide the suitable one automatically.
+    match run_backend(cli.backend, cli.socket.as_deref(), config_path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            tracing::error!("{}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
 
-    // Automatically decide the suitable backend and run it.
-    run_backend_auto()?;
+/// Report whether the config file is valid, for `--check-config`.
+fn check_config(path: Option<PathBuf>) -> ExitCode {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            tracing::error!("Unable to determine the location of the config file");
+            return ExitCode::FAILURE;
+        }
+    };
 
-    Ok(())
+    match Config::load(&path) {
+        Ok(_) => {
+            tracing::info!("{} is valid", path.display());
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            tracing::error!("{}: {}", path.display(), err);
+            ExitCode::FAILURE
+        }
+    }
 }
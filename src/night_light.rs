@@ -0,0 +1,160 @@
+use smithay::reexports::nix::libc;
+
+use crate::config::NightLightConfig;
+
+/// The colour temperature of daylight, in Kelvin, at which the colours are left alone.
+pub const DAY_TEMPERATURE: u32 = 6500;
+
+/// The colour temperature at a time of day, in minutes since midnight. It goes down to the night
+/// temperature in the `transition` minutes after sunset, and back up in the ones before sunrise.
+pub fn temperature(config: &NightLightConfig, minute: u32) -> u32 {
+    let (sunset, sunrise) = (config.sunset.0, config.sunrise.0);
+    let since_sunset = (minute + 24 * 60 - sunset) % (24 * 60);
+    let until_sunrise = (sunrise + 24 * 60 - minute) % (24 * 60);
+    let night_length = (sunrise + 24 * 60 - sunset) % (24 * 60);
+    if since_sunset >= night_length {
+        return DAY_TEMPERATURE;
+    }
+
+    let warmth = if config.transition == 0 {
+        1.0
+    } else {
+        (since_sunset.min(until_sunrise) as f64 / config.transition as f64).min(1.0)
+    };
+    let day = DAY_TEMPERATURE as f64;
+    (day + (config.temperature as f64 - day) * warmth).round() as u32
+}
+
+/// The local time, in minutes since midnight. `None` if it cannot be told.
+pub fn local_time_of_day() -> Option<u32> {
+    // The time zone is read again each time, so that changing it is followed.
+    unsafe { libc::tzset() };
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+    let tm = unsafe { libc::localtime_r(&now, tm.as_mut_ptr()).as_ref()? };
+    Some(tm.tm_hour as u32 * 60 + tm.tm_min as u32)
+}
+
+/// A gamma ramp of `size` entries per channel that shows a colour temperature, red then green
+/// then blue as `zwlr_gamma_control_v1` has it.
+pub fn gamma_ramp(temperature: u32, size: usize) -> Vec<u16> {
+    let white = whitepoint(temperature);
+    let day = whitepoint(DAY_TEMPERATURE);
+
+    let mut ramp = Vec::with_capacity(size * 3);
+    for channel in 0..3 {
+        let factor = (white[channel] / day[channel]).min(1.0);
+        ramp.extend((0..size).map(|i| {
+            let value = i as f64 / size.saturating_sub(1).max(1) as f64;
+            (value * factor * u16::MAX as f64).round() as u16
+        }));
+    }
+    ramp
+}
+
+/// The colour of a black body at a temperature, in RGB from 0 to 1. Tanner Helland's fit of the
+/// CIE data, which is close enough from 1000 K to 40000 K.
+fn whitepoint(temperature: u32) -> [f64; 3] {
+    let t = temperature.clamp(1000, 40000) as f64 / 100.0;
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+    let green = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+    [red, green, blue].map(|channel| channel.clamp(0.0, 255.0) / 255.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TimeOfDay;
+
+    fn config(sunset: u32, sunrise: u32, transition: u32) -> NightLightConfig {
+        NightLightConfig {
+            temperature: 4000,
+            sunset: TimeOfDay(sunset),
+            sunrise: TimeOfDay(sunrise),
+            transition,
+        }
+    }
+
+    #[test]
+    fn sunset_before_midnight() {
+        let config = config(20 * 60, 7 * 60, 30);
+        assert_eq!(temperature(&config, 12 * 60), DAY_TEMPERATURE);
+        assert_eq!(temperature(&config, 20 * 60), DAY_TEMPERATURE);
+        assert_eq!(temperature(&config, 20 * 60 + 15), 5250);
+        assert_eq!(temperature(&config, 20 * 60 + 30), 4000);
+        assert_eq!(temperature(&config, 0), 4000);
+        assert_eq!(temperature(&config, 6 * 60 + 45), 5250);
+        assert_eq!(temperature(&config, 7 * 60), DAY_TEMPERATURE);
+    }
+
+    #[test]
+    fn sunset_after_midnight() {
+        let config = config(60, 6 * 60, 30);
+        assert_eq!(temperature(&config, 23 * 60), DAY_TEMPERATURE);
+        assert_eq!(temperature(&config, 30), DAY_TEMPERATURE);
+        assert_eq!(temperature(&config, 3 * 60), 4000);
+        assert_eq!(temperature(&config, 6 * 60), DAY_TEMPERATURE);
+    }
+
+    #[test]
+    fn sunset_at_sunrise() {
+        let config = config(20 * 60, 20 * 60, 30);
+        for minute in [0, 20 * 60, 20 * 60 + 30, 23 * 60 + 59] {
+            assert_eq!(temperature(&config, minute), DAY_TEMPERATURE);
+        }
+    }
+
+    #[test]
+    fn no_transition() {
+        let config = config(20 * 60, 7 * 60, 0);
+        assert_eq!(temperature(&config, 20 * 60), 4000);
+        assert_eq!(temperature(&config, 7 * 60 - 1), 4000);
+        assert_eq!(temperature(&config, 7 * 60), DAY_TEMPERATURE);
+    }
+
+    #[test]
+    fn transition_longer_than_half_the_night() {
+        let config = config(20 * 60, 21 * 60, 60);
+        assert_eq!(temperature(&config, 20 * 60 + 30), 5250);
+        assert!((20 * 60..21 * 60).all(|minute| temperature(&config, minute) > 4000));
+    }
+
+    #[test]
+    fn gamma_ramp_of_daylight_is_linear() {
+        assert_eq!(
+            gamma_ramp(DAY_TEMPERATURE, 3),
+            [0, 32768, 65535, 0, 32768, 65535, 0, 32768, 65535]
+        );
+    }
+
+    #[test]
+    fn gamma_ramp_of_warm_light() {
+        let ramp = gamma_ramp(4000, 256);
+        assert_eq!(ramp.len(), 3 * 256);
+        let (red, green, blue) = (ramp[255], ramp[511], ramp[767]);
+        assert_eq!(red, u16::MAX);
+        assert!(green < red && blue < green);
+        assert!(ramp[..256].windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn tiny_gamma_ramps() {
+        assert!(gamma_ramp(4000, 0).is_empty());
+        assert_eq!(gamma_ramp(DAY_TEMPERATURE, 1), [0, 0, 0]);
+    }
+}
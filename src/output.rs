@@ -0,0 +1,293 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use smithay::{
+    backend::renderer::element::RenderElementStates,
+    desktop::{
+        layer_map_for_output,
+        utils::{
+            default_primary_scanout_output_compare,
+            surface_presentation_feedback_flags_from_states, surface_primary_scanout_output,
+            update_primary_scanout_output, OutputPresentationFeedback,
+        },
+        Space,
+    },
+    output::{Output, Scale},
+    reexports::wayland_server::backend::GlobalId,
+    utils::{Logical, Point, Transform},
+};
+
+use crate::{config::OutputConfig, state::State, window::WindowElement};
+
+/// The serial number from the EDID of an output, stored in its user data by the backends that
+/// know it.
+pub struct OutputSerial(pub String);
+
+/// How the backend set up an output, so that it can be restored when its settings go away.
+struct OutputDefaults {
+    transform: Transform,
+    scale: Scale,
+    /// Whether the mode has been set from the config. Otherwise it is left to the backend, which
+    /// may follow the size of a window.
+    configured_mode: Mutex<bool>,
+}
+
+/// The `wl_output` global of an output, which only exists while the output is enabled.
+#[derive(Default)]
+struct OutputGlobal(Mutex<Option<GlobalId>>);
+
+/// Whether what an output shows has changed since it was last drawn, stored in its user data.
+/// Backends that only draw when something changes look at it, the others draw on a timer.
+struct RedrawQueued(AtomicBool);
+
+/// Have an output drawn again, once the backend gets to it.
+pub fn queue_redraw(output: &Output) {
+    output
+        .user_data()
+        .insert_if_missing_threadsafe(|| RedrawQueued(AtomicBool::new(false)));
+    output
+        .user_data()
+        .get::<RedrawQueued>()
+        .unwrap()
+        .0
+        .store(true, Ordering::Relaxed);
+}
+
+/// Whether an output has to be drawn again. The backend is expected to draw it then.
+pub fn take_redraw(output: &Output) -> bool {
+    output
+        .user_data()
+        .get::<RedrawQueued>()
+        .map_or(false, |queued| queued.0.swap(false, Ordering::Relaxed))
+}
+
+/// The make, model and serial number of an output, as used to match it in the config.
+pub fn output_description(output: &Output) -> String {
+    let properties = output.physical_properties();
+    match output.user_data().get::<OutputSerial>() {
+        Some(serial) => format!("{} {} {}", properties.make, properties.model, serial.0),
+        None => format!("{} {}", properties.make, properties.model),
+    }
+}
+
+/// The presentation feedback of the surfaces drawn in a frame of an output, to be sent once the
+/// frame is on screen. A surface shown on several outputs is reported by the one it is most
+/// visible on, which is also the one that sends its frame callbacks.
+pub fn take_presentation_feedback(
+    output: &Output,
+    space: &Space<WindowElement>,
+    states: &RenderElementStates,
+) -> OutputPresentationFeedback {
+    let mut feedback = OutputPresentationFeedback::new(output);
+
+    for window in space.elements() {
+        window.with_surfaces(|surface, data| {
+            update_primary_scanout_output(
+                surface,
+                output,
+                data,
+                states,
+                default_primary_scanout_output_compare,
+            );
+        });
+        window.take_presentation_feedback(
+            &mut feedback,
+            surface_primary_scanout_output,
+            |surface, _| surface_presentation_feedback_flags_from_states(surface, states),
+        );
+    }
+
+    let layer_map = layer_map_for_output(output);
+    for layer in layer_map.layers() {
+        layer.with_surfaces(|surface, data| {
+            update_primary_scanout_output(
+                surface,
+                output,
+                data,
+                states,
+                default_primary_scanout_output_compare,
+            );
+        });
+        layer.take_presentation_feedback(
+            &mut feedback,
+            surface_primary_scanout_output,
+            |surface, _| surface_presentation_feedback_flags_from_states(surface, states),
+        );
+    }
+
+    feedback
+}
+
+/// How long a frame stays on screen, in nanoseconds as `wp_presentation` has it. Zero when the
+/// output has no refresh rate.
+pub fn refresh_interval(output: &Output) -> u32 {
+    output
+        .current_mode()
+        .filter(|mode| mode.refresh > 0)
+        .map(|mode| (1_000_000_000_000 / mode.refresh as u64) as u32)
+        .unwrap_or(0)
+}
+
+impl<BackendData> State<BackendData> {
+    /// Add an output that has been plugged in. It is set up according to the config.
+    pub fn add_output(&mut self, output: Output) {
+        output
+            .user_data()
+            .insert_if_missing_threadsafe(|| OutputDefaults {
+                transform: output.current_transform(),
+                scale: output.current_scale(),
+                configured_mode: Mutex::new(false),
+            });
+        self.outputs.push(output);
+        self.configure_outputs();
+    }
+
+    /// Remove an output that has been unplugged.
+    pub fn remove_output(&mut self, output: &Output) {
+        self.disable_output(output);
+        self.outputs.retain(|o| o != output);
+        // Outputs on the right may move to the left.
+        self.configure_outputs();
+    }
+
+    /// Have every output drawn again, for changes that are not tied to one of them.
+    pub fn queue_redraw_all(&self) {
+        self.space.outputs().for_each(queue_redraw);
+    }
+
+    /// Have the outputs at a location drawn again, e.g. where the cursor was or has gone.
+    pub fn queue_redraw_at(&self, location: Point<f64, Logical>) {
+        self.space.output_under(location).for_each(queue_redraw);
+    }
+
+    /// The settings of an output, from output management clients or else from the config.
+    pub fn output_config(&self, output: &Output) -> OutputConfig {
+        self.output_overrides
+            .get(&output.name())
+            .cloned()
+            .unwrap_or_else(|| self.config.outputs.find(output))
+    }
+
+    /// Apply the settings to every output: turn them on or off, set their mode, scale and transform,
+    /// and lay them out. The DRM backend follows the current mode of the outputs on its own.
+    pub fn configure_outputs(&mut self) {
+        let outputs = self.outputs.clone();
+        let configs = outputs
+            .iter()
+            .map(|output| self.output_config(output))
+            .collect::<Vec<_>>();
+
+        for (output, config) in outputs.iter().zip(&configs) {
+            if !config.enable {
+                self.disable_output(output);
+                continue;
+            }
+
+            let defaults = output.user_data().get::<OutputDefaults>();
+            let mode = config.mode.and_then(|mode| {
+                mode.find(output).or_else(|| {
+                    tracing::warn!("Output {} has no mode {:?}", output.name(), mode);
+                    None
+                })
+            });
+            // Back to the preferred mode when the config stops setting one.
+            let mode = match defaults {
+                Some(defaults) => {
+                    let mut configured = defaults.configured_mode.lock().unwrap();
+                    let was_configured = std::mem::replace(&mut *configured, mode.is_some());
+                    mode.or_else(|| output.preferred_mode().filter(|_| was_configured))
+                }
+                None => mode,
+            };
+            let transform = config
+                .transform
+                .map(Transform::from)
+                .or_else(|| defaults.map(|defaults| defaults.transform));
+            let scale = config
+                .scale
+                .filter(|scale| *scale > 0.0)
+                .map(|scale| {
+                    if scale.fract() == 0.0 {
+                        Scale::Integer(scale as i32)
+                    } else {
+                        Scale::Fractional(scale)
+                    }
+                })
+                .or_else(|| defaults.map(|defaults| defaults.scale));
+            output.change_current_state(mode, transform, scale, None);
+        }
+
+        // Outputs with a position first, so that the others can go to their right.
+        let enabled = outputs
+            .iter()
+            .zip(&configs)
+            .filter(|(_, config)| config.enable)
+            .collect::<Vec<_>>();
+        let mut placed = Vec::new();
+        for (output, config) in &enabled {
+            if let Some([x, y]) = config.position {
+                self.enable_output(output, (x, y).into());
+                placed.push((*output).clone());
+            }
+        }
+        for (output, config) in &enabled {
+            if config.position.is_none() {
+                let x = placed
+                    .iter()
+                    .filter_map(|output| self.space.output_geometry(output))
+                    .map(|geo| geo.loc.x + geo.size.w)
+                    .max()
+                    .unwrap_or(0);
+                self.enable_output(output, (x, 0).into());
+                placed.push((*output).clone());
+            }
+        }
+    }
+
+    /// Map an output at a location, creating its global and giving it a workspace if it was off.
+    /// The windows of an output that moves go along with it, and its layer surfaces are laid out
+    /// again in case its size has changed.
+    fn enable_output(&mut self, output: &Output, location: Point<i32, Logical>) {
+        let previous = self.space.output_geometry(output).map(|geo| geo.loc);
+        let enabled = previous.is_some();
+        self.space.map_output(output, location);
+        layer_map_for_output(output).arrange();
+        queue_redraw(output);
+        if let Some(previous) = previous.filter(|previous| *previous != location) {
+            self.move_output_windows(output, location - previous);
+        }
+
+        if !enabled {
+            output
+                .user_data()
+                .insert_if_missing_threadsafe(OutputGlobal::default);
+            let mut global = output
+                .user_data()
+                .get::<OutputGlobal>()
+                .unwrap()
+                .0
+                .lock()
+                .unwrap();
+            if global.is_none() {
+                *global = Some(output.create_global::<Self>(&self.display_handle));
+            }
+            drop(global);
+            self.add_output_workspace(output);
+        }
+    }
+
+    /// Unmap an output and remove its global. Its workspaces are moved to another output.
+    fn disable_output(&mut self, output: &Output) {
+        if self.space.outputs().any(|o| o == output) {
+            self.unmap_output(output);
+        }
+
+        if let Some(global) = output.user_data().get::<OutputGlobal>() {
+            if let Some(global) = global.0.lock().unwrap().take() {
+                self.display_handle.remove_global::<Self>(global);
+            }
+        }
+    }
+}
@@ -0,0 +1,450 @@
+//! ext-workspace-v1: lets panels and docks list the workspaces of every output and switch between
+//! them.
+//!
+//! The compositor owns the workspace model and hands a snapshot of it to
+//! [`ExtWorkspaceState::refresh`], which sends every client the differences to what it knows.
+//! Requests from clients are queued until they commit them, then handed to
+//! [`ExtWorkspaceHandler::workspace_requests`].
+
+use smithay::{
+    output::Output,
+    reexports::wayland_server::{
+        backend::{ClientId, ObjectId},
+        protocol::wl_output::WlOutput,
+        Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+    },
+    utils::IsAlive,
+};
+
+pub use generated::{
+    ext_workspace_group_handle_v1::{self, ExtWorkspaceGroupHandleV1},
+    ext_workspace_handle_v1::{self, ExtWorkspaceHandleV1},
+    ext_workspace_manager_v1::{self, ExtWorkspaceManagerV1},
+};
+
+#[allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+#[allow(non_upper_case_globals, non_snake_case, unused_imports)]
+#[allow(missing_docs, clippy::all)]
+mod generated {
+    use smithay::reexports::wayland_server;
+    use smithay::reexports::wayland_server::protocol::*;
+
+    pub mod __interfaces {
+        use smithay::reexports::wayland_server::backend as wayland_backend;
+        use smithay::reexports::wayland_server::protocol::__interfaces::*;
+        wayland_scanner::generate_interfaces!("protocols/ext-workspace-v1.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_server_code!("protocols/ext-workspace-v1.xml");
+}
+
+const VERSION: u32 = 1;
+
+/// A workspace as the compositor sees it.
+pub struct WorkspaceEntry {
+    /// Also used as the id of the workspace, so it has to be unique.
+    pub name: String,
+    /// Workspaces without an output are not part of any group.
+    pub output: Option<Output>,
+    pub active: bool,
+    /// One of its windows has asked for attention.
+    pub urgent: bool,
+    /// The workspace is not shown in any group, and panels should leave it out.
+    pub hidden: bool,
+}
+
+/// Something a client asked for. Only names are passed around since they are what identifies a
+/// workspace, and the workspace may be gone by the time the request is handled.
+#[derive(Debug, Clone)]
+pub enum WorkspaceRequest {
+    Activate(String),
+    Remove(String),
+    Create { output: Output, name: String },
+}
+
+pub trait ExtWorkspaceHandler {
+    fn ext_workspace_state(&mut self) -> &mut ExtWorkspaceState;
+
+    /// Apply the requests a client has committed, in order.
+    fn workspace_requests(&mut self, requests: Vec<WorkspaceRequest>);
+}
+
+pub struct ExtWorkspaceState {
+    display: DisplayHandle,
+    instances: Vec<ManagerInstance>,
+}
+
+/// A bound manager, with everything its client has been told about.
+struct ManagerInstance {
+    manager: ExtWorkspaceManagerV1,
+    groups: Vec<Group>,
+    workspaces: Vec<Workspace>,
+    pending: Vec<WorkspaceRequest>,
+}
+
+struct Group {
+    handle: ExtWorkspaceGroupHandleV1,
+    output: Output,
+    /// The `wl_output`s of the client that the group has entered.
+    wl_outputs: Vec<WlOutput>,
+}
+
+struct Workspace {
+    handle: ExtWorkspaceHandleV1,
+    name: String,
+    state: ext_workspace_handle_v1::State,
+    output: Option<Output>,
+}
+
+impl ExtWorkspaceState {
+    pub fn new<D>(display: &DisplayHandle) -> Self
+    where
+        D: GlobalDispatch<ExtWorkspaceManagerV1, ()> + 'static,
+    {
+        display.create_global::<D, ExtWorkspaceManagerV1, _>(VERSION, ());
+
+        Self {
+            display: display.clone(),
+            instances: Vec::new(),
+        }
+    }
+
+    /// Tell every client about the changes to the outputs and workspaces since the last refresh.
+    /// Cheap if nothing has changed, so it can be called on every event loop iteration.
+    pub fn refresh<D>(&mut self, outputs: &[Output], workspaces: &[WorkspaceEntry])
+    where
+        D: Dispatch<ExtWorkspaceGroupHandleV1, Output>
+            + Dispatch<ExtWorkspaceHandleV1, String>
+            + 'static,
+    {
+        for instance in &mut self.instances {
+            let client = match self.display.get_client(instance.manager.id()) {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            if instance.refresh::<D>(&self.display, &client, outputs, workspaces) {
+                instance.manager.done();
+            }
+        }
+    }
+}
+
+impl ManagerInstance {
+    /// Returns whether any event has been sent.
+    fn refresh<D>(
+        &mut self,
+        display: &DisplayHandle,
+        client: &Client,
+        outputs: &[Output],
+        entries: &[WorkspaceEntry],
+    ) -> bool
+    where
+        D: Dispatch<ExtWorkspaceGroupHandleV1, Output>
+            + Dispatch<ExtWorkspaceHandleV1, String>
+            + 'static,
+    {
+        let mut changed = false;
+
+        // Workspaces that are gone.
+        let groups = &self.groups;
+        self.workspaces.retain(|workspace| {
+            if entries.iter().any(|entry| entry.name == workspace.name) {
+                return true;
+            }
+            if let Some(group) = find_group(groups, workspace.output.as_ref()) {
+                group.handle.workspace_leave(&workspace.handle);
+            }
+            workspace.handle.removed();
+            changed = true;
+            false
+        });
+
+        // Groups whose output is gone, their workspaces leave them first.
+        let workspaces = &mut self.workspaces;
+        self.groups.retain(|group| {
+            if outputs.contains(&group.output) {
+                return true;
+            }
+            for workspace in workspaces
+                .iter_mut()
+                .filter(|workspace| workspace.output.as_ref() == Some(&group.output))
+            {
+                group.handle.workspace_leave(&workspace.handle);
+                workspace.output = None;
+            }
+            group.handle.removed();
+            changed = true;
+            false
+        });
+
+        for output in outputs {
+            if !self.groups.iter().any(|group| group.output == *output) {
+                let handle = match client.create_resource::<ExtWorkspaceGroupHandleV1, _, D>(
+                    display,
+                    self.manager.version(),
+                    output.clone(),
+                ) {
+                    Ok(handle) => handle,
+                    Err(_) => continue,
+                };
+                self.manager.workspace_group(&handle);
+                handle.capabilities(
+                    ext_workspace_group_handle_v1::GroupCapabilities::CreateWorkspace,
+                );
+                self.groups.push(Group {
+                    handle,
+                    output: output.clone(),
+                    wl_outputs: Vec::new(),
+                });
+                changed = true;
+            }
+        }
+
+        // The client may bind an output after the group has been announced.
+        for group in &mut self.groups {
+            for wl_output in group.output.client_outputs(client) {
+                if !group.wl_outputs.contains(&wl_output) {
+                    if group.handle.alive() {
+                        group.handle.output_enter(&wl_output);
+                    }
+                    group.wl_outputs.push(wl_output);
+                    changed = true;
+                }
+            }
+            group.wl_outputs.retain(IsAlive::alive);
+        }
+
+        for entry in entries {
+            let index = match self
+                .workspaces
+                .iter()
+                .position(|workspace| workspace.name == entry.name)
+            {
+                Some(index) => index,
+                None => {
+                    let handle = match client.create_resource::<ExtWorkspaceHandleV1, _, D>(
+                        display,
+                        self.manager.version(),
+                        entry.name.clone(),
+                    ) {
+                        Ok(handle) => handle,
+                        Err(_) => continue,
+                    };
+                    self.manager.workspace(&handle);
+                    handle.id(entry.name.clone());
+                    handle.name(entry.name.clone());
+                    handle.capabilities(
+                        ext_workspace_handle_v1::WorkspaceCapabilities::Activate
+                            | ext_workspace_handle_v1::WorkspaceCapabilities::Remove,
+                    );
+                    handle.state(workspace_state(entry));
+                    self.workspaces.push(Workspace {
+                        handle,
+                        name: entry.name.clone(),
+                        state: workspace_state(entry),
+                        output: None,
+                    });
+                    changed = true;
+                    self.workspaces.len() - 1
+                }
+            };
+            let workspace = &mut self.workspaces[index];
+
+            let state = workspace_state(entry);
+            if workspace.state != state {
+                workspace.handle.state(state);
+                workspace.state = state;
+                changed = true;
+            }
+
+            if workspace.output != entry.output {
+                if let Some(group) = find_group(&self.groups, workspace.output.as_ref()) {
+                    group.handle.workspace_leave(&workspace.handle);
+                }
+                if let Some(group) = find_group(&self.groups, entry.output.as_ref()) {
+                    group.handle.workspace_enter(&workspace.handle);
+                }
+                workspace.output = entry.output.clone();
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+fn find_group<'a>(groups: &'a [Group], output: Option<&Output>) -> Option<&'a Group> {
+    let output = output?;
+    groups
+        .iter()
+        .find(|group| group.output == *output && group.handle.alive())
+}
+
+fn workspace_state(entry: &WorkspaceEntry) -> ext_workspace_handle_v1::State {
+    let mut state = ext_workspace_handle_v1::State::empty();
+    if entry.active {
+        state |= ext_workspace_handle_v1::State::Active;
+    }
+    if entry.urgent {
+        state |= ext_workspace_handle_v1::State::Urgent;
+    }
+    if entry.hidden {
+        state |= ext_workspace_handle_v1::State::Hidden;
+    }
+    state
+}
+
+impl<D> GlobalDispatch<ExtWorkspaceManagerV1, (), D> for ExtWorkspaceState
+where
+    D: GlobalDispatch<ExtWorkspaceManagerV1, ()>
+        + Dispatch<ExtWorkspaceManagerV1, ()>
+        + ExtWorkspaceHandler
+        + 'static,
+{
+    fn bind(
+        state: &mut D,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtWorkspaceManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        let manager = data_init.init(resource, ());
+        // The workspaces are announced on the next refresh.
+        state.ext_workspace_state().instances.push(ManagerInstance {
+            manager,
+            groups: Vec::new(),
+            workspaces: Vec::new(),
+            pending: Vec::new(),
+        });
+    }
+}
+
+impl<D> Dispatch<ExtWorkspaceManagerV1, (), D> for ExtWorkspaceState
+where
+    D: Dispatch<ExtWorkspaceManagerV1, ()> + ExtWorkspaceHandler + 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        resource: &ExtWorkspaceManagerV1,
+        request: ext_workspace_manager_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        let instances = &mut state.ext_workspace_state().instances;
+        match request {
+            ext_workspace_manager_v1::Request::Commit => {
+                let requests = instances
+                    .iter_mut()
+                    .find(|instance| instance.manager == *resource)
+                    .map(|instance| std::mem::take(&mut instance.pending))
+                    .unwrap_or_default();
+                if !requests.is_empty() {
+                    state.workspace_requests(requests);
+                }
+            }
+            ext_workspace_manager_v1::Request::Stop => {
+                // The handles stay usable until the client destroys them, they just don't get any
+                // more events.
+                resource.finished();
+                instances.retain(|instance| instance.manager != *resource);
+            }
+        }
+    }
+
+    fn destroyed(state: &mut D, _client: ClientId, resource: ObjectId, _data: &()) {
+        state
+            .ext_workspace_state()
+            .instances
+            .retain(|instance| instance.manager.id() != resource);
+    }
+}
+
+impl<D> Dispatch<ExtWorkspaceGroupHandleV1, Output, D> for ExtWorkspaceState
+where
+    D: Dispatch<ExtWorkspaceGroupHandleV1, Output> + ExtWorkspaceHandler + 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        resource: &ExtWorkspaceGroupHandleV1,
+        request: ext_workspace_group_handle_v1::Request,
+        output: &Output,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            ext_workspace_group_handle_v1::Request::CreateWorkspace { workspace } => {
+                if let Some(instance) = state
+                    .ext_workspace_state()
+                    .instances
+                    .iter_mut()
+                    .find(|instance| instance.groups.iter().any(|g| g.handle == *resource))
+                {
+                    instance.pending.push(WorkspaceRequest::Create {
+                        output: output.clone(),
+                        name: workspace,
+                    });
+                }
+            }
+            // The group is dropped in `destroyed`.
+            ext_workspace_group_handle_v1::Request::Destroy => (),
+        }
+    }
+}
+
+impl<D> Dispatch<ExtWorkspaceHandleV1, String, D> for ExtWorkspaceState
+where
+    D: Dispatch<ExtWorkspaceHandleV1, String> + ExtWorkspaceHandler + 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        resource: &ExtWorkspaceHandleV1,
+        request: ext_workspace_handle_v1::Request,
+        name: &String,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        let request = match request {
+            ext_workspace_handle_v1::Request::Activate => WorkspaceRequest::Activate(name.clone()),
+            ext_workspace_handle_v1::Request::Remove => WorkspaceRequest::Remove(name.clone()),
+            // Every output always shows a workspace, and workspaces follow the output they are
+            // shown on, so these are not advertised and ignored.
+            ext_workspace_handle_v1::Request::Deactivate
+            | ext_workspace_handle_v1::Request::Assign { .. } => return,
+            // The workspace is dropped in `destroyed`.
+            ext_workspace_handle_v1::Request::Destroy => return,
+        };
+
+        if let Some(instance) = state
+            .ext_workspace_state()
+            .instances
+            .iter_mut()
+            .find(|instance| instance.workspaces.iter().any(|w| w.handle == *resource))
+        {
+            instance.pending.push(request);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! delegate_ext_workspace {
+    ($(@<$( $lt:tt $( : $clt:tt $(+ $dlt:tt )* )? ),+>)? $ty: ty) => {
+        smithay::reexports::wayland_server::delegate_global_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            $crate::protocols::ext_workspace::ExtWorkspaceManagerV1: ()
+        ] => $crate::protocols::ext_workspace::ExtWorkspaceState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            $crate::protocols::ext_workspace::ExtWorkspaceManagerV1: ()
+        ] => $crate::protocols::ext_workspace::ExtWorkspaceState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            $crate::protocols::ext_workspace::ExtWorkspaceGroupHandleV1: smithay::output::Output
+        ] => $crate::protocols::ext_workspace::ExtWorkspaceState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            $crate::protocols::ext_workspace::ExtWorkspaceHandleV1: String
+        ] => $crate::protocols::ext_workspace::ExtWorkspaceState);
+    };
+}
@@ -0,0 +1,240 @@
+//! wlr-gamma-control-unstable-v1: lets tools like gammastep and wlsunset set the gamma ramp of
+//! outputs.
+//!
+//! Only one client at a time controls an output, the others are told it failed. The ramps are read
+//! here and handed to [`GammaControlHandler::set_gamma`], which is asked to put the original ramp
+//! back once the controlling client is done.
+
+use std::{fs::File, io::ErrorKind, os::unix::fs::FileExt};
+
+use smithay::{
+    output::Output,
+    reexports::{
+        wayland_protocols_wlr::gamma_control::v1::server::{
+            zwlr_gamma_control_manager_v1::{self, ZwlrGammaControlManagerV1},
+            zwlr_gamma_control_v1::{self, ZwlrGammaControlV1},
+        },
+        wayland_server::{
+            backend::{ClientId, ObjectId},
+            Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+        },
+    },
+};
+
+const VERSION: u32 = 1;
+
+pub trait GammaControlHandler {
+    fn gamma_control_state(&mut self) -> &mut GammaControlManagerState;
+
+    /// The number of entries per channel of the gamma ramp of an output, `None` if its gamma
+    /// cannot be set.
+    fn gamma_size(&mut self, output: &Output) -> Option<usize>;
+
+    /// Set the gamma ramp of an output, red then green then blue, or put back the original one
+    /// if `ramp` is `None`. Returns whether it worked.
+    fn set_gamma(&mut self, output: &Output, ramp: Option<Vec<u16>>) -> bool;
+}
+
+pub struct GammaControlManagerState {
+    controls: Vec<GammaControl>,
+}
+
+/// A control that is in effect. Failed ones are forgotten, their client just has to destroy them.
+struct GammaControl {
+    control: ZwlrGammaControlV1,
+    output: Output,
+    size: usize,
+}
+
+impl GammaControlManagerState {
+    pub fn new<D>(display: &DisplayHandle) -> Self
+    where
+        D: GlobalDispatch<ZwlrGammaControlManagerV1, ()> + 'static,
+    {
+        display.create_global::<D, ZwlrGammaControlManagerV1, _>(VERSION, ());
+
+        Self {
+            controls: Vec::new(),
+        }
+    }
+
+    /// Tell the client controlling an output that is gone that it is over.
+    pub fn output_removed(&mut self, output: &Output) {
+        self.controls.retain(|control| {
+            if control.output != *output {
+                return true;
+            }
+            control.control.failed();
+            false
+        });
+    }
+}
+
+impl<D> GlobalDispatch<ZwlrGammaControlManagerV1, (), D> for GammaControlManagerState
+where
+    D: GlobalDispatch<ZwlrGammaControlManagerV1, ()>
+        + Dispatch<ZwlrGammaControlManagerV1, ()>
+        + GammaControlHandler
+        + 'static,
+{
+    fn bind(
+        _state: &mut D,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwlrGammaControlManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<D> Dispatch<ZwlrGammaControlManagerV1, (), D> for GammaControlManagerState
+where
+    D: Dispatch<ZwlrGammaControlManagerV1, ()>
+        + Dispatch<ZwlrGammaControlV1, ()>
+        + GammaControlHandler
+        + 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        _resource: &ZwlrGammaControlManagerV1,
+        request: zwlr_gamma_control_manager_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_gamma_control_manager_v1::Request::GetGammaControl { id, output } => {
+                let control = data_init.init(id, ());
+                let output = match Output::from_resource(&output) {
+                    Some(output) => output,
+                    None => {
+                        control.failed();
+                        return;
+                    }
+                };
+                let taken = state
+                    .gamma_control_state()
+                    .controls
+                    .iter()
+                    .any(|control| control.output == output);
+                let size = match state.gamma_size(&output) {
+                    Some(size) if !taken => size,
+                    _ => {
+                        control.failed();
+                        return;
+                    }
+                };
+
+                control.gamma_size(size as u32);
+                state.gamma_control_state().controls.push(GammaControl {
+                    control,
+                    output,
+                    size,
+                });
+            }
+            zwlr_gamma_control_manager_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ZwlrGammaControlV1, (), D> for GammaControlManagerState
+where
+    D: Dispatch<ZwlrGammaControlV1, ()> + GammaControlHandler + 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        resource: &ZwlrGammaControlV1,
+        request: zwlr_gamma_control_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_gamma_control_v1::Request::SetGamma { fd } => {
+                let (output, size) = match state
+                    .gamma_control_state()
+                    .controls
+                    .iter()
+                    .find(|control| control.control == *resource)
+                {
+                    Some(control) => (control.output.clone(), control.size),
+                    // Failed controls are inert.
+                    None => return,
+                };
+
+                // The ramp is red then green then blue, in native endianness.
+                let mut bytes = vec![0; size * 3 * 2];
+                match File::from(fd).read_exact_at(&mut bytes, 0) {
+                    Ok(()) => (),
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                        resource.post_error(
+                            zwlr_gamma_control_v1::Error::InvalidGamma,
+                            "the gamma ramp is too small",
+                        );
+                        return;
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "Failed to read the gamma ramp of {}: {}",
+                            output.name(),
+                            err
+                        );
+                        fail(state, resource, &output);
+                        return;
+                    }
+                }
+                let ramp = bytes
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+                    .collect();
+
+                if !state.set_gamma(&output, Some(ramp)) {
+                    fail(state, resource, &output);
+                }
+            }
+            zwlr_gamma_control_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(state: &mut D, _client: ClientId, resource: ObjectId, _data: &()) {
+        let controls = &mut state.gamma_control_state().controls;
+        if let Some(index) = controls
+            .iter()
+            .position(|control| control.control.id() == resource)
+        {
+            let control = controls.remove(index);
+            state.set_gamma(&control.output, None);
+        }
+    }
+}
+
+/// Give up on a control, and put the original ramp of its output back.
+fn fail<D: GammaControlHandler>(state: &mut D, control: &ZwlrGammaControlV1, output: &Output) {
+    control.failed();
+    state
+        .gamma_control_state()
+        .controls
+        .retain(|c| c.control != *control);
+    state.set_gamma(output, None);
+}
+
+#[macro_export]
+macro_rules! delegate_gamma_control {
+    ($(@<$( $lt:tt $( : $clt:tt $(+ $dlt:tt )* )? ),+>)? $ty: ty) => {
+        smithay::reexports::wayland_server::delegate_global_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::gamma_control::v1::server::zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1: ()
+        ] => $crate::protocols::gamma_control::GammaControlManagerState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::gamma_control::v1::server::zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1: ()
+        ] => $crate::protocols::gamma_control::GammaControlManagerState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::gamma_control::v1::server::zwlr_gamma_control_v1::ZwlrGammaControlV1: ()
+        ] => $crate::protocols::gamma_control::GammaControlManagerState);
+    };
+}
@@ -0,0 +1,5 @@
+//! Protocols that Smithay doesn't implement.
+
+pub mod ext_workspace;
+pub mod gamma_control;
+pub mod output_management;
@@ -0,0 +1,606 @@
+//! wlr-output-management-unstable-v1: lets tools like kanshi and wdisplays read the output layout
+//! and change it.
+//!
+//! The compositor hands the outputs to [`OutputManagementState::refresh`], which sends every client
+//! the differences to what it knows. Configurations are checked for protocol errors here, then
+//! handed to [`OutputManagementHandler::apply_output_configuration`], which has to answer them.
+
+use std::sync::Mutex;
+
+use smithay::{
+    output::{Mode, Output},
+    reexports::{
+        wayland_protocols_wlr::output_management::v1::server::{
+            zwlr_output_configuration_head_v1::{self, ZwlrOutputConfigurationHeadV1},
+            zwlr_output_configuration_v1::{self, ZwlrOutputConfigurationV1},
+            zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+            zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+            zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+        },
+        wayland_server::{
+            backend::{ClientId, ObjectId},
+            Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+        },
+    },
+    utils::{IsAlive, Logical, Point, Transform},
+};
+
+use crate::output::OutputSerial;
+
+/// Version 4 only adds adaptive sync, which is not supported.
+const VERSION: u32 = 3;
+
+/// An output as the compositor sees it. Everything else is read from the output itself.
+pub struct OutputHead {
+    pub output: Output,
+    /// `None` if the output is disabled.
+    pub position: Option<Point<i32, Logical>>,
+}
+
+/// What a client wants an enabled output to look like. Whatever is `None` stays as it is.
+#[derive(Debug, Clone, Default)]
+pub struct HeadConfiguration {
+    /// Either one of the modes of the output or a custom one, whose refresh rate is 0 if the client
+    /// doesn't care.
+    pub mode: Option<Mode>,
+    pub position: Option<Point<i32, Logical>>,
+    pub transform: Option<Transform>,
+    pub scale: Option<f64>,
+}
+
+pub trait OutputManagementHandler {
+    fn output_management_state(&mut self) -> &mut OutputManagementState;
+
+    /// Test or apply a configuration of every output, `None` for the ones to disable. The client
+    /// has to be sent `succeeded` or `failed` on `configuration`, which may wait until the backend
+    /// knows whether it worked.
+    fn apply_output_configuration(
+        &mut self,
+        configuration: ZwlrOutputConfigurationV1,
+        heads: Vec<(Output, Option<HeadConfiguration>)>,
+        test: bool,
+    );
+}
+
+pub struct OutputManagementState {
+    display: DisplayHandle,
+    instances: Vec<ManagerInstance>,
+    /// Bumped whenever the outputs change, so that configurations made for an older state can be
+    /// cancelled.
+    serial: u32,
+    heads: Vec<HeadSnapshot>,
+}
+
+/// A bound manager, with everything its client has been told about.
+struct ManagerInstance {
+    manager: ZwlrOutputManagerV1,
+    heads: Vec<Head>,
+}
+
+struct Head {
+    handle: ZwlrOutputHeadV1,
+    output: Output,
+    modes: Vec<(ZwlrOutputModeV1, Mode)>,
+    sent: Option<HeadSnapshot>,
+}
+
+/// The part of an output that is sent to clients.
+#[derive(Clone, PartialEq)]
+struct HeadSnapshot {
+    output: Output,
+    modes: Vec<Mode>,
+    preferred_mode: Option<Mode>,
+    current_mode: Option<Mode>,
+    position: Option<Point<i32, Logical>>,
+    transform: Transform,
+    scale: f64,
+}
+
+impl HeadSnapshot {
+    fn new(head: &OutputHead) -> Self {
+        Self {
+            output: head.output.clone(),
+            modes: head.output.modes(),
+            preferred_mode: head.output.preferred_mode(),
+            current_mode: head.output.current_mode(),
+            position: head.position,
+            transform: head.output.current_transform(),
+            scale: head.output.current_scale().fractional_scale(),
+        }
+    }
+}
+
+/// The user data of a configuration, filled by the client until it is applied or tested.
+pub struct ConfigurationData(Mutex<PendingConfiguration>);
+
+struct PendingConfiguration {
+    serial: u32,
+    heads: Vec<(Output, Option<ZwlrOutputConfigurationHeadV1>)>,
+    used: bool,
+}
+
+/// The user data of the configuration of an enabled output.
+pub struct ConfigurationHeadData(Mutex<HeadConfiguration>);
+
+impl OutputManagementState {
+    pub fn new<D>(display: &DisplayHandle) -> Self
+    where
+        D: GlobalDispatch<ZwlrOutputManagerV1, ()> + 'static,
+    {
+        display.create_global::<D, ZwlrOutputManagerV1, _>(VERSION, ());
+
+        Self {
+            display: display.clone(),
+            instances: Vec::new(),
+            serial: 0,
+            heads: Vec::new(),
+        }
+    }
+
+    /// Tell every client about the changes to the outputs since the last refresh. Cheap if nothing
+    /// has changed, so it can be called on every event loop iteration.
+    pub fn refresh<D>(&mut self, heads: &[OutputHead])
+    where
+        D: Dispatch<ZwlrOutputHeadV1, Output> + Dispatch<ZwlrOutputModeV1, Mode> + 'static,
+    {
+        let heads = heads.iter().map(HeadSnapshot::new).collect::<Vec<_>>();
+        if heads != self.heads {
+            self.serial = self.serial.wrapping_add(1);
+            self.heads = heads;
+        }
+
+        for instance in &mut self.instances {
+            let client = match self.display.get_client(instance.manager.id()) {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            if instance.refresh::<D>(&self.display, &client, &self.heads) {
+                instance.manager.done(self.serial);
+            }
+        }
+    }
+}
+
+impl ManagerInstance {
+    /// Returns whether any event has been sent.
+    fn refresh<D>(
+        &mut self,
+        display: &DisplayHandle,
+        client: &Client,
+        snapshots: &[HeadSnapshot],
+    ) -> bool
+    where
+        D: Dispatch<ZwlrOutputHeadV1, Output> + Dispatch<ZwlrOutputModeV1, Mode> + 'static,
+    {
+        let mut changed = false;
+
+        // Outputs that are gone.
+        self.heads.retain(|head| {
+            if snapshots
+                .iter()
+                .any(|snapshot| snapshot.output == head.output)
+            {
+                return true;
+            }
+            for (mode, _) in &head.modes {
+                mode.finished();
+            }
+            head.handle.finished();
+            changed = true;
+            false
+        });
+
+        for snapshot in snapshots {
+            let index = match self
+                .heads
+                .iter()
+                .position(|head| head.output == snapshot.output)
+            {
+                Some(index) => index,
+                None => {
+                    let handle = match client.create_resource::<ZwlrOutputHeadV1, _, D>(
+                        display,
+                        self.manager.version(),
+                        snapshot.output.clone(),
+                    ) {
+                        Ok(handle) => handle,
+                        Err(_) => continue,
+                    };
+                    self.manager.head(&handle);
+                    send_head_properties(&handle, &snapshot.output);
+                    self.heads.push(Head {
+                        handle,
+                        output: snapshot.output.clone(),
+                        modes: Vec::new(),
+                        sent: None,
+                    });
+                    self.heads.len() - 1
+                }
+            };
+            let head = &mut self.heads[index];
+
+            // Released heads don't get any more events.
+            if head.sent.as_ref() == Some(snapshot) || !head.handle.alive() {
+                continue;
+            }
+            changed = true;
+
+            head.modes.retain(|(handle, mode)| {
+                if snapshot.modes.contains(mode) {
+                    return true;
+                }
+                handle.finished();
+                false
+            });
+            for mode in &snapshot.modes {
+                if head.modes.iter().any(|(_, m)| m == mode) {
+                    continue;
+                }
+                let handle = match client.create_resource::<ZwlrOutputModeV1, _, D>(
+                    display,
+                    head.handle.version(),
+                    *mode,
+                ) {
+                    Ok(handle) => handle,
+                    Err(_) => continue,
+                };
+                head.handle.mode(&handle);
+                handle.size(mode.size.w, mode.size.h);
+                if mode.refresh > 0 {
+                    handle.refresh(mode.refresh);
+                }
+                if snapshot.preferred_mode == Some(*mode) {
+                    handle.preferred();
+                }
+                head.modes.push((handle, *mode));
+            }
+
+            head.handle.enabled(snapshot.position.is_some() as i32);
+            // The rest only means something for enabled outputs.
+            if let Some(position) = snapshot.position {
+                if let Some((handle, _)) = head
+                    .modes
+                    .iter()
+                    .find(|(_, mode)| Some(*mode) == snapshot.current_mode)
+                {
+                    head.handle.current_mode(handle);
+                }
+                head.handle.position(position.x, position.y);
+                head.handle.transform(snapshot.transform.into());
+                head.handle.scale(snapshot.scale);
+            }
+
+            head.sent = Some(snapshot.clone());
+        }
+
+        changed
+    }
+}
+
+/// The properties of a head that never change.
+fn send_head_properties(handle: &ZwlrOutputHeadV1, output: &Output) {
+    let properties = output.physical_properties();
+    handle.name(output.name());
+    handle.description(format!(
+        "{} {} ({})",
+        properties.make,
+        properties.model,
+        output.name()
+    ));
+    if properties.size.w > 0 && properties.size.h > 0 {
+        handle.physical_size(properties.size.w, properties.size.h);
+    }
+    if handle.version() >= 2 {
+        handle.make(properties.make);
+        handle.model(properties.model);
+        if let Some(serial) = output.user_data().get::<OutputSerial>() {
+            handle.serial_number(serial.0.clone());
+        }
+    }
+}
+
+impl<D> GlobalDispatch<ZwlrOutputManagerV1, (), D> for OutputManagementState
+where
+    D: GlobalDispatch<ZwlrOutputManagerV1, ()>
+        + Dispatch<ZwlrOutputManagerV1, ()>
+        + OutputManagementHandler
+        + 'static,
+{
+    fn bind(
+        state: &mut D,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwlrOutputManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        let manager = data_init.init(resource, ());
+        // The outputs are announced on the next refresh.
+        state
+            .output_management_state()
+            .instances
+            .push(ManagerInstance {
+                manager,
+                heads: Vec::new(),
+            });
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputManagerV1, (), D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputManagerV1, ()>
+        + Dispatch<ZwlrOutputConfigurationV1, ConfigurationData>
+        + OutputManagementHandler
+        + 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        resource: &ZwlrOutputManagerV1,
+        request: zwlr_output_manager_v1::Request,
+        _data: &(),
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_output_manager_v1::Request::CreateConfiguration { id, serial } => {
+                data_init.init(
+                    id,
+                    ConfigurationData(Mutex::new(PendingConfiguration {
+                        serial,
+                        heads: Vec::new(),
+                        used: false,
+                    })),
+                );
+            }
+            zwlr_output_manager_v1::Request::Stop => {
+                resource.finished();
+                state
+                    .output_management_state()
+                    .instances
+                    .retain(|instance| instance.manager != *resource);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(state: &mut D, _client: ClientId, resource: ObjectId, _data: &()) {
+        state
+            .output_management_state()
+            .instances
+            .retain(|instance| instance.manager.id() != resource);
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputHeadV1, Output, D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputHeadV1, Output> + OutputManagementHandler + 'static,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        _resource: &ZwlrOutputHeadV1,
+        request: zwlr_output_head_v1::Request,
+        _output: &Output,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            // Events to released heads are skipped in `refresh`.
+            zwlr_output_head_v1::Request::Release => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputModeV1, Mode, D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputModeV1, Mode> + OutputManagementHandler + 'static,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        _resource: &ZwlrOutputModeV1,
+        request: zwlr_output_mode_v1::Request,
+        _mode: &Mode,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_output_mode_v1::Request::Release => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputConfigurationV1, ConfigurationData, D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputConfigurationV1, ConfigurationData>
+        + Dispatch<ZwlrOutputConfigurationHeadV1, ConfigurationHeadData>
+        + OutputManagementHandler
+        + 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        resource: &ZwlrOutputConfigurationV1,
+        request: zwlr_output_configuration_v1::Request,
+        data: &ConfigurationData,
+        _dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        let mut pending = data.0.lock().unwrap();
+        if pending.used && !matches!(request, zwlr_output_configuration_v1::Request::Destroy) {
+            resource.post_error(
+                zwlr_output_configuration_v1::Error::AlreadyUsed,
+                "the configuration has already been applied or tested",
+            );
+            return;
+        }
+
+        let test = match request {
+            zwlr_output_configuration_v1::Request::EnableHead { id, head } => {
+                let handle = data_init.init(
+                    id,
+                    ConfigurationHeadData(Mutex::new(HeadConfiguration::default())),
+                );
+                configure_head(resource, &mut pending, &head, Some(handle));
+                return;
+            }
+            zwlr_output_configuration_v1::Request::DisableHead { head } => {
+                configure_head(resource, &mut pending, &head, None);
+                return;
+            }
+            zwlr_output_configuration_v1::Request::Apply => false,
+            zwlr_output_configuration_v1::Request::Test => true,
+            zwlr_output_configuration_v1::Request::Destroy => return,
+            _ => unreachable!(),
+        };
+        pending.used = true;
+
+        let manager_state = state.output_management_state();
+        if pending.serial != manager_state.serial {
+            resource.cancelled();
+            return;
+        }
+        if let Some(head) = manager_state
+            .heads
+            .iter()
+            .find(|head| !pending.heads.iter().any(|(o, _)| *o == head.output))
+        {
+            resource.post_error(
+                zwlr_output_configuration_v1::Error::UnconfiguredHead,
+                format!("{} has not been configured", head.output.name()),
+            );
+            return;
+        }
+
+        let heads = pending
+            .heads
+            .iter()
+            .map(|(output, handle)| {
+                let config = handle.as_ref().map(|handle| {
+                    handle
+                        .data::<ConfigurationHeadData>()
+                        .map(|data| data.0.lock().unwrap().clone())
+                        .unwrap_or_default()
+                });
+                (output.clone(), config)
+            })
+            .collect();
+        drop(pending);
+        state.apply_output_configuration(resource.clone(), heads, test);
+    }
+}
+
+/// Add a head to a configuration, `handle` is `None` if it is disabled.
+fn configure_head(
+    configuration: &ZwlrOutputConfigurationV1,
+    pending: &mut PendingConfiguration,
+    head: &ZwlrOutputHeadV1,
+    handle: Option<ZwlrOutputConfigurationHeadV1>,
+) {
+    let output = match head.data::<Output>() {
+        Some(output) => output,
+        None => return,
+    };
+    if pending.heads.iter().any(|(o, _)| o == output) {
+        configuration.post_error(
+            zwlr_output_configuration_v1::Error::AlreadyConfiguredHead,
+            format!("{} has already been configured", output.name()),
+        );
+        return;
+    }
+    pending.heads.push((output.clone(), handle));
+}
+
+impl<D> Dispatch<ZwlrOutputConfigurationHeadV1, ConfigurationHeadData, D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputConfigurationHeadV1, ConfigurationHeadData>
+        + OutputManagementHandler
+        + 'static,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        resource: &ZwlrOutputConfigurationHeadV1,
+        request: zwlr_output_configuration_head_v1::Request,
+        data: &ConfigurationHeadData,
+        _dhandle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        use zwlr_output_configuration_head_v1::{Error, Request};
+
+        let mut config = data.0.lock().unwrap();
+        let already_set = match request {
+            Request::SetMode { .. } | Request::SetCustomMode { .. } => config.mode.is_some(),
+            Request::SetPosition { .. } => config.position.is_some(),
+            Request::SetTransform { .. } => config.transform.is_some(),
+            Request::SetScale { .. } => config.scale.is_some(),
+            _ => false,
+        };
+        if already_set {
+            resource.post_error(Error::AlreadySet, "property has already been set");
+            return;
+        }
+
+        match request {
+            Request::SetMode { mode } => match mode.data::<Mode>() {
+                Some(mode) => config.mode = Some(*mode),
+                None => resource.post_error(Error::InvalidMode, "invalid mode"),
+            },
+            Request::SetCustomMode {
+                width,
+                height,
+                refresh,
+            } => {
+                if width <= 0 || height <= 0 || refresh < 0 {
+                    resource.post_error(Error::InvalidCustomMode, "invalid custom mode");
+                    return;
+                }
+                config.mode = Some(Mode {
+                    size: (width, height).into(),
+                    refresh,
+                });
+            }
+            Request::SetPosition { x, y } => config.position = Some((x, y).into()),
+            Request::SetTransform { transform } => match transform.into_result() {
+                Ok(transform) => config.transform = Some(transform.into()),
+                Err(_) => resource.post_error(Error::InvalidTransform, "invalid transform"),
+            },
+            Request::SetScale { scale } => {
+                if scale <= 0.0 || !scale.is_finite() {
+                    resource.post_error(Error::InvalidScale, "invalid scale");
+                    return;
+                }
+                config.scale = Some(scale);
+            }
+            // Adaptive sync only exists in version 4, which is not advertised.
+            _ => (),
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! delegate_output_management {
+    ($(@<$( $lt:tt $( : $clt:tt $(+ $dlt:tt )* )? ),+>)? $ty: ty) => {
+        smithay::reexports::wayland_server::delegate_global_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_manager_v1::ZwlrOutputManagerV1: ()
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_manager_v1::ZwlrOutputManagerV1: ()
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_head_v1::ZwlrOutputHeadV1: smithay::output::Output
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_mode_v1::ZwlrOutputModeV1: smithay::output::Mode
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_configuration_v1::ZwlrOutputConfigurationV1: $crate::protocols::output_management::ConfigurationData
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_configuration_head_v1::ZwlrOutputConfigurationHeadV1: $crate::protocols::output_management::ConfigurationHeadData
+        ] => $crate::protocols::output_management::OutputManagementState);
+    };
+}
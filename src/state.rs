@@ -1,24 +1,55 @@
-use std::time::Instant;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use smithay::{
-    desktop::{PopupManager, Space, Window, WindowSurfaceType},
+    desktop::{layer_map_for_output, PopupManager, Space, WindowSurfaceType},
     input::{
         pointer::{CursorImageStatus, PointerHandle},
         Seat, SeatState,
     },
     output::Output,
     reexports::{
-        calloop::{EventLoop, LoopSignal},
-        wayland_server::{protocol::wl_surface::WlSurface, Display},
+        calloop::{
+            timer::{TimeoutAction, Timer},
+            EventLoop, LoopHandle, LoopSignal,
+        },
+        wayland_server::{protocol::wl_surface::WlSurface, Display, DisplayHandle},
     },
-    utils::{Clock, Logical, Monotonic, Point},
+    utils::{Clock, Logical, Monotonic, Point, Rectangle},
     wayland::{
-        compositor::CompositorState, data_device::DataDeviceState, output::OutputManagerState,
-        shell::xdg::XdgShellState, shm::ShmState,
+        compositor::CompositorState,
+        data_device::DataDeviceState,
+        fractional_scale::FractionalScaleManagerState,
+        output::OutputManagerState,
+        presentation::PresentationState,
+        session_lock::SessionLockManagerState,
+        shell::{
+            wlr_layer::{Layer, WlrLayerShellState},
+            xdg::XdgShellState,
+        },
+        shm::ShmState,
+        viewporter::ViewporterState,
+        xdg_activation::XdgActivationState,
     },
+    xwayland::{X11Wm, XWayland, XWaylandEvent},
+};
+
+use crate::{
+    action::Action,
+    config::{init_config_watcher, Config, OutputConfig},
+    data::Data,
+    handlers::{LockState, PendingOutputConfiguration, PendingOutputTest},
+    ipc::{init_ipc_server, InputDeviceInfo, IpcState},
+    protocols::{ext_workspace::ExtWorkspaceState, output_management::OutputManagementState},
+    window::WindowElement,
+    workspace::Workspace,
 };
 
-use crate::data::Data;
+/// How long to wait before starting Xwayland again after it has exited.
+const XWAYLAND_RESTART_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -31,18 +62,58 @@ pub struct State<BackendData: 'static> {
     pub clock: Clock<Monotonic>,
 
     pub loop_signal: LoopSignal,
+    pub loop_handle: LoopHandle<'static, Data<BackendData>>,
+    pub display_handle: DisplayHandle,
+
+    pub config: Config,
+    /// Where the config is read from, and reloaded from when it changes.
+    pub config_path: Option<PathBuf>,
 
     pub compositor_state: CompositorState,
     pub shm_state: ShmState,
     pub output_manager_state: OutputManagerState,
     pub xdg_shell_state: XdgShellState,
+    pub xdg_activation_state: XdgActivationState,
+    pub layer_shell_state: WlrLayerShellState,
+    pub session_lock_state: SessionLockManagerState,
+    pub ext_workspace_state: ExtWorkspaceState,
+    pub output_management_state: OutputManagementState,
+    pub fractional_scale_manager_state: FractionalScaleManagerState,
+    pub viewporter_state: ViewporterState,
+    pub presentation_state: PresentationState,
     pub seat_state: SeatState<Self>,
     pub data_device_state: DataDeviceState,
     pub seat: Seat<Self>,
+    /// Keys whose press triggered a binding, with the action to run when they are released.
+    pub intercepted_keys: HashMap<u32, Action>,
+    /// Keys that are held down, by key code.
+    pub pressed_keys: HashSet<u32>,
     pub popups: PopupManager,
 
-    pub space: Space<Window>,
+    /// Every output that is plugged in, including the disabled ones that are not in `space`.
+    pub outputs: Vec<Output>,
+    /// Settings made by output management clients, by output name. They take precedence over the
+    /// config until it is reloaded.
+    pub output_overrides: HashMap<String, OutputConfig>,
+    /// The last applied output configuration, until the backend has tried it.
+    pub pending_output_configuration: Option<PendingOutputConfiguration>,
+    /// Output configurations to be tested, until the backend has tried them.
+    pub pending_output_tests: Vec<PendingOutputTest>,
+    /// Holds the enabled outputs and the windows of the workspaces that are shown.
+    pub space: Space<WindowElement>,
+    pub workspaces: Vec<Workspace>,
     pub cursor_status: CursorImageStatus,
+    pub lock: LockState,
+
+    /// Runs the X11 clients, started along with the compositor unless the config disables it, and
+    /// again whenever it exits.
+    pub xwayland: Option<XWayland>,
+    /// Maps the X11 windows into `space`. `None` until Xwayland is ready, or once it has exited.
+    pub xwm: Option<X11Wm>,
+
+    /// `None` if the IPC socket could not be created.
+    pub ipc: Option<IpcState>,
+    pub input_devices: Vec<InputDeviceInfo>,
 
     pub backend_data: BackendData,
 }
@@ -50,8 +121,9 @@ pub struct State<BackendData: 'static> {
 impl<BackendData> State<BackendData> {
     pub fn new(
         display: &Display<Self>,
-        event_loop: &mut EventLoop<Data<BackendData>>,
+        event_loop: &mut EventLoop<'static, Data<BackendData>>,
         backend_data: BackendData,
+        config_path: Option<PathBuf>,
     ) -> Result<Self, Error> {
         // Get the display handle. Again: it is just related to the Wayland protocol and has nothing to
         // do with the backend.
@@ -63,38 +135,99 @@ impl<BackendData> State<BackendData> {
         let shm_state = ShmState::new::<Self>(&dh, vec![]);
         let output_manager_state = OutputManagerState::new_with_xdg_output::<Self>(&dh);
         let xdg_shell_state = XdgShellState::new::<Self>(&dh);
+        // Lets clients pass the focus on, and mark windows that want attention as urgent.
+        let xdg_activation_state = XdgActivationState::new::<Self>(&dh);
+        let layer_shell_state = WlrLayerShellState::new::<Self>(&dh);
+        let session_lock_state = SessionLockManagerState::new::<Self, _>(&dh, |_| true);
+        let ext_workspace_state = ExtWorkspaceState::new::<Self>(&dh);
+        let output_management_state = OutputManagementState::new::<Self>(&dh);
+        // Lets clients render at the exact scale of fractionally scaled outputs, with viewporter
+        // to present the buffers at their logical size.
+        let fractional_scale_manager_state = FractionalScaleManagerState::new::<Self>(&dh);
+        let viewporter_state = ViewporterState::new::<Self>(&dh);
+        // Presentation times are reported on the monotonic clock, like DRM timestamps.
+        let clock = Clock::<Monotonic>::new().unwrap();
+        let presentation_state = PresentationState::new::<Self>(&dh, clock.id() as u32);
         let mut seat_state = SeatState::new();
         let data_device_state = DataDeviceState::new::<Self>(&dh);
 
+        let config = Config::load_or_default(config_path.as_deref());
+        if let Some(path) = &config_path {
+            if let Err(err) = init_config_watcher(event_loop, path) {
+                tracing::warn!(
+                    "Failed to watch {}, live reload disabled: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+
+        let ipc = init_ipc_server(event_loop)
+            .map_err(|err| tracing::warn!("Failed to start IPC server: {}", err))
+            .ok();
+
         let mut seat = seat_state.new_wl_seat(&dh, "alioth");
         // FIXME: Implement hot-plug
-        seat.add_keyboard(Default::default(), 200, 200)
-            .or_else(|_| {
-                tracing::error!("Failed to add keyboard");
-                Err(Error::KeyboardAddFailure)
-            })?;
+        seat.add_keyboard(
+            Default::default(),
+            config.keyboard.repeat_delay,
+            config.keyboard.repeat_rate,
+        )
+        .or_else(|_| {
+            tracing::error!("Failed to add keyboard");
+            Err(Error::KeyboardAddFailure)
+        })?;
         seat.add_pointer();
 
         let space = Space::default();
 
+        let xwayland = config.xwayland.then(|| init_xwayland(event_loop, &dh));
+
         // Pack the state.
         let state = State {
             start_time: Instant::now(),
-            clock: Clock::new().unwrap(),
+            clock,
 
             loop_signal: event_loop.get_signal(),
+            loop_handle: event_loop.handle(),
+            display_handle: dh,
+
+            config,
+            config_path,
 
             compositor_state,
             shm_state,
             output_manager_state,
             xdg_shell_state,
+            xdg_activation_state,
+            layer_shell_state,
+            session_lock_state,
+            ext_workspace_state,
+            output_management_state,
+            fractional_scale_manager_state,
+            viewporter_state,
+            presentation_state,
             seat_state,
             data_device_state,
             seat,
+            intercepted_keys: HashMap::new(),
+            pressed_keys: HashSet::new(),
             popups: PopupManager::default(),
 
+            outputs: Vec::new(),
+            output_overrides: HashMap::new(),
+            pending_output_configuration: None,
+            pending_output_tests: Vec::new(),
             space,
+            workspaces: Vec::new(),
             cursor_status: CursorImageStatus::Default,
+            lock: LockState::default(),
+
+            xwayland,
+            xwm: None,
+
+            ipc,
+            input_devices: Vec::new(),
 
             backend_data,
         };
@@ -107,21 +240,128 @@ impl<BackendData> State<BackendData> {
         pointer: &PointerHandle<Self>,
     ) -> Option<(WlSurface, Point<i32, Logical>)> {
         let pos = pointer.current_location();
-        self.space
-            .element_under(pos)
-            .and_then(|(window, location)| {
-                window
-                    .surface_under(pos - location.to_f64(), WindowSurfaceType::ALL)
-                    .map(|(surface, point)| (surface, point + location))
+        // Nothing but the lock surfaces gets input while the session is locked.
+        if self.is_locked() {
+            return self.lock_surface_under(pos);
+        }
+
+        let output = self.space.output_under(pos).next();
+        let output_loc = output
+            .and_then(|output| self.space.output_geometry(output))
+            .map(|geo| geo.loc)
+            .unwrap_or_default();
+        let layer_map = output.map(layer_map_for_output);
+
+        // Layer surfaces are searched relative to their output.
+        let layer_surface_under = |layers: &[Layer]| {
+            let map = layer_map.as_ref()?;
+            let pos = pos - output_loc.to_f64();
+            layers.iter().find_map(|layer| {
+                let layer = map.layer_under(*layer, pos)?;
+                let layer_loc = map.layer_geometry(layer)?.loc;
+                layer
+                    .surface_under(pos - layer_loc.to_f64(), WindowSurfaceType::ALL)
+                    .map(|(surface, point)| (surface, point + layer_loc + output_loc))
+            })
+        };
+
+        layer_surface_under(&[Layer::Overlay, Layer::Top])
+            .or_else(|| {
+                self.space
+                    .element_under(pos)
+                    .and_then(|(window, location)| {
+                        window
+                            .surface_under(pos - location.to_f64(), WindowSurfaceType::ALL)
+                            .map(|(surface, point)| (surface, point + location))
+                    })
             })
+            .or_else(|| layer_surface_under(&[Layer::Bottom, Layer::Background]))
     }
 
-    /// Currenty there's no proper muti-output support. So we simply put the new output on the
-    /// right. Soon an output manager will be implemented.
-    pub fn map_output_on_the_right(&mut self, output: Output) {
-        let x = self.space.outputs().fold(0, |sum, output| {
-            sum + self.space.output_geometry(output).unwrap().size.w
+    /// The area of an output that is not taken by the exclusive zones of layer surfaces, in global
+    /// coordinates.
+    pub fn usable_area(&self, output: &Output) -> Option<Rectangle<i32, Logical>> {
+        let output_geo = self.space.output_geometry(output)?;
+        let mut zone = layer_map_for_output(output).non_exclusive_zone();
+        zone.loc += output_geo.loc;
+        Some(zone)
+    }
+
+    /// Unmap an output that is going away. Its workspaces are moved to another output.
+    pub fn unmap_output(&mut self, output: &Output) {
+        self.remove_output_workspaces(output);
+        self.space.unmap_output(output);
+    }
+}
+
+/// Start Xwayland, with the XWM attached once it is ready. X11 clients find it through `$DISPLAY`.
+fn init_xwayland<BackendData>(
+    event_loop: &mut EventLoop<'static, Data<BackendData>>,
+    dh: &DisplayHandle,
+) -> XWayland {
+    let (xwayland, channel) = XWayland::new(dh);
+    let dh = dh.clone();
+    let result = event_loop
+        .handle()
+        .insert_source(channel, move |event, _, data| match event {
+            XWaylandEvent::Ready {
+                connection, client, ..
+            } => {
+                match X11Wm::start_wm(
+                    data.state.loop_handle.clone(),
+                    dh.clone(),
+                    connection,
+                    client,
+                ) {
+                    Ok(xwm) => data.state.xwm = Some(xwm),
+                    Err(err) => tracing::error!("Failed to start the XWM: {}", err),
+                }
+            }
+            XWaylandEvent::Exited => {
+                tracing::warn!(
+                    "Xwayland has exited, restarting it in {:?}",
+                    XWAYLAND_RESTART_DELAY
+                );
+                data.state.xwm = None;
+                data.state.remove_x11_windows();
+                // A delay keeps an Xwayland that crashes right away from taking the event loop
+                // with it.
+                let timer = data.state.loop_handle.insert_source(
+                    Timer::from_duration(XWAYLAND_RESTART_DELAY),
+                    |_, _, data| {
+                        if let Some(xwayland) = &data.state.xwayland {
+                            start_xwayland(xwayland, data.state.loop_handle.clone());
+                        }
+                        TimeoutAction::Drop
+                    },
+                );
+                if let Err(err) = timer {
+                    tracing::error!("Failed to insert the Xwayland restart timer: {}", err);
+                }
+            }
         });
-        self.space.map_output(&output, (x, 0));
+    if let Err(err) = result {
+        tracing::error!("Failed to insert the Xwayland source: {}", err);
+        return xwayland;
+    }
+
+    start_xwayland(&xwayland, event_loop.handle());
+    xwayland
+}
+
+/// Start the Xwayland server, and point the clients spawned from now on to it.
+fn start_xwayland<BackendData>(
+    xwayland: &XWayland,
+    loop_handle: LoopHandle<'static, Data<BackendData>>,
+) {
+    match xwayland.start(
+        loop_handle,
+        None,
+        std::iter::empty::<(String, String)>(),
+        true,
+        |_| {},
+    ) {
+        Ok(display) => std::env::set_var("DISPLAY", format!(":{}", display)),
+        Err(err) => tracing::warn!("Failed to start Xwayland, X11 clients won't work: {}", err),
     }
 }
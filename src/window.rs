@@ -0,0 +1,500 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use smithay::{
+    backend::renderer::{
+        element::{
+            surface::{render_elements_from_surface_tree, WaylandSurfaceRenderElement},
+            AsRenderElements,
+        },
+        ImportAll, Renderer, Texture,
+    },
+    desktop::{
+        space::SpaceElement,
+        utils::{
+            send_dmabuf_feedback_surface_tree, send_frames_surface_tree,
+            take_presentation_feedback_surface_tree, with_surfaces_surface_tree,
+            OutputPresentationFeedback,
+        },
+        Window, WindowSurfaceType,
+    },
+    output::Output,
+    reexports::{
+        wayland_protocols::{
+            wp::presentation_time::server::wp_presentation_feedback,
+            xdg::shell::server::xdg_toplevel,
+        },
+        wayland_server::protocol::wl_surface::WlSurface,
+    },
+    utils::{user_data::UserDataMap, IsAlive, Logical, Physical, Point, Rectangle, Scale, Size},
+    wayland::{
+        compositor::{self, SurfaceData},
+        dmabuf::DmabufFeedback,
+        shell::xdg::{SurfaceCachedState, XdgToplevelSurfaceData},
+    },
+    xwayland::X11Surface,
+};
+
+/// A window in the space: an xdg toplevel, or an X11 window managed through Xwayland.
+///
+/// X11 clients get an unscaled coordinate space: one X11 pixel is one physical pixel of the most
+/// scaled output, so that they render at its native resolution instead of being scaled up. The
+/// geometry of X11 windows is converted to logical pixels here.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WindowElement {
+    Wayland(Window),
+    X11(X11Surface),
+}
+
+/// How many X11 pixels make a logical pixel, stored in the user data of X11 windows.
+struct X11Scale(Mutex<f64>);
+
+/// The scale of the X11 coordinate space a window was configured in.
+pub fn x11_scale(surface: &X11Surface) -> f64 {
+    surface
+        .user_data()
+        .get::<X11Scale>()
+        .map(|scale| *scale.0.lock().unwrap())
+        .unwrap_or(1.0)
+}
+
+pub fn set_x11_scale(surface: &X11Surface, scale: f64) {
+    surface
+        .user_data()
+        .insert_if_missing_threadsafe(|| X11Scale(Mutex::new(scale)));
+    *surface
+        .user_data()
+        .get::<X11Scale>()
+        .unwrap()
+        .0
+        .lock()
+        .unwrap() = scale;
+}
+
+/// Whether a window has asked for attention, stored in its user data.
+struct Urgent(AtomicBool);
+
+/// Convert a rectangle in X11 pixels to logical pixels.
+pub fn from_x11(rect: Rectangle<i32, Logical>, scale: f64) -> Rectangle<i32, Logical> {
+    Rectangle::from_loc_and_size(
+        rect.loc.to_f64().downscale(scale).to_i32_round(),
+        rect.size.to_f64().downscale(scale).to_i32_round(),
+    )
+}
+
+impl WindowElement {
+    /// The root surface of the window. X11 windows only have one once Xwayland has associated it.
+    pub fn wl_surface(&self) -> Option<WlSurface> {
+        match self {
+            WindowElement::Wayland(window) => Some(window.toplevel().wl_surface().clone()),
+            WindowElement::X11(surface) => surface.wl_surface(),
+        }
+    }
+
+    /// Whether a surface is the root surface of the window.
+    pub fn has_surface(&self, surface: &WlSurface) -> bool {
+        self.wl_surface().as_ref() == Some(surface)
+    }
+
+    /// Menus, tooltips and the like of X11 clients, which place themselves and are not managed.
+    pub fn is_override_redirect(&self) -> bool {
+        match self {
+            WindowElement::Wayland(_) => false,
+            WindowElement::X11(surface) => surface.is_override_redirect(),
+        }
+    }
+
+    pub fn user_data(&self) -> &UserDataMap {
+        match self {
+            WindowElement::Wayland(window) => window.user_data(),
+            WindowElement::X11(surface) => surface.user_data(),
+        }
+    }
+
+    /// Whether the window has asked for attention and has not had the focus since.
+    pub fn is_urgent(&self) -> bool {
+        self.user_data()
+            .get::<Urgent>()
+            .map_or(false, |urgent| urgent.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set_urgent(&self, urgent: bool) {
+        self.user_data()
+            .insert_if_missing_threadsafe(|| Urgent(AtomicBool::new(false)));
+        self.user_data()
+            .get::<Urgent>()
+            .unwrap()
+            .0
+            .store(urgent, Ordering::Relaxed);
+    }
+
+    pub fn title_and_app_id(&self) -> (Option<String>, Option<String>) {
+        match self {
+            WindowElement::Wayland(window) => {
+                compositor::with_states(window.toplevel().wl_surface(), |states| {
+                    states
+                        .data_map
+                        .get::<XdgToplevelSurfaceData>()
+                        .map(|data| {
+                            let data = data.lock().unwrap();
+                            (data.title.clone(), data.app_id.clone())
+                        })
+                        .unwrap_or_default()
+                })
+            }
+            // The class is the closest thing to an app ID.
+            WindowElement::X11(surface) => (Some(surface.title()), Some(surface.class())),
+        }
+    }
+
+    /// Activated windows draw themselves as focused. Wayland ones only learn about it with the
+    /// next configure.
+    pub fn set_activated(&self, activated: bool) {
+        match self {
+            WindowElement::Wayland(window) => {
+                window.set_activated(activated);
+            }
+            WindowElement::X11(surface) => {
+                if let Err(err) = surface.set_activated(activated) {
+                    tracing::warn!("Failed to activate X11 window: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Send the pending state of a Wayland window. X11 windows are configured right away, so
+    /// there is nothing to send.
+    pub fn send_pending_configure(&self) {
+        if let WindowElement::Wayland(window) = self {
+            window.toplevel().send_pending_configure();
+        }
+    }
+
+    pub fn send_close(&self) {
+        match self {
+            WindowElement::Wayland(window) => window.toplevel().send_close(),
+            WindowElement::X11(surface) => {
+                if let Err(err) = surface.close() {
+                    tracing::warn!("Failed to close X11 window: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Ask the window to take a size. Wayland windows get it with the next configure.
+    pub fn request_size(&self, size: Size<i32, Logical>) {
+        match self {
+            WindowElement::Wayland(window) => {
+                window.toplevel().with_pending_state(|state| {
+                    state.size = Some(size);
+                });
+            }
+            WindowElement::X11(surface) => {
+                let scale = x11_scale(surface);
+                let mut geometry = surface.geometry();
+                geometry.size = size.to_f64().upscale(scale).to_i32_round();
+                if let Err(err) = surface.configure(geometry) {
+                    tracing::warn!("Failed to configure X11 window: {}", err);
+                }
+            }
+        }
+    }
+
+    /// The smallest and biggest sizes the window can take. A dimension of 0 means no limit.
+    pub fn size_limits(&self) -> (Size<i32, Logical>, Size<i32, Logical>) {
+        match self {
+            WindowElement::Wayland(window) => {
+                compositor::with_states(window.toplevel().wl_surface(), |states| {
+                    let data = states.cached_state.current::<SurfaceCachedState>();
+                    (data.min_size, data.max_size)
+                })
+            }
+            WindowElement::X11(surface) => {
+                let scale = x11_scale(surface);
+                let logical = |size: Option<Size<i32, Logical>>| {
+                    size.map(|size| size.to_f64().downscale(scale).to_i32_round())
+                        .unwrap_or_default()
+                };
+                (logical(surface.min_size()), logical(surface.max_size()))
+            }
+        }
+    }
+
+    /// Whether the window is maximized or fullscreen, as last acknowledged by the client.
+    pub fn has_state(&self, state: xdg_toplevel::State) -> bool {
+        match self {
+            WindowElement::Wayland(window) => {
+                window.toplevel().current_state().states.contains(state)
+            }
+            WindowElement::X11(surface) => match state {
+                xdg_toplevel::State::Maximized => surface.is_maximized(),
+                xdg_toplevel::State::Fullscreen => surface.is_fullscreen(),
+                _ => false,
+            },
+        }
+    }
+
+    /// Put the window into or out of a state (maximized or fullscreen). Returns whether it stays
+    /// in either of them, in which case it keeps covering its output.
+    pub fn set_state(&self, state: xdg_toplevel::State, set: bool) -> bool {
+        match self {
+            WindowElement::Wayland(window) => {
+                let mut remaining = false;
+                window.toplevel().with_pending_state(|pending| {
+                    if set {
+                        pending.states.set(state);
+                    } else {
+                        pending.states.unset(state);
+                    }
+                    remaining = pending.states.contains(xdg_toplevel::State::Fullscreen)
+                        || pending.states.contains(xdg_toplevel::State::Maximized);
+                });
+                remaining
+            }
+            WindowElement::X11(surface) => {
+                let result = match state {
+                    xdg_toplevel::State::Maximized => surface.set_maximized(set),
+                    xdg_toplevel::State::Fullscreen => surface.set_fullscreen(set),
+                    _ => Ok(()),
+                };
+                if let Err(err) = result {
+                    tracing::warn!("Failed to change the state of X11 window: {}", err);
+                }
+                surface.is_maximized() || surface.is_fullscreen()
+            }
+        }
+    }
+
+    /// Let Wayland windows deal with a commit of their surfaces.
+    pub fn on_commit(&self) {
+        if let WindowElement::Wayland(window) = self {
+            window.on_commit();
+        }
+    }
+
+    /// The surface under a point relative to the window, with the location it should be given
+    /// input at. The location of X11 surfaces is made up so that the input ends up in X11 pixels.
+    pub fn surface_under(
+        &self,
+        point: Point<f64, Logical>,
+        surface_type: WindowSurfaceType,
+    ) -> Option<(WlSurface, Point<i32, Logical>)> {
+        match self {
+            WindowElement::Wayland(window) => window.surface_under(point, surface_type),
+            WindowElement::X11(surface) => {
+                let geometry = SpaceElement::geometry(self);
+                if !geometry.to_f64().contains(point) {
+                    return None;
+                }
+                let x11_point = point.upscale(x11_scale(surface));
+                Some((surface.wl_surface()?, (point - x11_point).to_i32_round()))
+            }
+        }
+    }
+
+    pub fn send_frame<T, F>(
+        &self,
+        output: &Output,
+        time: T,
+        throttle: Option<Duration>,
+        primary_scan_out_output: F,
+    ) where
+        T: Into<Duration>,
+        F: FnMut(&WlSurface, &SurfaceData) -> Option<Output> + Copy,
+    {
+        match self {
+            WindowElement::Wayland(window) => {
+                window.send_frame(output, time, throttle, primary_scan_out_output)
+            }
+            WindowElement::X11(surface) => {
+                if let Some(wl_surface) = surface.wl_surface() {
+                    send_frames_surface_tree(
+                        &wl_surface,
+                        output,
+                        time,
+                        throttle,
+                        primary_scan_out_output,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn send_dmabuf_feedback<'a, P, F>(
+        &self,
+        output: &Output,
+        primary_scan_out_output: P,
+        select_dmabuf_feedback: F,
+    ) where
+        P: FnMut(&WlSurface, &SurfaceData) -> Option<Output> + Copy,
+        F: Fn(&WlSurface, &SurfaceData) -> &'a DmabufFeedback + Copy,
+    {
+        match self {
+            WindowElement::Wayland(window) => {
+                window.send_dmabuf_feedback(output, primary_scan_out_output, select_dmabuf_feedback)
+            }
+            WindowElement::X11(surface) => {
+                if let Some(wl_surface) = surface.wl_surface() {
+                    send_dmabuf_feedback_surface_tree(
+                        &wl_surface,
+                        output,
+                        primary_scan_out_output,
+                        select_dmabuf_feedback,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn take_presentation_feedback<F1, F2>(
+        &self,
+        output_feedback: &mut OutputPresentationFeedback,
+        primary_scan_out_output: F1,
+        presentation_feedback_flags: F2,
+    ) where
+        F1: FnMut(&WlSurface, &SurfaceData) -> Option<Output> + Copy,
+        F2: FnMut(&WlSurface, &SurfaceData) -> wp_presentation_feedback::Kind + Copy,
+    {
+        match self {
+            WindowElement::Wayland(window) => window.take_presentation_feedback(
+                output_feedback,
+                primary_scan_out_output,
+                presentation_feedback_flags,
+            ),
+            WindowElement::X11(surface) => {
+                if let Some(wl_surface) = surface.wl_surface() {
+                    take_presentation_feedback_surface_tree(
+                        &wl_surface,
+                        output_feedback,
+                        primary_scan_out_output,
+                        presentation_feedback_flags,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn with_surfaces<F>(&self, mut processor: F)
+    where
+        F: FnMut(&WlSurface, &SurfaceData),
+    {
+        match self {
+            WindowElement::Wayland(window) => window.with_surfaces(processor),
+            WindowElement::X11(surface) => {
+                if let Some(wl_surface) = surface.wl_surface() {
+                    with_surfaces_surface_tree(&wl_surface, &mut processor);
+                }
+            }
+        }
+    }
+}
+
+impl IsAlive for WindowElement {
+    fn alive(&self) -> bool {
+        match self {
+            WindowElement::Wayland(window) => window.alive(),
+            WindowElement::X11(surface) => surface.alive(),
+        }
+    }
+}
+
+impl SpaceElement for WindowElement {
+    fn geometry(&self) -> Rectangle<i32, Logical> {
+        match self {
+            WindowElement::Wayland(window) => SpaceElement::geometry(window),
+            WindowElement::X11(surface) => {
+                let size = from_x11(surface.geometry(), x11_scale(surface)).size;
+                Rectangle::from_loc_and_size((0, 0), size)
+            }
+        }
+    }
+
+    fn bbox(&self) -> Rectangle<i32, Logical> {
+        match self {
+            WindowElement::Wayland(window) => SpaceElement::bbox(window),
+            // Xwayland doesn't use subsurfaces, so nothing sticks out.
+            WindowElement::X11(_) => SpaceElement::geometry(self),
+        }
+    }
+
+    fn is_in_input_region(&self, point: &Point<f64, Logical>) -> bool {
+        match self {
+            WindowElement::Wayland(window) => SpaceElement::is_in_input_region(window, point),
+            WindowElement::X11(_) => SpaceElement::geometry(self).to_f64().contains(*point),
+        }
+    }
+
+    fn z_index(&self) -> u8 {
+        match self {
+            WindowElement::Wayland(window) => SpaceElement::z_index(window),
+            WindowElement::X11(surface) => SpaceElement::z_index(surface),
+        }
+    }
+
+    fn set_activate(&self, activated: bool) {
+        match self {
+            WindowElement::Wayland(window) => SpaceElement::set_activate(window, activated),
+            WindowElement::X11(surface) => SpaceElement::set_activate(surface, activated),
+        }
+    }
+
+    fn output_enter(&self, output: &Output, overlap: Rectangle<i32, Logical>) {
+        match self {
+            WindowElement::Wayland(window) => SpaceElement::output_enter(window, output, overlap),
+            WindowElement::X11(surface) => SpaceElement::output_enter(surface, output, overlap),
+        }
+    }
+
+    fn output_leave(&self, output: &Output) {
+        match self {
+            WindowElement::Wayland(window) => SpaceElement::output_leave(window, output),
+            WindowElement::X11(surface) => SpaceElement::output_leave(surface, output),
+        }
+    }
+
+    fn refresh(&self) {
+        match self {
+            WindowElement::Wayland(window) => SpaceElement::refresh(window),
+            WindowElement::X11(surface) => SpaceElement::refresh(surface),
+        }
+    }
+}
+
+impl<R> AsRenderElements<R> for WindowElement
+where
+    R: Renderer + ImportAll,
+    <R as Renderer>::TextureId: Texture + 'static,
+{
+    type RenderElement = WaylandSurfaceRenderElement<R>;
+
+    fn render_elements<C: From<Self::RenderElement>>(
+        &self,
+        renderer: &mut R,
+        location: Point<i32, Physical>,
+        scale: Scale<f64>,
+        alpha: f32,
+    ) -> Vec<C> {
+        match self {
+            WindowElement::Wayland(window) => {
+                AsRenderElements::<R>::render_elements(window, renderer, location, scale, alpha)
+            }
+            // The buffers are in X11 pixels, which are only scaled by what is left of the output
+            // scale.
+            WindowElement::X11(surface) => match surface.wl_surface() {
+                Some(wl_surface) => render_elements_from_surface_tree(
+                    renderer,
+                    &wl_surface,
+                    location,
+                    Scale::from(scale.x / x11_scale(surface)),
+                    alpha,
+                ),
+                None => Vec::new(),
+            },
+        }
+    }
+}
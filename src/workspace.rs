@@ -1 +1,591 @@
+use std::str::FromStr;
 
+use smithay::{
+    output::Output,
+    utils::{IsAlive, Logical, Point, SERIAL_COUNTER},
+};
+
+use crate::{ipc::Event, state::State, window::WindowElement};
+
+/// A set of windows with its own stacking order. Every output shows exactly one workspace, the
+/// windows of the shown workspaces are mapped in `State::space` and the others are kept here.
+pub struct Workspace {
+    pub name: String,
+    /// The output the workspace is shown on, or will be shown on.
+    pub output: Option<Output>,
+    pub visible: bool,
+    /// The windows, from bottom to top. The locations are only up to date while the workspace is
+    /// hidden, otherwise the space knows better.
+    windows: Vec<(WindowElement, Point<i32, Logical>)>,
+    /// The window that had focus when the workspace was hidden.
+    focus: Option<WindowElement>,
+}
+
+impl Workspace {
+    fn new(name: String, output: Option<Output>) -> Self {
+        Self {
+            name,
+            output,
+            visible: false,
+            windows: Vec::new(),
+            focus: None,
+        }
+    }
+
+    pub fn windows(&self) -> impl Iterator<Item = &WindowElement> {
+        self.windows.iter().map(|(window, _)| window)
+    }
+
+    pub fn contains(&self, window: &WindowElement) -> bool {
+        self.windows().any(|w| w == window)
+    }
+}
+
+/// Where a window should go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkspaceTarget {
+    Named(String),
+    /// The next workspace on the same output.
+    Next,
+    /// The previous workspace on the same output.
+    Previous,
+}
+
+impl FromStr for WorkspaceTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "next" => Ok(WorkspaceTarget::Next),
+            "previous" => Ok(WorkspaceTarget::Previous),
+            _ => Ok(WorkspaceTarget::Named(s.to_string())),
+        }
+    }
+}
+
+impl<BackendData> State<BackendData> {
+    /// The output the user is working on: the one of the focused window, or the one under the
+    /// pointer.
+    pub fn current_output(&self) -> Option<Output> {
+        self.focused_window()
+            .and_then(|window| self.space.outputs_for_element(&window).into_iter().next())
+            .or_else(|| {
+                let pointer = self.seat.get_pointer()?;
+                self.space
+                    .output_under(pointer.current_location())
+                    .next()
+                    .cloned()
+            })
+            .or_else(|| self.space.outputs().next().cloned())
+    }
+
+    /// The workspace shown on an output.
+    pub fn visible_workspace(&self, output: &Output) -> Option<&Workspace> {
+        self.workspaces
+            .iter()
+            .find(|ws| ws.visible && ws.output.as_ref() == Some(output))
+    }
+
+    pub fn workspace_of_window(&self, window: &WindowElement) -> Option<&Workspace> {
+        self.workspaces.iter().find(|ws| ws.contains(window))
+    }
+
+    /// Windows on every workspace, shown or not.
+    pub fn all_windows(&self) -> impl Iterator<Item = &WindowElement> {
+        self.workspaces.iter().flat_map(Workspace::windows)
+    }
+
+    /// Resolve a target relative to the current output to the name of a workspace. Names that
+    /// don't exist yet are fine, the workspace is created when needed.
+    fn workspace_name(&self, target: &WorkspaceTarget) -> Option<String> {
+        let name = match target {
+            WorkspaceTarget::Named(name) => return Some(name.clone()),
+            _ => {
+                let output = self.current_output()?;
+                let names = self
+                    .workspaces
+                    .iter()
+                    .filter(|ws| ws.output.as_ref() == Some(&output))
+                    .map(|ws| ws.name.as_str())
+                    .collect::<Vec<_>>();
+                let current = self.visible_workspace(&output)?;
+                let index = names.iter().position(|name| *name == current.name)?;
+                let index = match target {
+                    WorkspaceTarget::Next => (index + 1) % names.len(),
+                    _ => (index + names.len() - 1) % names.len(),
+                };
+                names[index]
+            }
+        };
+        Some(name.to_string())
+    }
+
+    /// The lowest number that is not the name of a workspace yet.
+    fn free_workspace_name(&self) -> String {
+        (1..)
+            .map(|n: u32| n.to_string())
+            .find(|name| self.workspaces.iter().all(|ws| ws.name != *name))
+            .unwrap()
+    }
+
+    fn workspace_index(&self, name: &str) -> Option<usize> {
+        self.workspaces.iter().position(|ws| ws.name == name)
+    }
+
+    /// Show a workspace on the current output, creating it if needed. If it is already shown on
+    /// another output, the focus just moves there.
+    pub fn switch_workspace(&mut self, target: &WorkspaceTarget) {
+        let name = match self.workspace_name(target) {
+            Some(name) => name,
+            None => return,
+        };
+        if let Some(output) = self.current_output() {
+            self.show_workspace_on(name, &output);
+        }
+    }
+
+    /// Show a workspace on the output it belongs to, or on the current output if it has none.
+    pub fn activate_workspace(&mut self, name: &str) {
+        let output = self
+            .workspace_index(name)
+            .and_then(|index| self.workspaces[index].output.clone())
+            .or_else(|| self.current_output());
+        if let Some(output) = output {
+            self.show_workspace_on(name.to_string(), &output);
+        }
+    }
+
+    /// Show a workspace on an output, creating it if needed.
+    pub fn show_workspace_on(&mut self, name: String, output: &Output) {
+        let index = match self.workspace_index(&name) {
+            Some(index) => index,
+            None => {
+                self.workspaces
+                    .push(Workspace::new(name, Some(output.clone())));
+                self.workspaces.len() - 1
+            }
+        };
+
+        if self.workspaces[index].visible {
+            self.focus_workspace(index);
+            return;
+        }
+
+        // A hidden workspace moves to the output.
+        let from = self.workspaces[index].output.clone();
+        self.move_workspace_locations(index, from.as_ref(), output);
+        self.workspaces[index].output = Some(output.clone());
+
+        if let Some(current) = self
+            .workspaces
+            .iter()
+            .position(|ws| ws.visible && ws.output.as_ref() == Some(output))
+        {
+            self.hide_workspace(current);
+        }
+        self.show_workspace(index);
+        self.focus_workspace(index);
+        self.remove_empty_workspaces();
+    }
+
+    /// Move a window to a workspace, creating it if needed. The window is hidden if the workspace
+    /// is.
+    pub fn move_window_to_workspace(&mut self, window: &WindowElement, target: &WorkspaceTarget) {
+        let name = match self.workspace_name(target) {
+            Some(name) => name,
+            None => return,
+        };
+        let from = match self.workspaces.iter().position(|ws| ws.contains(window)) {
+            Some(from) => from,
+            None => return,
+        };
+        if self.workspaces[from].name == name {
+            return;
+        }
+        let to = match self.workspace_index(&name) {
+            Some(index) => index,
+            None => {
+                let output = self.workspaces[from].output.clone();
+                self.workspaces.push(Workspace::new(name, output));
+                self.workspaces.len() - 1
+            }
+        };
+
+        // Keep the location of the window relative to its output.
+        let mut location = self.space.element_location(window).unwrap_or_default();
+        let from_output = self.workspaces[from].output.clone();
+        let to_output = self.workspaces[to].output.clone();
+        if let (Some(from_output), Some(to_output)) = (&from_output, &to_output) {
+            location += self.output_offset(from_output, to_output);
+        }
+
+        self.workspaces[from].windows.retain(|(w, _)| w != window);
+        if self.workspaces[from].focus.as_ref() == Some(window) {
+            self.workspaces[from].focus = None;
+        }
+        self.workspaces[to].windows.push((window.clone(), location));
+
+        if self.workspaces[to].visible {
+            self.space.map_element(window.clone(), location, false);
+        } else {
+            self.space.unmap_elem(window);
+            window.set_activated(false);
+            window.send_pending_configure();
+        }
+
+        // The focus stays on the workspace the window has left.
+        if self.workspaces[from].visible {
+            self.focus_workspace(from);
+        }
+        self.remove_empty_workspaces();
+    }
+
+    /// Create a workspace with the lowest free number and show it on the current output.
+    pub fn new_workspace(&mut self) {
+        let name = self.free_workspace_name();
+        self.switch_workspace(&WorkspaceTarget::Named(name));
+    }
+
+    /// Destroy the workspace shown on the current output.
+    pub fn destroy_workspace(&mut self) {
+        let name = self
+            .current_output()
+            .and_then(|output| self.visible_workspace(&output))
+            .map(|workspace| workspace.name.clone());
+        if let Some(name) = name {
+            self.remove_workspace(&name);
+        }
+    }
+
+    /// Destroy a workspace. If it is shown, another workspace of its output is shown instead, or
+    /// a new one if there is none. Its windows move to the workspace shown on its output.
+    pub fn remove_workspace(&mut self, name: &str) {
+        let index = match self.workspace_index(name) {
+            Some(index) => index,
+            None => return,
+        };
+        // Without an output there is nowhere to put the windows.
+        let output = match self.workspaces[index].output.clone() {
+            Some(output) => output,
+            None => return,
+        };
+
+        if self.workspaces[index].visible {
+            let other = self
+                .neighbour_workspace(index)
+                .unwrap_or_else(|| self.free_workspace_name());
+            self.show_workspace_on(other, &output);
+        }
+
+        // An empty workspace is already gone by now.
+        if let Some(index) = self.workspace_index(name) {
+            let windows = self.workspaces.remove(index).windows;
+            if let Some(target) = self
+                .workspaces
+                .iter_mut()
+                .find(|ws| ws.visible && ws.output.as_ref() == Some(&output))
+            {
+                for (window, location) in windows {
+                    self.space.map_element(window.clone(), location, false);
+                    target.windows.push((window, location));
+                }
+            }
+        }
+    }
+
+    /// The workspace before a workspace on the same output, if there is another one.
+    fn neighbour_workspace(&self, index: usize) -> Option<String> {
+        let output = self.workspaces[index].output.as_ref();
+        let others = self
+            .workspaces
+            .iter()
+            .enumerate()
+            .filter(|(i, ws)| *i != index && ws.output.as_ref() == output)
+            .collect::<Vec<_>>();
+        others
+            .iter()
+            .rev()
+            .find(|(i, _)| *i < index)
+            .or_else(|| others.last())
+            .map(|(_, ws)| ws.name.clone())
+    }
+
+    /// Add a new window to the workspace shown on the output it is on.
+    pub fn add_window_to_workspace(&mut self, window: &WindowElement) {
+        let output = self
+            .space
+            .outputs_for_element(window)
+            .into_iter()
+            .next()
+            .or_else(|| self.current_output());
+        let location = self.space.element_location(window).unwrap_or_default();
+
+        if let Some(workspace) = self
+            .workspaces
+            .iter_mut()
+            .find(|ws| ws.visible && ws.output == output)
+        {
+            workspace.windows.push((window.clone(), location));
+        }
+    }
+
+    /// Put a window that has been dragged onto another output on the workspace shown there. The
+    /// output is the one under the pointer when the window is dropped.
+    pub fn drop_window(&mut self, window: &WindowElement, pointer_location: Point<f64, Logical>) {
+        let output = match self.space.output_under(pointer_location).next() {
+            Some(output) => output.clone(),
+            None => return,
+        };
+        let from = self.workspaces.iter().position(|ws| ws.contains(window));
+        let to = self
+            .workspaces
+            .iter()
+            .position(|ws| ws.visible && ws.output.as_ref() == Some(&output));
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) if from != to => (from, to),
+            _ => return,
+        };
+
+        // Both workspaces are shown, so the window stays where it has been dropped.
+        let location = self.space.element_location(window).unwrap_or_default();
+        self.workspaces[from].windows.retain(|(w, _)| w != window);
+        if self.workspaces[from].focus.as_ref() == Some(window) {
+            self.workspaces[from].focus = None;
+        }
+        self.workspaces[to].windows.push((window.clone(), location));
+        self.remove_empty_workspaces();
+    }
+
+    /// Forget a window that is gone without being destroyed, like an unmapped X11 window.
+    pub fn remove_window(&mut self, window: &WindowElement) {
+        for workspace in &mut self.workspaces {
+            workspace.windows.retain(|(w, _)| w != window);
+            if workspace.focus.as_ref() == Some(window) {
+                workspace.focus = None;
+            }
+        }
+        self.remove_empty_workspaces();
+    }
+
+    /// Forget the windows that have been destroyed.
+    pub fn remove_dead_windows(&mut self) {
+        for workspace in &mut self.workspaces {
+            workspace.windows.retain(|(window, _)| window.alive());
+            workspace.focus = workspace.focus.take().filter(WindowElement::alive);
+        }
+        self.remove_empty_workspaces();
+    }
+
+    /// Give a new output a workspace: the workspaces left without an output go to it and the
+    /// first of them is shown, otherwise a new one is. Windows that were mapped before there was
+    /// any output end up on it as well.
+    pub fn add_output_workspace(&mut self, output: &Output) {
+        let orphaned = self
+            .workspaces
+            .iter()
+            .enumerate()
+            .filter(|(_, ws)| ws.output.is_none())
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        for &index in &orphaned {
+            self.workspaces[index].output = Some(output.clone());
+        }
+        let index = match orphaned.first() {
+            Some(&index) => index,
+            None => {
+                let name = self.free_workspace_name();
+                self.workspaces
+                    .push(Workspace::new(name, Some(output.clone())));
+                self.workspaces.len() - 1
+            }
+        };
+
+        let orphans = self
+            .space
+            .elements()
+            .filter(|window| self.workspace_of_window(window).is_none())
+            .map(|window| {
+                let location = self.space.element_location(window).unwrap_or_default();
+                (window.clone(), location)
+            })
+            .collect::<Vec<_>>();
+        self.workspaces[index].windows.extend(orphans);
+
+        self.show_workspace(index);
+    }
+
+    /// Move the workspaces of an output that is going away to another output. The windows that
+    /// were shown join the workspace shown there, so that they don't disappear from sight. Without
+    /// another output, the workspaces are kept hidden until the next one is added. Has to be
+    /// called while the output is still mapped.
+    pub fn remove_output_workspaces(&mut self, output: &Output) {
+        let target = self.space.outputs().find(|o| *o != output).cloned();
+
+        let mut shown = None;
+        for index in 0..self.workspaces.len() {
+            if self.workspaces[index].output.as_ref() != Some(output) {
+                continue;
+            }
+            if self.workspaces[index].visible {
+                self.hide_workspace(index);
+                shown = Some(index);
+            }
+            if let Some(target) = &target {
+                self.move_workspace_locations(index, Some(output), target);
+            }
+            self.workspaces[index].output = target.clone();
+        }
+
+        let (from, target) = match (shown, target) {
+            (Some(from), Some(target)) => (from, target),
+            _ => return,
+        };
+        let to = match self
+            .workspaces
+            .iter()
+            .position(|ws| ws.visible && ws.output.as_ref() == Some(&target))
+        {
+            Some(to) => to,
+            None => return,
+        };
+        let windows = std::mem::take(&mut self.workspaces[from].windows);
+        let focus = self.workspaces[from].focus.take();
+        for (window, location) in &windows {
+            self.space.map_element(window.clone(), *location, false);
+        }
+        self.workspaces[to].windows.extend(windows);
+        // The user was working on the output that went away.
+        if let Some(window) = focus {
+            self.focus_window(window);
+        }
+        self.remove_empty_workspaces();
+    }
+
+    /// Move the windows of the workspaces of an output by an offset, for when the output itself
+    /// has moved.
+    pub fn move_output_windows(&mut self, output: &Output, offset: Point<i32, Logical>) {
+        for index in 0..self.workspaces.len() {
+            let workspace = &self.workspaces[index];
+            if workspace.output.as_ref() != Some(output) {
+                continue;
+            }
+            if !workspace.visible {
+                for (_, location) in &mut self.workspaces[index].windows {
+                    *location += offset;
+                }
+                continue;
+            }
+
+            let windows = self
+                .space
+                .elements()
+                .filter(|window| workspace.contains(window))
+                .filter_map(|window| {
+                    let location = self.space.element_location(window)?;
+                    Some((window.clone(), location))
+                })
+                .collect::<Vec<_>>();
+            for (window, location) in windows {
+                self.space.map_element(window, location + offset, false);
+            }
+        }
+    }
+
+    /// Hide a workspace, remembering the stacking order, the locations and the focus of its
+    /// windows.
+    fn hide_workspace(&mut self, index: usize) {
+        let focused = self.focused_window();
+        let workspace = &self.workspaces[index];
+        let windows = self
+            .space
+            .elements()
+            .filter(|window| workspace.contains(window))
+            .map(|window| {
+                let location = self.space.element_location(window).unwrap_or_default();
+                (window.clone(), location)
+            })
+            .collect::<Vec<_>>();
+
+        for (window, _) in &windows {
+            self.space.unmap_elem(window);
+            window.set_activated(false);
+            window.send_pending_configure();
+        }
+
+        let workspace = &mut self.workspaces[index];
+        workspace.focus = focused.filter(|window| windows.iter().any(|(w, _)| w == window));
+        workspace.windows = windows;
+        workspace.visible = false;
+    }
+
+    fn show_workspace(&mut self, index: usize) {
+        let workspace = &mut self.workspaces[index];
+        workspace.visible = true;
+        for (window, location) in &workspace.windows {
+            self.space.map_element(window.clone(), *location, false);
+        }
+
+        let (name, output) = (
+            workspace.name.clone(),
+            workspace.output.as_ref().map(Output::name),
+        );
+        self.emit_ipc_event(Event::WorkspaceShown { name, output });
+    }
+
+    /// Give the keyboard focus to the window of a shown workspace that had it last, or to its
+    /// topmost one.
+    fn focus_workspace(&mut self, index: usize) {
+        let workspace = &self.workspaces[index];
+        let window = workspace.focus.clone().or_else(|| {
+            self.space
+                .elements()
+                .filter(|window| workspace.contains(window))
+                .last()
+                .cloned()
+        });
+
+        match window {
+            Some(window) => self.focus_window(window),
+            None => {
+                if self.is_locked() || self.exclusive_layer().is_some() {
+                    return;
+                }
+                for window in self.space.elements() {
+                    window.set_activated(false);
+                    window.send_pending_configure();
+                }
+                if let Some(keyboard) = self.seat.get_keyboard() {
+                    keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+                }
+            }
+        }
+    }
+
+    /// Hidden workspaces without windows are not worth keeping.
+    fn remove_empty_workspaces(&mut self) {
+        self.workspaces
+            .retain(|ws| ws.visible || !ws.windows.is_empty());
+    }
+
+    /// Translate the remembered locations of the windows of a hidden workspace from one output to
+    /// another.
+    fn move_workspace_locations(&mut self, index: usize, from: Option<&Output>, to: &Output) {
+        let offset = match from {
+            Some(from) if from != to => self.output_offset(from, to),
+            _ => return,
+        };
+        for (_, location) in &mut self.workspaces[index].windows {
+            *location += offset;
+        }
+    }
+
+    fn output_offset(&self, from: &Output, to: &Output) -> Point<i32, Logical> {
+        let location = |output| {
+            self.space
+                .output_geometry(output)
+                .map(|geo| geo.loc)
+                .unwrap_or_default()
+        };
+        location(to) - location(from)
+    }
+}